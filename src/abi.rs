@@ -0,0 +1,101 @@
+use crate::error::Error;
+use alloy::{
+    dyn_abi::{DynSolType, DynSolValue, JsonAbiExt, Specifier},
+    json_abi::{Function, Param},
+};
+use revm::primitives::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Function to call, the server encoding the calldata from it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FunctionSpec {
+    /// Human-readable signature, e.g. `transfer(address,uint256)`
+    Signature(String),
+    /// JSON ABI fragment of the function
+    Abi(Function),
+}
+
+impl FunctionSpec {
+    pub fn function(&self) -> Result<Function, Error> {
+        match self {
+            FunctionSpec::Signature(signature) => Function::parse(signature)
+                .map_err(|e| Error::InvalidAbiCall(format!("{signature}: {e}"))),
+            FunctionSpec::Abi(function) => Ok(function.clone()),
+        }
+    }
+
+    /// Selector followed by the ABI-encoded `args`
+    pub fn encode_call(&self, args: &[Value]) -> Result<Bytes, Error> {
+        let function = self.function()?;
+        let values = abi_values(&function.inputs, args)?;
+        let calldata = function
+            .abi_encode_input(&values)
+            .map_err(|e| Error::InvalidAbiCall(format!("{}: {e}", function.signature())))?;
+        Ok(calldata.into())
+    }
+}
+
+/// `args` given as JSON, typed as `params`. Strings are parsed like Solidity literals,
+/// JSON numbers and booleans as their text, arrays and tuples as JSON arrays.
+pub fn abi_values(params: &[Param], args: &[Value]) -> Result<Vec<DynSolValue>, Error> {
+    if params.len() != args.len() {
+        return Err(Error::InvalidAbiCall(format!(
+            "expected {} arguments, got {}",
+            params.len(),
+            args.len()
+        )));
+    }
+    params
+        .iter()
+        .zip(args)
+        .map(|(param, arg)| {
+            let ty = param
+                .resolve()
+                .map_err(|e| Error::InvalidAbiCall(format!("{}: {e}", param.ty)))?;
+            abi_value(&ty, arg).map_err(|reason| {
+                let name = if param.name.is_empty() {
+                    &param.ty
+                } else {
+                    &param.name
+                };
+                Error::InvalidAbiCall(format!("argument {name}: {reason}"))
+            })
+        })
+        .collect()
+}
+
+fn abi_value(ty: &DynSolType, arg: &Value) -> Result<DynSolValue, String> {
+    match (ty, arg) {
+        (DynSolType::Array(inner), Value::Array(items)) => Ok(DynSolValue::Array(
+            items
+                .iter()
+                .map(|item| abi_value(inner, item))
+                .collect::<Result<_, _>>()?,
+        )),
+        (DynSolType::FixedArray(inner, len), Value::Array(items)) if items.len() == *len => {
+            Ok(DynSolValue::FixedArray(
+                items
+                    .iter()
+                    .map(|item| abi_value(inner, item))
+                    .collect::<Result<_, _>>()?,
+            ))
+        }
+        (DynSolType::Tuple(types), Value::Array(items)) if items.len() == types.len() => {
+            Ok(DynSolValue::Tuple(
+                types
+                    .iter()
+                    .zip(items)
+                    .map(|(ty, item)| abi_value(ty, item))
+                    .collect::<Result<_, _>>()?,
+            ))
+        }
+        (ty, Value::String(text)) => ty.coerce_str(text).map_err(|e| e.to_string()),
+        (ty, Value::Number(number)) => ty
+            .coerce_str(&number.to_string())
+            .map_err(|e| e.to_string()),
+        (ty, Value::Bool(flag)) => ty.coerce_str(&flag.to_string()).map_err(|e| e.to_string()),
+        (ty, arg) => Err(format!("{arg} is not a {ty}")),
+    }
+}
@@ -0,0 +1,47 @@
+use crate::{error::Error, gas_estimator::Tx};
+use alloy::{
+    eips::BlockId,
+    primitives::{address, Address},
+    providers::RootProvider,
+    sol,
+};
+
+/// `NodeInterface` virtual contract, only reachable through `eth_call` on an Arbitrum node
+pub const NODE_INTERFACE: Address = address!("0x00000000000000000000000000000000000000C8");
+
+sol! {
+    #[sol(rpc)]
+    interface INodeInterface {
+        function gasEstimateL1Component(address to, bool contractCreation, bytes calldata data)
+            external
+            payable
+            returns (uint64 gasEstimateForL1, uint256 baseFee, uint256 l1BaseFeeEstimate);
+    }
+}
+
+/// L2 gas Arbitrum charges for posting `tx` to L1, queried from `NodeInterface.gasEstimateL1Component`
+/// at `block`
+pub async fn l1_gas_estimate(
+    provider: &RootProvider,
+    tx: &Tx,
+    block: BlockId,
+) -> Result<u64, Error> {
+    let node_interface = INodeInterface::new(NODE_INTERFACE, provider);
+    let mut call = node_interface
+        .gasEstimateL1Component(
+            tx.to.unwrap_or_default(),
+            tx.to.is_none(),
+            tx.data.clone().unwrap_or_default(),
+        )
+        .value(tx.value);
+    if let Some(from) = tx.from {
+        call = call.from(from);
+    }
+
+    let estimate = call
+        .call()
+        .block(block)
+        .await
+        .map_err(Error::ContractCall)?;
+    Ok(estimate.gasEstimateForL1)
+}
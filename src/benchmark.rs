@@ -0,0 +1,189 @@
+use crate::replay::ReplayOutcome;
+use revm::primitives::{Address, FixedBytes};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Blocks of `from..=to` to replay: all of them, or `sample` spread evenly over the range,
+/// so the same range and sample size always benchmark the same blocks
+pub fn sample_blocks(from: u64, to: u64, sample: Option<usize>) -> Vec<u64> {
+    let count = to.saturating_sub(from) + 1;
+    match sample {
+        Some(sample) if (sample as u64) < count => (0..sample as u64)
+            .map(|index| from + index * count / sample as u64)
+            .collect(),
+        _ => (from..=to).collect(),
+    }
+}
+
+/// Accuracy of the estimates of a set of replayed transactions. Errors are our estimate
+/// minus the gas the transaction used, relative to the latter.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AccuracyStats {
+    pub transactions: usize,
+    /// Transactions that failed to estimate, left out of the figures below
+    pub failed: usize,
+    /// Average signed error, positive when overestimating
+    pub mean_error_percent: f64,
+    pub mean_abs_error_percent: f64,
+    pub p50_abs_error_percent: f64,
+    pub p90_abs_error_percent: f64,
+    pub p99_abs_error_percent: f64,
+    pub max_abs_error_percent: f64,
+    /// Estimates below what the transaction used, which as its gas limit would have run out of gas
+    pub underestimates: usize,
+    pub underestimate_rate: f64,
+}
+
+impl AccuracyStats {
+    pub fn new<'a>(outcomes: impl IntoIterator<Item = &'a ReplayOutcome>) -> Self {
+        let mut stats = Self::default();
+        let mut errors = Vec::new();
+        for outcome in outcomes {
+            stats.transactions += 1;
+            match &outcome.report {
+                Ok(report) => {
+                    errors.push(report.difference_percent);
+                    if report.underestimated {
+                        stats.underestimates += 1;
+                    }
+                }
+                Err(_) => stats.failed += 1,
+            }
+        }
+        if errors.is_empty() {
+            return stats;
+        }
+
+        let estimated = errors.len() as f64;
+        stats.mean_error_percent = errors.iter().sum::<f64>() / estimated;
+        let mut abs_errors: Vec<f64> = errors.iter().map(|error| error.abs()).collect();
+        abs_errors.sort_by(f64::total_cmp);
+        stats.mean_abs_error_percent = abs_errors.iter().sum::<f64>() / estimated;
+        stats.p50_abs_error_percent = percentile(&abs_errors, 50);
+        stats.p90_abs_error_percent = percentile(&abs_errors, 90);
+        stats.p99_abs_error_percent = percentile(&abs_errors, 99);
+        stats.max_abs_error_percent = abs_errors[abs_errors.len() - 1];
+        stats.underestimate_rate = stats.underestimates as f64 / estimated;
+        stats
+    }
+}
+
+/// Nearest-rank percentile of sorted, non-empty `values`
+fn percentile(values: &[f64], percent: usize) -> f64 {
+    let rank = (values.len() * percent).div_ceil(100).max(1);
+    values[rank - 1]
+}
+
+/// Transactions calling the same function of the same contract
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OffenderGroup {
+    /// `None` for contract creations
+    pub to: Option<Address>,
+    /// `None` for calls without a full selector, such as plain transfers
+    pub selector: Option<FixedBytes<4>>,
+    #[serde(flatten)]
+    pub stats: AccuracyStats,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccuracyReport {
+    pub from_block: u64,
+    pub to_block: u64,
+    /// Blocks actually replayed
+    pub blocks: usize,
+    pub overall: AccuracyStats,
+    /// Groups with the most underestimates, then the largest mean absolute error
+    pub worst_offenders: Vec<OffenderGroup>,
+}
+
+impl AccuracyReport {
+    pub fn new(
+        from_block: u64,
+        to_block: u64,
+        blocks: usize,
+        outcomes: &[ReplayOutcome],
+        top: usize,
+    ) -> Self {
+        let mut groups: HashMap<_, Vec<&ReplayOutcome>> = HashMap::new();
+        for outcome in outcomes {
+            let transaction = &outcome.transaction;
+            let selector = transaction
+                .data
+                .as_ref()
+                .and_then(|data| data.get(..4))
+                .filter(|_| transaction.to.is_some())
+                .map(FixedBytes::<4>::from_slice);
+            groups
+                .entry((transaction.to, selector))
+                .or_default()
+                .push(outcome);
+        }
+        let mut worst_offenders: Vec<_> = groups
+            .into_iter()
+            .map(|((to, selector), outcomes)| OffenderGroup {
+                to,
+                selector,
+                stats: AccuracyStats::new(outcomes),
+            })
+            .collect();
+        worst_offenders.sort_by(|a, b| {
+            b.stats
+                .underestimates
+                .cmp(&a.stats.underestimates)
+                .then(
+                    b.stats
+                        .mean_abs_error_percent
+                        .total_cmp(&a.stats.mean_abs_error_percent),
+                )
+                .then(b.stats.transactions.cmp(&a.stats.transactions))
+        });
+        worst_offenders.truncate(top);
+
+        Self {
+            from_block,
+            to_block,
+            blocks,
+            overall: AccuracyStats::new(outcomes),
+            worst_offenders,
+        }
+    }
+
+    /// One row for all transactions, with `to` and `selector` set to `all`, then one per worst offender
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "to,selector,transactions,failed,mean_error_percent,mean_abs_error_percent,\
+             p50_abs_error_percent,p90_abs_error_percent,p99_abs_error_percent,\
+             max_abs_error_percent,underestimates,underestimate_rate\n",
+        );
+        let rows = std::iter::once(("all".to_string(), "all".to_string(), &self.overall)).chain(
+            self.worst_offenders.iter().map(|group| {
+                (
+                    group.to.map(|to| to.to_string()).unwrap_or_default(),
+                    group
+                        .selector
+                        .map(|selector| selector.to_string())
+                        .unwrap_or_default(),
+                    &group.stats,
+                )
+            }),
+        );
+        for (to, selector, stats) in rows {
+            csv.push_str(&format!(
+                "{},{},{},{},{:.4},{:.4},{:.4},{:.4},{:.4},{:.4},{},{:.4}\n",
+                to,
+                selector,
+                stats.transactions,
+                stats.failed,
+                stats.mean_error_percent,
+                stats.mean_abs_error_percent,
+                stats.p50_abs_error_percent,
+                stats.p90_abs_error_percent,
+                stats.p99_abs_error_percent,
+                stats.max_abs_error_percent,
+                stats.underestimates,
+                stats.underestimate_rate
+            ));
+        }
+        csv
+    }
+}
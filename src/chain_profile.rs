@@ -0,0 +1,255 @@
+use crate::{error::Error, utils::calculate_calldata_cost};
+use revm::{
+    precompile::{secp256r1, PrecompileSpecId, Precompiles},
+    primitives::{hardfork::SpecId, Address, Bytes},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+/// Built-in profiles, in the same format as user supplied profile files
+const BUILTIN_PROFILES: &str = include_str!("chains.toml");
+
+/// Where the block gas limit used as the default transaction gas limit comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockGasLimitSource {
+    /// Gas limit of the latest block reported by the upstream
+    Latest,
+    /// Constant gas limit
+    Fixed(u64),
+}
+
+/// Activation point of a hardfork, by block number or by timestamp
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HardforkActivation {
+    pub spec: SpecId,
+    pub block: Option<u64>,
+    pub timestamp: Option<u64>,
+}
+
+impl HardforkActivation {
+    fn is_active(&self, block_number: u64, timestamp: u64) -> bool {
+        match (self.block, self.timestamp) {
+            (Some(block), _) => block_number >= block,
+            (None, Some(activation)) => timestamp >= activation,
+            (None, None) => true,
+        }
+    }
+}
+
+/// Intrinsic gas a chain charges differently from Ethereum, in place of the constants of the
+/// active hardfork. Unset fields follow the hardfork.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IntrinsicGas {
+    pub tx_base_cost: Option<u64>,
+    pub tx_create_cost: Option<u64>,
+    pub calldata_zero_byte_cost: Option<u64>,
+    pub calldata_non_zero_byte_cost: Option<u64>,
+}
+
+impl IntrinsicGas {
+    pub fn base_cost(&self) -> u128 {
+        self.tx_base_cost.unwrap_or(21_000) as u128
+    }
+
+    /// Cost of a creation on top of the base cost, charged since Homestead
+    pub fn create_cost(&self, spec: SpecId) -> u128 {
+        let standard = if spec.is_enabled_in(SpecId::HOMESTEAD) {
+            32_000
+        } else {
+            0
+        };
+        self.tx_create_cost.unwrap_or(standard) as u128
+    }
+
+    /// Cost of a zero and of a non-zero calldata byte. Non-zero bytes cost 68 before Istanbul.
+    pub fn calldata_byte_costs(&self, spec: SpecId) -> (u64, u64) {
+        let non_zero = if spec.is_enabled_in(SpecId::ISTANBUL) {
+            16
+        } else {
+            68
+        };
+        (
+            self.calldata_zero_byte_cost.unwrap_or(4),
+            self.calldata_non_zero_byte_cost.unwrap_or(non_zero),
+        )
+    }
+
+    /// Gas these constants charge for a transaction with `data` minus what the EVM charges
+    /// with the ones of `spec`, which simulated gas has to be corrected by
+    pub fn adjustment(&self, data: &Bytes, create: bool, spec: SpecId) -> i128 {
+        let intrinsic = |gas: &IntrinsicGas| {
+            let create_cost = if create { gas.create_cost(spec) } else { 0 };
+            (gas.base_cost() + create_cost + calculate_calldata_cost(data, gas, spec)) as i128
+        };
+        intrinsic(self) - intrinsic(&IntrinsicGas::default())
+    }
+}
+
+/// How the chain charges for a transaction on top of EVM execution
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GasModel {
+    /// Execution gas only
+    #[default]
+    Ethereum,
+    /// Execution gas plus an L1 data fee priced by the `GasPriceOracle` predeploy
+    OpStack,
+    /// L2 execution gas plus the L1 calldata charge, also paid in L2 gas
+    Arbitrum,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NativeCurrency {
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+impl Default for NativeCurrency {
+    fn default() -> Self {
+        Self {
+            symbol: "ETH".to_string(),
+            decimals: 18,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainProfile {
+    pub chain_id: u64,
+    pub name: String,
+    /// Hardforks in activation order
+    #[serde(default)]
+    pub hardforks: Vec<HardforkActivation>,
+    pub block_gas_limit: BlockGasLimitSource,
+    /// Chain-specific precompiles on top of the ones of the active hardfork
+    #[serde(default)]
+    pub extra_precompiles: Vec<Address>,
+    #[serde(default)]
+    pub intrinsic_gas: IntrinsicGas,
+    #[serde(default)]
+    pub gas_model: GasModel,
+    #[serde(default)]
+    pub native_currency: NativeCurrency,
+    pub default_rpc_url: Option<String>,
+    /// Target time between blocks, bounding how long an estimate stays fresh
+    #[serde(default)]
+    pub block_time_ms: Option<u64>,
+}
+
+impl ChainProfile {
+    /// Profile for chains without a known profile: Ethereum rules at the latest hardfork
+    pub fn generic(chain_id: u64) -> Self {
+        Self {
+            chain_id,
+            name: format!("Chain {}", chain_id),
+            hardforks: Vec::new(),
+            block_gas_limit: BlockGasLimitSource::Latest,
+            extra_precompiles: Vec::new(),
+            intrinsic_gas: IntrinsicGas::default(),
+            gas_model: GasModel::default(),
+            native_currency: NativeCurrency::default(),
+            default_rpc_url: None,
+            block_time_ms: None,
+        }
+    }
+
+    /// Hardfork active at the given block. Profiles without a schedule follow the latest
+    /// hardfork, the others do not cover blocks before their first listed one.
+    pub fn spec_at(&self, block_number: u64, timestamp: u64) -> Result<SpecId, Error> {
+        if self.hardforks.is_empty() {
+            return Ok(SpecId::default());
+        }
+        self.hardforks
+            .iter()
+            .rev()
+            .find(|fork| fork.is_active(block_number, timestamp))
+            .map(|fork| fork.spec)
+            .ok_or(Error::BlockNotCovered {
+                chain_id: self.chain_id,
+                block_number,
+            })
+    }
+
+    /// Precompiles the EVM runs for this chain: the ones of `spec`,
+    /// plus the chain-specific ones revm implements (RIP-7212 P256VERIFY)
+    pub fn precompiles(&self, spec: SpecId) -> &'static Precompiles {
+        // Leaked once per spec, like revm does for its own sets
+        static WITH_P256: OnceLock<Mutex<HashMap<SpecId, &'static Precompiles>>> = OnceLock::new();
+
+        let with_p256 = self
+            .extra_precompiles
+            .contains(secp256r1::P256VERIFY.address());
+        let base = Precompiles::new(PrecompileSpecId::from_spec_id(spec));
+        if !with_p256 {
+            return base;
+        }
+
+        let mut cache = WITH_P256.get_or_init(Default::default).lock().unwrap();
+        cache.entry(spec).or_insert_with(|| {
+            let mut precompiles = base.clone();
+            precompiles.extend(secp256r1::precompiles());
+            Box::leak(Box::new(precompiles))
+        })
+    }
+
+    /// Gas limit transactions without one are simulated with
+    pub fn block_gas_limit(&self, latest_gas_limit: u64) -> u64 {
+        match self.block_gas_limit {
+            BlockGasLimitSource::Latest => latest_gas_limit,
+            BlockGasLimitSource::Fixed(gas_limit) => gas_limit,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileFile {
+    chains: Vec<ChainProfile>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChainRegistry {
+    profiles: HashMap<u64, ChainProfile>,
+}
+
+impl ChainRegistry {
+    /// Registry with the built-in profiles only
+    pub fn builtin() -> Self {
+        let mut registry = Self {
+            profiles: HashMap::new(),
+        };
+        registry
+            .extend_from_toml(BUILTIN_PROFILES)
+            .expect("built-in chain profiles are valid");
+        registry
+    }
+
+    /// Built-in profiles, overridden by the ones in `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let mut registry = Self::builtin();
+        let contents = std::fs::read_to_string(path).map_err(Error::Io)?;
+        registry.extend_from_toml(&contents)?;
+        Ok(registry)
+    }
+
+    fn extend_from_toml(&mut self, contents: &str) -> Result<(), Error> {
+        let file: ProfileFile = toml::from_str(contents).map_err(Error::ChainProfile)?;
+        for profile in file.chains {
+            self.profiles.insert(profile.chain_id, profile);
+        }
+        Ok(())
+    }
+
+    /// Profile for `chain_id`, falling back to a generic one for unknown chains
+    pub fn get(&self, chain_id: u64) -> ChainProfile {
+        self.profiles
+            .get(&chain_id)
+            .cloned()
+            .unwrap_or_else(|| ChainProfile::generic(chain_id))
+    }
+}
@@ -0,0 +1,237 @@
+use crate::{
+    error::Error,
+    gas_estimator::{ChainContext, GasEstimate, Tx},
+    state_cache::HitMiss,
+};
+use lru::LruCache;
+use revm::primitives::{Address, B256};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+
+type SharedResult = Result<GasEstimate, Arc<Error>>;
+
+#[derive(Debug, Clone)]
+pub struct CoalescerConfig {
+    /// How long a result is served again, further bounded by the chain's block time
+    pub result_ttl: Duration,
+    pub max_results: usize,
+}
+
+impl Default for CoalescerConfig {
+    fn default() -> Self {
+        Self {
+            result_ttl: Duration::from_secs(2),
+            max_results: 10_000,
+        }
+    }
+}
+
+/// What makes two estimates identical: the transaction, the block it runs against
+/// and the per-request options that change the result
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EstimateKey {
+    chain_id: u64,
+    block_hash: B256,
+    /// Canonical JSON of the transaction
+    tx: String,
+    verify_state: bool,
+    profile: Option<Address>,
+}
+
+impl EstimateKey {
+    pub fn new(tx: &Tx, chain: &ChainContext, verify_state: bool) -> Self {
+        Self {
+            chain_id: chain.profile.chain_id,
+            block_hash: chain.block_hash,
+            tx: serde_json::to_string(tx).expect("transactions serialize"),
+            verify_state,
+            profile: None,
+        }
+    }
+
+    /// Profiled estimates carry their profile, so they are not shared with plain ones
+    pub fn with_profile(mut self, profile: Option<Address>) -> Self {
+        self.profile = profile;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoalescerStats {
+    /// Estimates actually computed
+    pub executed: u64,
+    /// Requests that joined an identical estimate already in flight
+    pub coalesced: u64,
+    /// Lookups of recently computed results
+    pub results: HitMiss,
+    pub cached_results: usize,
+    pub in_flight: usize,
+}
+
+/// Shares one computation between identical estimates in flight at the same time,
+/// and serves recent results again while they are fresh
+pub struct EstimateCoalescer {
+    in_flight: Mutex<HashMap<EstimateKey, Arc<OnceCell<SharedResult>>>>,
+    results: Mutex<LruCache<EstimateKey, (Instant, GasEstimate)>>,
+    config: CoalescerConfig,
+    executed: AtomicU64,
+    coalesced: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl EstimateCoalescer {
+    pub fn new(config: CoalescerConfig) -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+            results: Mutex::new(LruCache::new(
+                NonZeroUsize::new(config.max_results).unwrap_or(NonZeroUsize::MIN),
+            )),
+            config,
+            executed: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Result of the estimate identified by `key`: a fresh cached one, the one of an identical
+    /// estimate in flight, or `estimate` itself. If the request computing an estimate goes away,
+    /// one of the requests waiting for it takes over.
+    pub async fn run<F, Fut>(
+        &self,
+        key: EstimateKey,
+        block_time: Option<Duration>,
+        estimate: F,
+    ) -> Result<GasEstimate, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<GasEstimate, Error>>,
+    {
+        let ttl = block_time.map_or(self.config.result_ttl, |block_time| {
+            block_time.min(self.config.result_ttl)
+        });
+        if let Some(estimate) = self.cached(&key, ttl) {
+            return Ok(estimate);
+        }
+
+        let cell = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(cell) => {
+                    self.coalesced.fetch_add(1, Ordering::Relaxed);
+                    cell.clone()
+                }
+                None => {
+                    let cell = Arc::new(OnceCell::new());
+                    in_flight.insert(key.clone(), cell.clone());
+                    cell
+                }
+            }
+        };
+        let in_flight = InFlight {
+            coalescer: self,
+            key: &key,
+            cell: &cell,
+        };
+        let result = cell
+            .get_or_init(|| async {
+                self.executed.fetch_add(1, Ordering::Relaxed);
+                estimate().await.map_err(Arc::new)
+            })
+            .await
+            .clone();
+        drop(in_flight);
+
+        match result {
+            Ok(estimate) => {
+                if !ttl.is_zero() {
+                    self.results
+                        .lock()
+                        .unwrap()
+                        .put(key.clone(), (Instant::now(), estimate.clone()));
+                }
+                Ok(estimate)
+            }
+            Err(e) => Err(Error::Shared(e)),
+        }
+    }
+
+    fn cached(&self, key: &EstimateKey, ttl: Duration) -> Option<GasEstimate> {
+        let mut results = self.results.lock().unwrap();
+        let estimate = match results.get(key) {
+            Some((computed_at, estimate)) if computed_at.elapsed() < ttl => Some(estimate.clone()),
+            Some(_) => {
+                results.pop(key);
+                None
+            }
+            None => None,
+        };
+        let counter = if estimate.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        estimate
+    }
+
+    pub fn stats(&self) -> CoalescerStats {
+        CoalescerStats {
+            executed: self.executed.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            results: HitMiss {
+                hits: self.hits.load(Ordering::Relaxed),
+                misses: self.misses.load(Ordering::Relaxed),
+            },
+            cached_results: self.results.lock().unwrap().len(),
+            in_flight: self.in_flight.lock().unwrap().len(),
+        }
+    }
+}
+
+impl Default for EstimateCoalescer {
+    fn default() -> Self {
+        Self::new(CoalescerConfig::default())
+    }
+}
+
+impl std::fmt::Debug for EstimateCoalescer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EstimateCoalescer")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+/// Removes an estimate from the in-flight map once it completed, or once the last request
+/// waiting for it went away, so a later identical request starts over instead of joining nothing
+struct InFlight<'a> {
+    coalescer: &'a EstimateCoalescer,
+    key: &'a EstimateKey,
+    cell: &'a Arc<OnceCell<SharedResult>>,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.coalescer.in_flight.lock().unwrap();
+        if in_flight
+            .get(self.key)
+            .is_some_and(|current| Arc::ptr_eq(current, self.cell))
+            // Held by the map and by this request only
+            && (self.cell.initialized() || Arc::strong_count(self.cell) <= 2)
+        {
+            in_flight.remove(self.key);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use crate::{
+    abi::abi_values,
+    error::Error,
+    gas_estimator::{GasEstimate, Tx},
+};
+use alloy::{
+    dyn_abi::DynSolValue,
+    json_abi::JsonAbi,
+    primitives::{address, hex},
+};
+use revm::primitives::{Address, Bytes, B256, U256};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+/// Runtime code size limit of EIP-170
+pub const MAX_CODE_SIZE: usize = 24_576;
+
+/// Initcode size limit of EIP-3860
+pub const MAX_INITCODE_SIZE: usize = 2 * MAX_CODE_SIZE;
+
+/// Deterministic deployment proxy Foundry deploys through with CREATE2 when given a salt
+pub const CREATE2_DEPLOYER: Address = address!("0x4e59b44847b379578588920cA78FbF26c0B4956C");
+
+/// Foundry build artifact, as written to `contracts/out/<File>.sol/<Contract>.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Artifact {
+    pub abi: JsonAbi,
+    pub bytecode: ArtifactBytecode,
+    pub deployed_bytecode: ArtifactBytecode,
+    /// Index of the contract's source file in its source maps
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+    /// Only written when Foundry is configured with `ast = true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ast: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArtifactBytecode {
+    /// Hex code, with `__$<hash>$__` placeholders where libraries are not linked yet
+    pub object: String,
+    /// Placeholders to fill with library addresses, by source file and library name
+    #[serde(default)]
+    pub link_references: BTreeMap<String, BTreeMap<String, Vec<LinkOffset>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_map: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LinkOffset {
+    /// Byte offset of the placeholder
+    pub start: usize,
+    pub length: usize,
+}
+
+impl Artifact {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let artifact = std::fs::read_to_string(path)
+            .map_err(|e| Error::InvalidArtifact(format!("{}: {e}", path.display())))?;
+        serde_json::from_str(&artifact)
+            .map_err(|e| Error::InvalidArtifact(format!("{}: {e}", path.display())))
+    }
+
+    /// Linked creation code followed by the ABI-encoded constructor `args`
+    pub fn initcode(
+        &self,
+        args: &[Value],
+        libraries: &HashMap<String, Address>,
+    ) -> Result<Bytes, Error> {
+        let mut initcode = self.bytecode.link(libraries)?.to_vec();
+        if initcode.is_empty() {
+            return Err(Error::InvalidArtifact(
+                "no creation code, the contract is abstract or an interface".to_string(),
+            ));
+        }
+        let inputs = self
+            .abi
+            .constructor
+            .as_ref()
+            .map_or(&[][..], |constructor| &constructor.inputs);
+        let values = abi_values(inputs, args)?;
+        initcode.extend(DynSolValue::Tuple(values).abi_encode_params());
+        Ok(initcode.into())
+    }
+
+    /// Path of the contract's source file, as in its metadata or AST
+    pub fn source_path(&self) -> Option<String> {
+        let target = self
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.pointer("/settings/compilationTarget"))
+            .and_then(Value::as_object)
+            .and_then(|target| target.keys().next());
+        let ast_path = self
+            .ast
+            .as_ref()
+            .and_then(|ast| ast.get("absolutePath"))
+            .and_then(Value::as_str);
+        target.map(String::as_str).or(ast_path).map(str::to_string)
+    }
+
+    /// Size of the runtime code, which linking and immutables leave unchanged
+    pub fn runtime_code_size(&self) -> usize {
+        let object = &self.deployed_bytecode.object;
+        object.strip_prefix("0x").unwrap_or(object).len() / 2
+    }
+}
+
+impl ArtifactBytecode {
+    /// The code with every library placeholder replaced by the address in `libraries`,
+    /// keyed by `<file>:<name>` or by the name alone
+    pub fn link(&self, libraries: &HashMap<String, Address>) -> Result<Bytes, Error> {
+        let mut object = self
+            .object
+            .strip_prefix("0x")
+            .unwrap_or(&self.object)
+            .to_string();
+        if !object.is_ascii() {
+            return Err(Error::InvalidArtifact("bytecode is not hex".to_string()));
+        }
+        for (file, file_libraries) in &self.link_references {
+            for (name, offsets) in file_libraries {
+                let address = libraries
+                    .get(&format!("{file}:{name}"))
+                    .or_else(|| libraries.get(name))
+                    .ok_or_else(|| {
+                        Error::InvalidArtifact(format!("library {file}:{name} is not linked"))
+                    })?;
+                for offset in offsets {
+                    let placeholder = offset.start * 2..(offset.start + offset.length) * 2;
+                    if offset.length != Address::len_bytes() || placeholder.end > object.len() {
+                        return Err(Error::InvalidArtifact(format!(
+                            "invalid link reference of {file}:{name} at {}",
+                            offset.start
+                        )));
+                    }
+                    object.replace_range(placeholder, &hex::encode(address));
+                }
+            }
+        }
+        hex::decode(&object)
+            .map(Bytes::from)
+            .map_err(|e| Error::InvalidArtifact(format!("bytecode is not hex: {e}")))
+    }
+
+    /// The code with library placeholders zeroed, enough to walk its instructions
+    pub fn unlinked(&self) -> Result<Bytes, Error> {
+        let placeholders = self
+            .link_references
+            .values()
+            .flat_map(|libraries| libraries.keys())
+            .map(|name| (name.clone(), Address::ZERO))
+            .collect();
+        self.link(&placeholders)
+    }
+}
+
+/// Contract deployment to estimate: a CREATE transaction, or a call to the
+/// CREATE2 deployer when given a salt
+#[derive(Debug, Clone)]
+pub struct Deployment {
+    pub initcode: Bytes,
+    pub runtime_code_size: usize,
+    pub from: Option<Address>,
+    pub value: U256,
+    /// Nonce of the CREATE transaction, the sender's next one when absent
+    pub nonce: Option<u64>,
+    pub salt: Option<B256>,
+}
+
+impl Deployment {
+    pub fn new(
+        artifact: &Artifact,
+        args: &[Value],
+        libraries: &HashMap<String, Address>,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            initcode: artifact.initcode(args, libraries)?,
+            runtime_code_size: artifact.runtime_code_size(),
+            from: None,
+            value: U256::ZERO,
+            nonce: None,
+            salt: None,
+        })
+    }
+
+    pub fn transaction(&self) -> Tx {
+        let (to, data) = match self.salt {
+            // The deployer takes the salt followed by the initcode
+            Some(salt) => (
+                Some(CREATE2_DEPLOYER),
+                [salt.as_slice(), &self.initcode].concat().into(),
+            ),
+            None => (None, self.initcode.clone()),
+        };
+        Tx {
+            from: self.from,
+            to,
+            value: self.value,
+            data: Some(data),
+            nonce: self.nonce,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeploymentEstimate {
+    pub estimate: GasEstimate,
+    /// Where the contract lands, from the sender and nonce for CREATE, or from the
+    /// deployer, salt and initcode for CREATE2
+    pub address: Address,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<B256>,
+    pub initcode: Bytes,
+    pub initcode_size: usize,
+    pub runtime_code_size: usize,
+    /// Runtime code above the EIP-170 limit fails to deploy, whatever the gas
+    pub exceeds_code_size_limit: bool,
+    /// Initcode above the EIP-3860 limit is rejected before running
+    pub exceeds_initcode_size_limit: bool,
+}
+
+impl DeploymentEstimate {
+    pub fn new(deployment: Deployment, address: Address, estimate: GasEstimate) -> Self {
+        Self {
+            estimate,
+            address,
+            salt: deployment.salt,
+            initcode_size: deployment.initcode.len(),
+            exceeds_code_size_limit: deployment.runtime_code_size > MAX_CODE_SIZE,
+            exceeds_initcode_size_limit: deployment.initcode.len() > MAX_INITCODE_SIZE,
+            runtime_code_size: deployment.runtime_code_size,
+            initcode: deployment.initcode,
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Our estimate next to the upstream node's `eth_estimateGas` at the same block
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Comparison {
+    pub estimated_gas: u128,
+    /// `None` when the upstream failed to estimate, see `upstream_error`
+    pub upstream_gas: Option<u128>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_error: Option<String>,
+    /// Our estimate minus the upstream's
+    pub difference: Option<i128>,
+    /// `difference` relative to the upstream's estimate
+    pub difference_percent: Option<f64>,
+}
+
+impl Comparison {
+    pub fn new(estimated_gas: u128, upstream: Result<u128, String>) -> Self {
+        match upstream {
+            Ok(upstream_gas) => {
+                let difference = estimated_gas as i128 - upstream_gas as i128;
+                Self {
+                    estimated_gas,
+                    upstream_gas: Some(upstream_gas),
+                    upstream_error: None,
+                    difference: Some(difference),
+                    difference_percent: (upstream_gas > 0)
+                        .then(|| difference as f64 / upstream_gas as f64 * 100.0),
+                }
+            }
+            Err(e) => Self {
+                estimated_gas,
+                upstream_gas: None,
+                upstream_error: Some(e),
+                difference: None,
+                difference_percent: None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DivergenceStats {
+    pub compared: u64,
+    /// Comparisons off by more than the threshold
+    pub divergent: u64,
+    pub upstream_errors: u64,
+}
+
+/// Server-wide comparison of every estimate with the upstream's, counting the ones that diverge
+#[derive(Debug, Default)]
+pub struct DivergenceMonitor {
+    /// Relative difference above which an estimate is divergent. `None` leaves
+    /// comparisons to the requests that ask for them.
+    threshold_percent: Option<f64>,
+    compared: AtomicU64,
+    divergent: AtomicU64,
+    upstream_errors: AtomicU64,
+}
+
+impl DivergenceMonitor {
+    pub fn new(threshold_percent: Option<f64>) -> Self {
+        Self {
+            threshold_percent,
+            ..Default::default()
+        }
+    }
+
+    /// Whether every estimate is compared
+    pub fn is_enabled(&self) -> bool {
+        self.threshold_percent.is_some()
+    }
+
+    /// Count `comparison`, returning whether it diverges
+    pub fn record(&self, comparison: &Comparison) -> bool {
+        self.compared.fetch_add(1, Ordering::Relaxed);
+        if comparison.upstream_error.is_some() {
+            self.upstream_errors.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let divergent = match (self.threshold_percent, comparison.difference_percent) {
+            (Some(threshold), Some(percent)) => percent.abs() > threshold,
+            // Any gas where the upstream expects none is off
+            (Some(_), None) => comparison.difference != Some(0),
+            (None, _) => false,
+        };
+        if divergent {
+            self.divergent.fetch_add(1, Ordering::Relaxed);
+        }
+        divergent
+    }
+
+    pub fn stats(&self) -> DivergenceStats {
+        DivergenceStats {
+            compared: self.compared.load(Ordering::Relaxed),
+            divergent: self.divergent.load(Ordering::Relaxed),
+            upstream_errors: self.upstream_errors.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,9 +1,111 @@
 use alloy::transports::{RpcError, TransportError, TransportErrorKind};
+use revm::primitives::{Address, B256};
+use std::{sync::Arc, time::Duration};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    #[allow(dead_code)]
     #[error("Alloy Transport Error: {0}")]
     TransportError(TransportError),
     #[error("Alloy Rpc Error: {0}")]
     RpcError(RpcError<TransportErrorKind>),
+    #[error("Alloy Contract Error: {0}")]
+    ContractCall(alloy::contract::Error),
+    #[error(
+        "Nonce too low for {address}: transaction nonce {tx_nonce}, account nonce {account_nonce}"
+    )]
+    NonceTooLow {
+        address: Address,
+        tx_nonce: u64,
+        account_nonce: u64,
+    },
+    #[error("Transaction chain id {tx_chain_id} does not match upstream chain id {chain_id}")]
+    ChainIdMismatch { tx_chain_id: u64, chain_id: u64 },
+    #[error("Block not found")]
+    BlockNotFound,
+    #[error("Chain profile of chain {chain_id} lists no hardfork active at block {block_number}")]
+    BlockNotCovered { chain_id: u64, block_number: u64 },
+    #[error("Invalid chain profile: {0}")]
+    ChainProfile(toml::de::Error),
+    #[error("IO Error: {0}")]
+    Io(std::io::Error),
+    #[error("HTTP Client Error: {0}")]
+    HttpClient(alloy::transports::http::reqwest::Error),
+    #[error("Invalid RPC URL: {0}")]
+    InvalidRpcUrl(String),
+    #[error("State store: {0}")]
+    StateStore(String),
+    #[error("Block header does not hash to {0}")]
+    HeaderHashMismatch(B256),
+    #[error(
+        "Block {block_number} is {block_hash} upstream but {trusted_hash} on the trusted upstream"
+    )]
+    UntrustedBlock {
+        block_number: u64,
+        block_hash: B256,
+        trusted_hash: B256,
+    },
+    #[error("State of {address} does not match the state root of block {block_hash}: {reason}")]
+    StateProofMismatch {
+        address: Address,
+        block_hash: B256,
+        reason: String,
+    },
+    #[error("EVM rejected the transaction: {0}")]
+    EvmExecution(String),
+    #[error("Simulation did not converge within {0} rounds")]
+    TooManyRounds(usize),
+    #[error("Simulation did not finish within {0:?}")]
+    SimulationTimeout(Duration),
+    #[error("Simulation fetched more than {0} storage slots")]
+    TooManySlots(usize),
+    #[error("Simulation round exceeded {0} EVM steps")]
+    TooManySteps(u64),
+    #[error("EVM execution queue is full ({0} waiting)")]
+    EvmQueueFull(usize),
+    #[error("EVM worker stopped before finishing the simulation")]
+    EvmWorkerLost,
+    #[error("Call to {address} failed: {reason}")]
+    ViewCallFailed { address: Address, reason: String },
+    #[error("Invalid margin policy: {0}")]
+    InvalidMarginPolicy(String),
+    #[error("Transaction {0} not found")]
+    TransactionNotFound(B256),
+    #[error("Transaction {0} is not mined yet")]
+    TransactionPending(B256),
+    #[error(
+        "Trace of block {block_hash} covers {traced} transactions, not the one at index {index}"
+    )]
+    IncompleteBlockTrace {
+        block_hash: B256,
+        traced: usize,
+        index: u64,
+    },
+    #[error("Invalid raw transaction: {0}")]
+    InvalidRawTransaction(String),
+    #[error("Invalid ABI call: {0}")]
+    InvalidAbiCall(String),
+    #[error("Invalid artifact: {0}")]
+    InvalidArtifact(String),
+    #[error("CREATE2 deployer {0} is not deployed on this chain")]
+    Create2DeployerMissing(Address),
+    #[error("Deployment to {address} fails: the constructor {reason}")]
+    DeploymentFailed { address: Address, reason: String },
+    #[error("Local execution failed: {0}")]
+    LocalExecution(String),
+    #[error("Invalid gas snapshot: {0}")]
+    InvalidSnapshot(String),
+    /// Error of an estimate shared by identical requests
+    #[error("{0}")]
+    Shared(Arc<Error>),
+}
+
+impl Error {
+    /// The error itself, or the one it shares
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Shared(e) => e.root(),
+            e => e,
+        }
+    }
 }
@@ -0,0 +1,114 @@
+use crate::error::Error;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug, Clone)]
+pub struct EvmPoolConfig {
+    /// Threads executing the EVM
+    pub threads: usize,
+    /// Executions waiting for a thread before new ones are rejected
+    pub queue_size: usize,
+}
+
+impl Default for EvmPoolConfig {
+    fn default() -> Self {
+        Self {
+            threads: thread::available_parallelism().map_or(4, |threads| threads.get()),
+            queue_size: 256,
+        }
+    }
+}
+
+/// Dedicated threads EVM execution runs on, so a heavy simulation
+/// never blocks the async runtime serving other requests
+pub struct EvmPool {
+    jobs: SyncSender<Job>,
+    config: EvmPoolConfig,
+}
+
+impl EvmPool {
+    pub fn new(config: EvmPoolConfig) -> Self {
+        let (jobs, queue) = sync_channel::<Job>(config.queue_size);
+        let queue = Arc::new(Mutex::new(queue));
+        for index in 0..config.threads.max(1) {
+            let queue = queue.clone();
+            thread::Builder::new()
+                .name(format!("evm-{index}"))
+                .spawn(move || Self::work(&queue))
+                .expect("failed to spawn EVM thread");
+        }
+        Self { jobs, config }
+    }
+
+    /// Run jobs until the pool is dropped. A panicking job fails its own request, not the thread.
+    fn work(queue: &Mutex<Receiver<Job>>) {
+        loop {
+            let job = queue.lock().unwrap().recv();
+            match job {
+                Ok(job) => {
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                }
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Run `execute` on the pool. `execute` is handed a flag that is raised when the
+    /// returned future is dropped, e.g. because the client disconnected, and should stop early once it is.
+    pub async fn run<T, F>(&self, execute: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(Arc<AtomicBool>) -> T + Send + 'static,
+    {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (result_tx, result_rx) = oneshot::channel();
+        let flag = cancelled.clone();
+        let job: Job = Box::new(move || {
+            // Executions cancelled while queued are skipped
+            if !flag.load(Ordering::Relaxed) {
+                let _ = result_tx.send(execute(flag));
+            }
+        });
+        match self.jobs.try_send(job) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => return Err(Error::EvmQueueFull(self.config.queue_size)),
+            Err(TrySendError::Disconnected(_)) => return Err(Error::EvmWorkerLost),
+        }
+
+        let _cancel_on_drop = CancelOnDrop(cancelled);
+        result_rx.await.map_err(|_| Error::EvmWorkerLost)
+    }
+}
+
+impl Default for EvmPool {
+    fn default() -> Self {
+        Self::new(EvmPoolConfig::default())
+    }
+}
+
+impl std::fmt::Debug for EvmPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EvmPool")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+/// Raises the cancellation flag of an execution whose caller went away
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
@@ -1,27 +1,71 @@
 use crate::{
+    arbitrum,
+    chain_profile::{ChainProfile, ChainRegistry, GasModel},
+    coalescer::{EstimateCoalescer, EstimateKey},
+    deploy::{Deployment, DeploymentEstimate, CREATE2_DEPLOYER},
+    divergence::{Comparison, DivergenceMonitor},
     error::Error,
+    evm_pool::EvmPool,
+    margin::MarginPolicy,
+    op_stack::{encode_unsigned, L1FeeParams, GAS_PRICE_ORACLE},
+    profile::ExecutionProfile,
+    provider_pool::{Upstream, UpstreamCapabilities},
+    replay::{ReplayOutcome, ReplayReport, StateOverlay, TransactionDiff},
+    state_cache::{CachedAccount, StateCache},
+    state_proof::verify_account_proof,
+    state_store::{BlockKey, StateStore, StoredHeader},
     tracer::Tracer,
-    utils::{calculate_calldata_cost, calculate_contract_creation_cost},
+    utils::{access_list_cost, calculate_calldata_cost, calldata_floor, precompile_gas},
 };
 use alloy::{
-    eips::BlockId,
-    network::Ethereum,
-    primitives::U64,
+    consensus::{transaction::SignerRecoverable, Transaction as ConsensusTransaction, TxEnvelope},
+    eips::{eip2718::Decodable2718, eip7702::SignedAuthorization, BlockId},
+    primitives::{address, utils::format_units, U64},
     providers::{Provider, RootProvider},
+    rpc::types::{
+        state::{AccountOverride, StateOverride},
+        trace::geth::{
+            DiffMode, GethDebugTracingCallOptions, GethDebugTracingOptions, PreStateConfig,
+            PreStateMode,
+        },
+        Block, BlockTransactionsKind, Header, Transaction, TransactionReceipt, TransactionRequest,
+    },
+};
+use futures::{
+    future::try_join_all,
+    stream::{self, StreamExt, TryStreamExt},
 };
 use revm::{
-    context::{transaction::AccessList, tx::TxEnvBuilder},
+    context::{result::ExecutionResult, transaction::AccessList, tx::TxEnvBuilder, TxEnv},
+    context_interface::either::Either,
     database::{CacheDB, EmptyDB},
+    handler::EthPrecompiles,
     inspector::InspectEvm,
-    primitives::{keccak256, Address, Bytes, TxKind, U256},
+    precompile::Precompiles,
+    primitives::{
+        eip4844::{BLOB_BASE_FEE_UPDATE_FRACTION_CANCUN, BLOB_BASE_FEE_UPDATE_FRACTION_PRAGUE},
+        hardfork::SpecId,
+        keccak256, Address, Bytes, TxKind, B256, KECCAK_EMPTY, U256,
+    },
     state::{AccountInfo, Bytecode},
     Context, MainBuilder, MainContext,
 };
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    future::IntoFuture,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::time::timeout_at;
 
-pub const BLOCK_GAS_LIMIT: u64 = 30_000_000; // or 36,000,000
+/// Upstream fetches in flight at once while loading the state a simulation round discovered
+const MAX_CONCURRENT_FETCHES: usize = 16;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Sender used by nodes for `eth_estimateGas` requests without `from`
+pub const DEFAULT_SENDER: Address = address!("0x0000000000000000000000000000000000000000");
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Tx {
     // Standard transaction fields
     pub from: Option<Address>,
@@ -46,9 +90,66 @@ pub struct Tx {
     #[serde(alias = "accessList")]
     pub access_list: Option<AccessList>,
 
-    // Transaction type (0=Legacy, 1=EIP-2930, 2=EIP-1559)
+    // Transaction type (0=Legacy, 1=EIP-2930, 2=EIP-1559, 3=EIP-4844, 4=EIP-7702)
     #[serde(alias = "type")]
     pub transaction_type: Option<U64>,
+
+    // EIP-4844 blobs
+    #[serde(default, alias = "blobVersionedHashes")]
+    pub blob_versioned_hashes: Option<Vec<B256>>,
+    #[serde(default, alias = "maxFeePerBlobGas")]
+    pub max_fee_per_blob_gas: Option<u128>,
+
+    // EIP-7702 code delegations
+    #[serde(default, alias = "authorizationList")]
+    pub authorization_list: Option<Vec<SignedAuthorization>>,
+}
+
+impl Tx {
+    /// Estimate request of a signed transaction of any type, sent by `from`
+    pub fn from_signed(tx: &impl ConsensusTransaction, from: Address) -> Self {
+        Self {
+            from: Some(from),
+            to: tx.to(),
+            value: tx.value(),
+            data: Some(tx.input().clone()),
+            nonce: Some(tx.nonce()),
+            chain_id: tx.chain_id().map(U64::from),
+            gas_limit: Some(tx.gas_limit()),
+            gas_price: tx.gas_price(),
+            max_fee_per_gas: tx.is_dynamic_fee().then(|| tx.max_fee_per_gas()),
+            max_priority_fee_per_gas: tx.max_priority_fee_per_gas(),
+            access_list: tx.access_list().cloned(),
+            transaction_type: Some(U64::from(tx.ty())),
+            blob_versioned_hashes: tx.blob_versioned_hashes().map(<[B256]>::to_vec),
+            max_fee_per_blob_gas: tx.max_fee_per_blob_gas(),
+            authorization_list: tx.authorization_list().map(<[SignedAuthorization]>::to_vec),
+        }
+    }
+
+    /// Decode an EIP-2718 encoded signed transaction, recovering its sender from the signature
+    pub fn from_raw(raw: &[u8]) -> Result<Self, Error> {
+        let mut buf = raw;
+        let envelope = TxEnvelope::decode_2718(&mut buf)
+            .map_err(|e| Error::InvalidRawTransaction(e.to_string()))?;
+        if !buf.is_empty() {
+            return Err(Error::InvalidRawTransaction(format!(
+                "{} trailing bytes",
+                buf.len()
+            )));
+        }
+        let from = envelope
+            .recover_signer()
+            .map_err(|e| Error::InvalidRawTransaction(e.to_string()))?;
+        Ok(Self::from_signed(&envelope, from))
+    }
+
+    /// Whether the transaction delegates code to any account
+    pub fn has_authorizations(&self) -> bool {
+        self.authorization_list
+            .as_ref()
+            .is_some_and(|list| !list.is_empty())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -56,7 +157,62 @@ pub struct GasEstimate {
     pub estimated_gas: u128,
     pub gas_price: u128,
     pub total_cost_wei: u128,
+    /// Total cost in the chain's native currency, e.g. "0.00042 ETH"
+    pub total_cost_native: String,
+    pub chain_id: u64,
     pub breakdown: GasBreakdown,
+    /// Whether the simulated state was proven against the state root of a block
+    /// the trusted upstream confirmed
+    #[serde(default)]
+    pub verified: bool,
+    /// EVM runs it took for the simulation to discover all the state it touches
+    #[serde(default)]
+    pub simulation_rounds: usize,
+    /// `estimated_gas` padded by the margin policy of the request or the server
+    #[serde(default)]
+    pub padded_gas: u128,
+    #[serde(default)]
+    pub execution: ExecutionSummary,
+    /// Why the simulated execution failed, the estimate being the gas it spent until then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<String>,
+    /// The upstream node's own estimate, when asked for with `compare`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comparison: Option<Comparison>,
+    /// Gas by program counter of the profiled code, when asked for with `profile`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<ExecutionProfile>,
+}
+
+/// What the simulated execution did that makes its gas less predictable
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExecutionSummary {
+    /// Accounts and storage slots first accessed outside the access list
+    pub cold_accesses: usize,
+    /// Calls contracts made on top of the transaction's own call
+    pub external_calls: usize,
+    pub gas_refunded: u64,
+}
+
+/// Outcome of a converged simulation
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub gas_used: u128,
+    pub rounds: usize,
+    pub profile: Option<ExecutionProfile>,
+    pub execution: ExecutionSummary,
+    /// Why the final round did not succeed, `None` when it did
+    pub failure: Option<String>,
+}
+
+/// Why `result` did not succeed, `None` when it did
+pub fn failure_reason(result: &ExecutionResult) -> Option<String> {
+    match result {
+        ExecutionResult::Success { .. } => None,
+        ExecutionResult::Revert { output, .. } if output.is_empty() => Some("reverted".to_string()),
+        ExecutionResult::Revert { output, .. } => Some(format!("reverted with {output}")),
+        ExecutionResult::Halt { reason, .. } => Some(format!("halted with {reason:?}")),
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -65,6 +221,12 @@ pub struct GasBreakdown {
     pub data_cost: u128,
     pub contract_creation_cost: u128,
     pub execution_cost: u128,
+    /// L2 gas Arbitrum charges for the L1 calldata, on top of the L2 execution above
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub l1_gas: Option<u128>,
+    /// Fee in wei, not gas, OP-stack chains charge for posting the transaction to L1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub l1_data_fee: Option<u128>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -75,214 +237,1304 @@ pub struct NetworkGasInfo {
     pub latest_block_number: u64,
 }
 
+/// Chain and block an estimate runs against
+#[derive(Debug, Clone)]
+pub struct ChainContext {
+    pub profile: ChainProfile,
+    pub spec: SpecId,
+    pub block_number: u64,
+    /// Block state is read at, pinned so every fetch of one estimate sees the same state
+    pub block_hash: B256,
+    /// State root of the pinned block, that verified state is proven against
+    pub state_root: B256,
+    /// Block the transaction executes in: the pinned block itself for estimates,
+    /// the next one when a mined transaction is replayed on its parent's state
+    pub execution_block_number: u64,
+    pub timestamp: u64,
+    pub block_gas_limit: u64,
+    /// Header fields of the block the transaction executes in, that
+    /// `BASEFEE`, `COINBASE`, `PREVRANDAO` and `BLOBBASEFEE` read
+    pub base_fee: u64,
+    pub beneficiary: Address,
+    pub prevrandao: B256,
+    pub excess_blob_gas: Option<u64>,
+    /// Precompiles the EVM runs at `spec` on this chain
+    pub precompiles: &'static Precompiles,
+    /// Changes of the transactions mined before a replayed one in its block, on top of the pinned state
+    pub overlay: Option<Arc<StateOverlay>>,
+    /// Gas price of estimates pinned to a past block: its base fee.
+    /// The upstream's current gas price when absent.
+    pub gas_price: Option<u128>,
+    /// Block read back from the state store, whose state is then read from it too
+    /// rather than prefetched from the upstream
+    pub from_store: bool,
+}
+
+impl ChainContext {
+    /// Whether `address` is a precompile of the active hardfork or a chain-specific one
+    pub fn is_precompile(&self, address: Address) -> bool {
+        self.precompiles.contains(&address) || self.profile.extra_precompiles.contains(&address)
+    }
+
+    pub fn block_key(&self) -> BlockKey {
+        BlockKey {
+            chain_id: self.profile.chain_id,
+            block_number: self.block_number,
+            block_hash: self.block_hash,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EstimatorConfig {
+    pub chains: Arc<ChainRegistry>,
+    /// Sender simulated when a transaction has no `from`
+    pub default_sender: Address,
+    /// Balance the default sender is funded with, so value and fees never fail the simulation
+    pub default_sender_balance: U256,
+    /// Upstream state shared across requests
+    pub state_cache: Arc<StateCache>,
+    /// On-disk copy of fetched state, read before going to the upstream
+    pub state_store: Option<Arc<StateStore>>,
+    /// Block estimates run against, the latest one when absent
+    pub block: Option<u64>,
+    /// Only simulate state proven by `eth_getProof` against the pinned block's state root.
+    /// The shared caches and `debug_traceCall` prestate are not trusted in this mode.
+    pub verify_state: bool,
+    /// Upstream trusted to report canonical block hashes. With `verify_state`, the pinned block
+    /// must have the same hash there, tying the proven state to the chain rather than to the
+    /// serving upstream's own header. Without it, verified mode only checks proof consistency.
+    pub trusted_upstream: Option<RootProvider>,
+    /// Bounds on the simulation loop
+    pub limits: SimulationLimits,
+    /// Threads simulations run the EVM on, shared across requests
+    pub evm_pool: Arc<EvmPool>,
+    /// Identical estimates in flight and recent results, shared across requests
+    pub coalescer: Arc<EstimateCoalescer>,
+    /// Padding reported on top of the raw estimate
+    pub margin: MarginPolicy,
+    /// Report the upstream node's `eth_estimateGas` next to ours
+    pub compare: bool,
+    /// Server-wide comparison with the upstream, shared across requests
+    pub divergence: Arc<DivergenceMonitor>,
+    /// Report the gas spent by each instruction of the code at this address
+    pub profile: Option<Address>,
+}
+
+impl Default for EstimatorConfig {
+    fn default() -> Self {
+        Self {
+            chains: Arc::new(ChainRegistry::builtin()),
+            default_sender: DEFAULT_SENDER,
+            // 1 billion ETH
+            default_sender_balance: U256::from(10u128.pow(18) * 1_000_000_000),
+            state_cache: Arc::new(StateCache::default()),
+            state_store: None,
+            block: None,
+            verify_state: false,
+            trusted_upstream: None,
+            limits: SimulationLimits::default(),
+            evm_pool: Arc::new(EvmPool::default()),
+            coalescer: Arc::new(EstimateCoalescer::default()),
+            margin: MarginPolicy::default(),
+            compare: false,
+            divergence: Arc::new(DivergenceMonitor::default()),
+            profile: None,
+        }
+    }
+}
+
+/// Bounds on a simulation, so state that keeps leading to new state cannot hold a request forever
+#[derive(Debug, Clone)]
+pub struct SimulationLimits {
+    /// EVM runs before the simulation has to converge
+    pub max_rounds: usize,
+    /// Wall-clock budget of the simulation, including state fetches
+    pub max_duration: Duration,
+    /// Storage slots discovered and fetched over all rounds
+    pub max_fetched_slots: usize,
+    /// EVM steps in a single round
+    pub max_steps: u64,
+}
+
+impl Default for SimulationLimits {
+    fn default() -> Self {
+        Self {
+            max_rounds: 32,
+            max_duration: Duration::from_secs(30),
+            max_fetched_slots: 10_000,
+            max_steps: 50_000_000,
+        }
+    }
+}
+
 pub struct GasEstimator {
     provider: RootProvider,
+    capabilities: Arc<UpstreamCapabilities>,
+    config: EstimatorConfig,
 }
 
 impl GasEstimator {
-    pub fn new(rpc_url: &str) -> Self {
-        let provider = RootProvider::<Ethereum>::new_http(rpc_url.parse().unwrap());
-        Self { provider }
+    pub fn new(upstream: Upstream, config: EstimatorConfig) -> Self {
+        Self {
+            provider: upstream.provider,
+            capabilities: upstream.capabilities,
+            config,
+        }
     }
 
-    /// Custom gas estimation implementation from scratch
+    /// Custom gas estimation implementation from scratch.
+    /// Identical estimates against the same block share one computation.
     pub async fn estimate_gas(&self, tx_params: Tx) -> Result<GasEstimate, Error> {
-        // Calculate gas breakdown using our custom logic
-        let breakdown = self.calculate_gas_breakdown(&tx_params).await?;
+        let chain = self.chain_context(&tx_params).await?;
+        self.estimate_on(tx_params, &chain).await
+    }
 
-        // Sum up all gas costs
-        let estimated_gas =
-            breakdown.base_cost + breakdown.contract_creation_cost + breakdown.execution_cost;
+    /// Estimate against the state of an already resolved block
+    async fn estimate_on(&self, tx_params: Tx, chain: &ChainContext) -> Result<GasEstimate, Error> {
+        let key = EstimateKey::new(&tx_params, chain, self.config.verify_state)
+            .with_profile(self.config.profile);
+        let block_time = chain.profile.block_time_ms.map(Duration::from_millis);
+        let mut estimate = self
+            .config
+            .coalescer
+            .run(key, block_time, || self.estimate_at(&tx_params, chain))
+            .await?;
+        // Requests differing only in their margin share the raw estimate
+        estimate.padded_gas = self.config.margin.apply(
+            estimate.estimated_gas,
+            &estimate.execution,
+            chain.block_gas_limit,
+        );
 
-        // Get current gas price information
-        let gas_price = self
+        let monitor = &self.config.divergence;
+        if self.config.compare || monitor.is_enabled() {
+            let comparison = self
+                .compare_with_upstream(&tx_params, chain, estimate.estimated_gas)
+                .await;
+            // Only counted, requests are never written out
+            monitor.record(&comparison);
+            if self.config.compare {
+                estimate.comparison = Some(comparison);
+            }
+        }
+        Ok(estimate)
+    }
+
+    /// Estimate deploying a contract, and the address it lands at
+    pub async fn estimate_deployment(
+        &self,
+        deployment: Deployment,
+    ) -> Result<DeploymentEstimate, Error> {
+        let mut transaction = deployment.transaction();
+        let sender = transaction.from.unwrap_or(self.config.default_sender);
+        let chain = self.chain_context(&transaction).await?;
+        let address = match deployment.salt {
+            Some(salt) => {
+                if !self.is_contract(Some(CREATE2_DEPLOYER), &chain).await? {
+                    return Err(Error::Create2DeployerMissing(CREATE2_DEPLOYER));
+                }
+                CREATE2_DEPLOYER.create2_from_code(salt, &deployment.initcode)
+            }
+            None => {
+                // Read on the block estimated against, like the rest of the state
+                let nonce = match deployment.nonce {
+                    Some(nonce) => nonce,
+                    None => self.account(sender, &chain).await?.nonce,
+                };
+                // The estimate runs at the nonce the address is derived from
+                transaction.nonce = Some(nonce);
+                sender.create(nonce)
+            }
+        };
+        let estimate = self.estimate_on(transaction, &chain).await?;
+        // A contract whose constructor fails never lands, whatever the gas
+        if let Some(reason) = estimate.failure {
+            return Err(Error::DeploymentFailed { address, reason });
+        }
+        Ok(DeploymentEstimate::new(deployment, address, estimate))
+    }
+
+    /// Replay a mined transaction: estimate it on its parent block's state, with the transactions
+    /// before it in the block applied, and compare with the gas its receipt reports
+    pub async fn replay_transaction(&self, tx_hash: B256) -> Result<ReplayReport, Error> {
+        let tx = self
             .provider
-            .get_gas_price()
+            .get_transaction_by_hash(tx_hash)
+            .await
+            .map_err(Error::RpcError)?
+            .ok_or(Error::TransactionNotFound(tx_hash))?;
+        let (Some(block_hash), Some(index)) = (tx.block_hash, tx.transaction_index) else {
+            return Err(Error::TransactionPending(tx_hash));
+        };
+        let receipt = self
+            .provider
+            .get_transaction_receipt(tx_hash)
+            .await
+            .map_err(Error::RpcError)?
+            .ok_or(Error::TransactionPending(tx_hash))?;
+
+        let (_, mut chain) = self
+            .replay_context(BlockId::hash(block_hash), BlockTransactionsKind::Hashes)
+            .await?;
+        if index > 0 {
+            let mut overlay = StateOverlay::default();
+            for diff in self.block_diffs(block_hash, index).await? {
+                overlay.apply(diff);
+            }
+            chain.overlay = Some(Arc::new(overlay));
+        }
+        self.replay(&tx, &receipt, &chain).await
+    }
+
+    /// Replay every transaction of a block, tracing the block once for all of them.
+    /// Transactions failing to estimate are reported without failing the others.
+    pub async fn replay_block(&self, block_number: u64) -> Result<Vec<ReplayOutcome>, Error> {
+        let (block, chain) = self
+            .replay_context(BlockId::number(block_number), BlockTransactionsKind::Full)
+            .await?;
+        let block_hash = block.header.hash;
+        let receipts = self
+            .provider
+            .get_block_receipts(BlockId::hash(block_hash))
+            .await
+            .map_err(Error::RpcError)?
+            .ok_or(Error::BlockNotFound)?;
+        let transactions: Vec<_> = block.transactions.into_transactions().collect();
+        let mut diffs = match transactions.len() {
+            0 | 1 => Vec::new(),
+            count => self.block_diffs(block_hash, count as u64 - 1).await?,
+        }
+        .into_iter();
+
+        let mut overlay = StateOverlay::default();
+        let mut outcomes = Vec::with_capacity(transactions.len());
+        for (tx, receipt) in transactions.iter().zip(&receipts) {
+            let mut chain = chain.clone();
+            if tx.transaction_index.unwrap_or_default() > 0 {
+                if let Some(diff) = diffs.next() {
+                    overlay.apply(diff);
+                }
+                chain.overlay = Some(Arc::new(overlay.clone()));
+            }
+            outcomes.push(ReplayOutcome {
+                tx_hash: *tx.inner.tx_hash(),
+                transaction: Self::replayed_tx(tx, receipt.effective_gas_price),
+                report: self.replay(tx, receipt, &chain).await,
+            });
+        }
+        Ok(outcomes)
+    }
+
+    /// Estimate `tx` against the replay context `chain` and compare with its receipt
+    async fn replay(
+        &self,
+        tx: &Transaction,
+        receipt: &TransactionReceipt,
+        chain: &ChainContext,
+    ) -> Result<ReplayReport, Error> {
+        let tx_params = Self::replayed_tx(tx, receipt.effective_gas_price);
+        let mut estimate = self.estimate_at(&tx_params, chain).await?;
+        estimate.padded_gas = self.config.margin.apply(
+            estimate.estimated_gas,
+            &estimate.execution,
+            chain.block_gas_limit,
+        );
+
+        Ok(ReplayReport::new(
+            *tx.inner.tx_hash(),
+            chain.execution_block_number,
+            tx.transaction_index.unwrap_or_default(),
+            tx_params,
+            estimate,
+            receipt.gas_used,
+            receipt.status(),
+        ))
+    }
+
+    /// The block `block` and the context of replaying its transactions: the parent block's
+    /// state, executed in the environment of the block itself
+    async fn replay_context(
+        &self,
+        block: BlockId,
+        kind: BlockTransactionsKind,
+    ) -> Result<(Block, ChainContext), Error> {
+        let chain_id = self
+            .provider
+            .get_chain_id()
+            .await
+            .map_err(Error::RpcError)?;
+        let block = self
+            .provider
+            .get_block(block)
+            .kind(kind)
+            .await
+            .map_err(Error::RpcError)?
+            .ok_or(Error::BlockNotFound)?;
+        let parent = self
+            .provider
+            .get_block(BlockId::hash(block.header.parent_hash))
+            .await
+            .map_err(Error::RpcError)?
+            .ok_or(Error::BlockNotFound)?;
+        let (header, parent) = (&block.header, &parent.header);
+        if self.config.verify_state {
+            self.check_header(parent).await?;
+        }
+
+        let profile = self.config.chains.get(chain_id);
+        let spec = profile.spec_at(header.number, header.timestamp)?;
+        let chain = ChainContext {
+            spec,
+            precompiles: profile.precompiles(spec),
+            block_number: parent.number,
+            block_hash: parent.hash,
+            state_root: parent.state_root,
+            execution_block_number: header.number,
+            timestamp: header.timestamp,
+            block_gas_limit: profile.block_gas_limit(header.gas_limit),
+            base_fee: header.base_fee_per_gas.unwrap_or_default(),
+            beneficiary: header.beneficiary,
+            prevrandao: header.mix_hash,
+            excess_blob_gas: header.excess_blob_gas,
+            overlay: None,
+            gas_price: None,
+            from_store: false,
+            profile,
+        };
+        Ok((block, chain))
+    }
+
+    /// State changes of the transactions before the one at `index` in block `block_hash`,
+    /// from the upstream's prestate tracer in diff mode
+    async fn block_diffs(&self, block_hash: B256, index: u64) -> Result<Vec<DiffMode>, Error> {
+        let options = GethDebugTracingOptions::prestate_tracer(PreStateConfig {
+            diff_mode: Some(true),
+            ..Default::default()
+        });
+        let diffs: Vec<TransactionDiff> = self
+            .provider
+            .raw_request("debug_traceBlockByHash".into(), (block_hash, options))
             .await
             .map_err(Error::RpcError)?;
+        if diffs.len() <= index as usize {
+            return Err(Error::IncompleteBlockTrace {
+                block_hash,
+                traced: diffs.len(),
+                index,
+            });
+        }
+        Ok(diffs
+            .into_iter()
+            .take(index as usize)
+            .map(|diff| diff.result)
+            .collect())
+    }
+
+    /// Estimate request of a mined transaction, at the gas price it actually paid
+    fn replayed_tx(tx: &Transaction, gas_price: u128) -> Tx {
+        Tx {
+            gas_price: Some(gas_price),
+            ..Tx::from_signed(tx, tx.inner.signer())
+        }
+    }
+
+    /// Ask the upstream node for its own `eth_estimateGas` at the pinned block
+    async fn compare_with_upstream(
+        &self,
+        tx_params: &Tx,
+        chain: &ChainContext,
+        estimated_gas: u128,
+    ) -> Comparison {
+        let upstream = self
+            .provider
+            .estimate_gas(Self::upstream_request(tx_params))
+            .block(BlockId::hash(chain.block_hash))
+            .await
+            .map(u128::from)
+            .map_err(|e| e.to_string());
+        Comparison::new(estimated_gas, upstream)
+    }
+
+    /// The transaction as an upstream node's `eth_call`-like methods take it
+    fn upstream_request(tx_params: &Tx) -> TransactionRequest {
+        let mut request = TransactionRequest::default()
+            .value(tx_params.value)
+            .input(tx_params.data.clone().unwrap_or_default().into());
+        if let Some(from) = tx_params.from {
+            request = request.from(from);
+        }
+        if let Some(to) = tx_params.to {
+            request = request.to(to);
+        }
+        if let Some(gas_limit) = tx_params.gas_limit {
+            request = request.gas_limit(gas_limit);
+        }
+        if let Some(access_list) = &tx_params.access_list {
+            request = request.access_list(access_list.clone());
+        }
+        request.blob_versioned_hashes = tx_params.blob_versioned_hashes.clone();
+        request.max_fee_per_blob_gas = tx_params.max_fee_per_blob_gas;
+        request.authorization_list = tx_params.authorization_list.clone();
+        request
+    }
+
+    async fn estimate_at(
+        &self,
+        tx_params: &Tx,
+        chain: &ChainContext,
+    ) -> Result<GasEstimate, Error> {
+        // Calculate gas breakdown using our custom logic
+        let (mut breakdown, simulation) = self.calculate_gas_breakdown(tx_params, chain).await?;
+
+        if chain.profile.gas_model == GasModel::Arbitrum {
+            breakdown.l1_gas = Some(
+                arbitrum::l1_gas_estimate(
+                    &self.provider,
+                    tx_params,
+                    BlockId::hash(chain.block_hash),
+                )
+                .await? as u128,
+            );
+        }
+
+        // Sum up all gas costs
+        let estimated_gas = breakdown.base_cost
+            + breakdown.contract_creation_cost
+            + breakdown.execution_cost
+            + breakdown.l1_gas.unwrap_or_default();
+
+        // Get current gas price information
+        let gas_price = self.gas_price(chain).await?;
+
+        if chain.profile.gas_model == GasModel::OpStack {
+            breakdown.l1_data_fee = Some(
+                self.op_stack_l1_data_fee(tx_params, chain, estimated_gas, gas_price)
+                    .await?,
+            );
+        }
 
         // Calculate total cost
-        let total_cost_wei = estimated_gas * tx_params.gas_price.unwrap_or(gas_price);
+        let total_cost_wei = estimated_gas * tx_params.gas_price.unwrap_or(gas_price)
+            + breakdown.l1_data_fee.unwrap_or_default();
+        let currency = &chain.profile.native_currency;
+        let total_cost_native = format!(
+            "{} {}",
+            format_units(U256::from(total_cost_wei), currency.decimals).unwrap_or_default(),
+            currency.symbol
+        );
+
+        // The changes a replay applies on top of the pinned state come without proofs,
+        // and neither does Arbitrum's L1 gas, an `eth_call` to the node interface
+        let verified = self.config.verify_state
+            && self.config.trusted_upstream.is_some()
+            && chain.overlay.is_none()
+            && breakdown.l1_gas.is_none();
 
         Ok(GasEstimate {
             estimated_gas,
             gas_price,
             total_cost_wei,
+            total_cost_native,
+            chain_id: chain.profile.chain_id,
             breakdown,
+            verified,
+            simulation_rounds: simulation
+                .as_ref()
+                .map_or(0, |simulation| simulation.rounds),
+            padded_gas: estimated_gas,
+            execution: simulation
+                .as_ref()
+                .map(|simulation| simulation.execution)
+                .unwrap_or_default(),
+            failure: simulation
+                .as_ref()
+                .and_then(|simulation| simulation.failure.clone()),
+            comparison: None,
+            profile: simulation.and_then(|simulation| simulation.profile),
         })
     }
 
-    /// Calculate detailed gas breakdown using specialized estimators
-    async fn calculate_gas_breakdown(&self, tx_params: &Tx) -> Result<GasBreakdown, Error> {
-        // Base transaction cost
-        // IMPROVEMENT
-        // Use provider to fetch base fee per gas to determain base fee
-        let base_cost = if let Some(chain_id) = tx_params.chain_id {
-            // Anvil has 0 base cost if calling a contract
-            if chain_id == U64::from(31337) && self.is_contract(tx_params.to).await? {
-                0
-            } else {
-                21000
+    /// Resolve the chain profile from the chain id the upstream reports, and the hardfork and
+    /// gas limit of the pinned block: the configured one, or the latest
+    pub async fn chain_context(&self, tx_params: &Tx) -> Result<ChainContext, Error> {
+        // A past block estimated against before is read back from the state store,
+        // without asking the upstream, when the transaction says which chain it is on
+        if let (Some(block_number), Some(store), Some(chain_id)) = (
+            self.config.block,
+            &self.config.state_store,
+            tx_params.chain_id,
+        ) {
+            if !self.config.verify_state {
+                if let Some((block, header)) = store.header(chain_id.to(), block_number).await? {
+                    return self.pinned_context(block, header, true);
+                }
             }
-        } else {
-            21000
+        }
+
+        let chain_id = self
+            .provider
+            .get_chain_id()
+            .await
+            .map_err(Error::RpcError)?;
+        if let Some(tx_chain_id) = tx_params.chain_id {
+            if tx_chain_id.to::<u64>() != chain_id {
+                return Err(Error::ChainIdMismatch {
+                    tx_chain_id: tx_chain_id.to(),
+                    chain_id,
+                });
+            }
+        }
+
+        let pinned_block = self
+            .provider
+            .get_block(self.config.block.map_or(BlockId::latest(), BlockId::number))
+            .await
+            .map_err(Error::RpcError)?
+            .ok_or(Error::BlockNotFound)?;
+        let header = &pinned_block.header;
+        if self.config.verify_state {
+            // The state root is only as trustworthy as the header it comes from
+            self.check_header(header).await?;
+        }
+
+        self.config
+            .state_cache
+            .pin_block(chain_id, header.number, header.hash);
+
+        let block = BlockKey {
+            chain_id,
+            block_number: header.number,
+            block_hash: header.hash,
+        };
+        let header = StoredHeader {
+            timestamp: header.timestamp,
+            gas_limit: header.gas_limit,
+            state_root: header.state_root,
+            base_fee_per_gas: header.base_fee_per_gas,
+            beneficiary: header.beneficiary,
+            prevrandao: header.mix_hash,
+            excess_blob_gas: header.excess_blob_gas,
         };
+        if let Some(store) = &self.config.state_store {
+            store.insert_header(block, header).await?;
+        }
+        self.pinned_context(block, header, false)
+    }
 
-        let execution_cost = if tx_params.to.is_some() && tx_params.data.is_some() {
-            self.simulate_call(&tx_params).await?
-        } else {
-            0
+    /// Check that `header` hashes to its block hash, and that the trusted upstream,
+    /// when there is one, has that block at its height
+    async fn check_header(&self, header: &Header) -> Result<(), Error> {
+        if header.inner.hash_slow() != header.hash {
+            return Err(Error::HeaderHashMismatch(header.hash));
+        }
+        let Some(trusted) = &self.config.trusted_upstream else {
+            return Ok(());
         };
+        let trusted_hash = trusted
+            .get_block_by_number(header.number.into())
+            .await
+            .map_err(Error::RpcError)?
+            .ok_or(Error::BlockNotFound)?
+            .header
+            .hash;
+        if trusted_hash != header.hash {
+            return Err(Error::UntrustedBlock {
+                block_number: header.number,
+                block_hash: header.hash,
+                trusted_hash,
+            });
+        }
+        Ok(())
+    }
 
-        // Calculate data cost (calldata)
-        let data_cost = if tx_params.data.is_some() && self.is_contract(tx_params.to).await.unwrap()
-        {
-            calculate_calldata_cost(tx_params.data.as_ref().unwrap())
-        } else {
-            0
+    fn pinned_context(
+        &self,
+        block: BlockKey,
+        header: StoredHeader,
+        from_store: bool,
+    ) -> Result<ChainContext, Error> {
+        let profile = self.config.chains.get(block.chain_id);
+        let spec = profile.spec_at(block.block_number, header.timestamp)?;
+        Ok(ChainContext {
+            spec,
+            precompiles: profile.precompiles(spec),
+            block_number: block.block_number,
+            block_hash: block.block_hash,
+            state_root: header.state_root,
+            execution_block_number: block.block_number,
+            timestamp: header.timestamp,
+            block_gas_limit: profile.block_gas_limit(header.gas_limit),
+            base_fee: header.base_fee_per_gas.unwrap_or_default(),
+            beneficiary: header.beneficiary,
+            prevrandao: header.prevrandao,
+            excess_blob_gas: header.excess_blob_gas,
+            overlay: None,
+            gas_price: self
+                .config
+                .block
+                .and(header.base_fee_per_gas)
+                .map(u128::from),
+            from_store,
+            profile,
+        })
+    }
+
+    /// Gas price estimates at `chain` are priced at
+    async fn gas_price(&self, chain: &ChainContext) -> Result<u128, Error> {
+        match chain.gas_price {
+            Some(gas_price) => Ok(gas_price),
+            None => self.provider.get_gas_price().await.map_err(Error::RpcError),
+        }
+    }
+
+    /// L1 data fee of the transaction on an OP-stack chain, priced with the `GasPriceOracle` parameters
+    async fn op_stack_l1_data_fee(
+        &self,
+        tx_params: &Tx,
+        chain: &ChainContext,
+        estimated_gas: u128,
+        gas_price: u128,
+    ) -> Result<u128, Error> {
+        let nonce = match (tx_params.nonce, tx_params.from) {
+            (Some(nonce), _) => nonce,
+            (None, Some(from)) => self.account(from, chain).await?.nonce,
+            (None, None) => 0,
         };
+        let unsigned_tx = encode_unsigned(
+            tx_params,
+            chain.profile.chain_id,
+            nonce,
+            estimated_gas as u64,
+            gas_price,
+        );
+
+        let outputs = self
+            .view_calls(GAS_PRICE_ORACLE, L1FeeParams::calls(), chain)
+            .await?;
+        let params = L1FeeParams::decode(&outputs)?;
+        Ok(params.l1_data_fee(&unsigned_tx).to())
+    }
+
+    /// Outputs of view calls to `to` with each of `calls`, run in the local EVM on the pinned
+    /// state. The state they read is loaded round by round like a simulation loads its own.
+    pub async fn view_calls(
+        &self,
+        to: Address,
+        calls: Vec<Bytes>,
+        chain: &ChainContext,
+    ) -> Result<Vec<Bytes>, Error> {
+        let limits = &self.config.limits;
+        let mut cache_db = CacheDB::new(EmptyDB::default());
+        self.add_code_to_db(&mut cache_db, to, chain).await?;
+        // The caller only pays for gas, it needs no state of its own
+        cache_db.insert_account_info(
+            Address::ZERO,
+            AccountInfo {
+                balance: U256::MAX,
+                ..Default::default()
+            },
+        );
+        let mut tracer = Tracer::new().with_step_limit(limits.max_steps);
+        tracer.mark_loaded(&cache_db);
+        let txs = calls
+            .into_iter()
+            .map(|data| {
+                TxEnvBuilder::new()
+                    .caller(Address::ZERO)
+                    .kind(TxKind::Call(to))
+                    .data(data)
+                    .gas_limit(chain.block_gas_limit)
+                    .chain_id(Some(chain.profile.chain_id))
+                    .build()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        for _ in 0..limits.max_rounds {
+            let round_db = cache_db.clone();
+            let round_txs = txs.clone();
+            let round_chain = chain.clone();
+            let mut round_tracer = std::mem::take(&mut tracer);
+            let (results, round_tracer) = self
+                .config
+                .evm_pool
+                .run(move |cancelled| {
+                    round_tracer.set_cancellation(cancelled);
+                    let results = round_txs
+                        .into_iter()
+                        .map(|tx| {
+                            Self::execute(round_db.clone(), tx, &round_chain, &mut round_tracer)
+                        })
+                        .collect::<Result<Vec<_>, _>>();
+                    (results, round_tracer)
+                })
+                .await?;
+            tracer = round_tracer;
+            let results = results?;
 
-        // Calculate contract creation cost
-        // IMPROVEMENT:
-        // Use EVM for more precise predictions
+            if tracer.step_limit_exceeded() {
+                return Err(Error::TooManySteps(limits.max_steps));
+            }
+            if !tracer.has_new_accesses() {
+                return results
+                    .into_iter()
+                    .map(|result| match result {
+                        ExecutionResult::Success { output, .. } => Ok(output.into_data()),
+                        result => Err(Error::ViewCallFailed {
+                            address: to,
+                            reason: failure_reason(&result).unwrap_or_default(),
+                        }),
+                    })
+                    .collect();
+            }
+            self.add_state_to_db(
+                &mut cache_db,
+                tracer.contract_addresses.iter().copied(),
+                tracer.storage_accesses.iter().copied(),
+                chain,
+            )
+            .await?;
+            tracer.reset_state();
+        }
+        Err(Error::TooManyRounds(limits.max_rounds))
+    }
+
+    /// Calculate detailed gas breakdown using specialized estimators,
+    /// and the simulation it took, if any
+    async fn calculate_gas_breakdown(
+        &self,
+        tx_params: &Tx,
+        chain: &ChainContext,
+    ) -> Result<(GasBreakdown, Option<Simulation>), Error> {
+        let intrinsic_gas = &chain.profile.intrinsic_gas;
+
+        // Anything sent to code has to run it, with or without calldata:
+        // plain ETH transfers still hit `receive()`/`fallback()`
+        let to_precompile = tx_params.to.filter(|to| chain.is_precompile(*to));
+        let to_contract = to_precompile.is_none() && self.is_contract(tx_params.to, chain).await?;
+
+        // Base transaction cost
+        // IMPROVEMENT
+        // Use provider to fetch base fee per gas to determain base fee
+        let base_cost = intrinsic_gas.base_cost();
+
+        // Calculate data cost (calldata, or initcode for creations)
+        let data_cost = tx_params.data.as_ref().map_or(0, |data| {
+            calculate_calldata_cost(data, intrinsic_gas, chain.spec)
+        });
+
+        // Intrinsic cost of a creation. The initcode and the code deposit are left to the
+        // simulation, which runs the constructor.
         let contract_creation_cost = if tx_params.to.is_none() {
-            calculate_contract_creation_cost(tx_params.data.as_ref())
+            intrinsic_gas.create_cost(chain.spec)
         } else {
             0
         };
 
-        Ok(GasBreakdown {
+        let input = tx_params.data.clone().unwrap_or_default();
+        // Without a simulation the calldata and access list are charged here, the total
+        // lifted to the EIP-7623 floor like the EVM does
+        let unsimulated_cost = |gas: u128| {
+            let intrinsic = data_cost + access_list_cost(tx_params.access_list.as_ref());
+            (base_cost + intrinsic + gas).max(calldata_floor(&input, chain.spec)) - base_cost
+        };
+        let (execution_cost, simulation) =
+            match to_precompile.and_then(|to| precompile_gas(to, &input, chain.spec)) {
+                // Direct precompile calls are priced without simulation
+                Some(gas) => (unsimulated_cost(gas as u128), None),
+                // Simulated gas already includes the base and creation costs, so they are not
+                // counted twice. Delegating code is simulated too, even to an account that has none yet.
+                None if to_contract
+                    || to_precompile.is_some()
+                    || tx_params.to.is_none()
+                    || tx_params.has_authorizations() =>
+                {
+                    let simulation = self.simulate_call(tx_params, chain).await?;
+                    // The EVM charges Ethereum's intrinsic gas, not the chain's own
+                    let adjustment =
+                        intrinsic_gas.adjustment(&input, tx_params.to.is_none(), chain.spec);
+                    let gas_used = (simulation.gas_used as i128 + adjustment).max(0) as u128;
+                    (
+                        gas_used.saturating_sub(base_cost + contract_creation_cost),
+                        Some(simulation),
+                    )
+                }
+                // ETH sent to an EOA stays on the fast path without running the EVM
+                None => (unsimulated_cost(0), None),
+            };
+
+        let breakdown = GasBreakdown {
             base_cost,
             data_cost,
             contract_creation_cost,
             execution_cost,
-        })
+            l1_gas: None,
+            l1_data_fee: None,
+        };
+        Ok((breakdown, simulation))
     }
 
-    async fn is_contract(&self, to: Option<Address>) -> Result<bool, Error> {
-        if to.is_none() {
+    async fn is_contract(&self, to: Option<Address>, chain: &ChainContext) -> Result<bool, Error> {
+        let Some(to) = to else {
             return Ok(false);
+        };
+        let account = self.account(to, chain).await?;
+        Ok(account.code_hash != KECCAK_EMPTY)
+    }
+
+    /// Balance, nonce and code hash of `address` at the pinned block,
+    /// from the state cache, the state store or the upstream
+    pub async fn account(
+        &self,
+        address: Address,
+        chain: &ChainContext,
+    ) -> Result<CachedAccount, Error> {
+        let account = if self.config.verify_state {
+            self.fetch_proof(address, &[], chain).await?.0
+        } else {
+            match self.cached_account(address, chain)? {
+                Some(account) => account,
+                None => self.fetch_account(address, chain).await?,
+            }
+        };
+        self.overlaid_account(address, account, chain)
+    }
+
+    /// `account` with the changes of the overlay, if any. Code the overlay deployed is cached
+    /// by its hash like any other, so it is found when the account's code is loaded.
+    fn overlaid_account(
+        &self,
+        address: Address,
+        account: CachedAccount,
+        chain: &ChainContext,
+    ) -> Result<CachedAccount, Error> {
+        let Some(overlay) = &chain.overlay else {
+            return Ok(account);
+        };
+        let (account, code) = overlay.account(address, account);
+        if let Some(code) = code {
+            self.remember_bytecode(account.code_hash, code)?;
         }
-        let code = self
-            .provider
-            .get_code_at(to.unwrap())
-            .await
-            .map_err(Error::RpcError)?;
-        Ok(!code.is_empty())
+        Ok(account)
+    }
+
+    fn cached_account(
+        &self,
+        address: Address,
+        chain: &ChainContext,
+    ) -> Result<Option<CachedAccount>, Error> {
+        if self.config.verify_state {
+            return Ok(None);
+        }
+        let cache = &self.config.state_cache;
+        let chain_id = chain.profile.chain_id;
+        if let Some(account) = cache.account(chain_id, chain.block_hash, address) {
+            return Ok(Some(account));
+        }
+        if let Some(store) = &self.config.state_store {
+            if let Some(account) = store.account(chain.block_key(), address)? {
+                cache.insert_account(chain_id, chain.block_hash, address, account);
+                return Ok(Some(account));
+            }
+        }
+        Ok(None)
     }
 
-    /// Check if an address is a precompile address
-    /// Ethereum precompiles are at addresses 0x01 through 0x09 (and potentially higher)
-    pub fn is_precompile(address: Address) -> bool {
-        let addr_u64 = address.as_slice()[19];
-        // Check if address is in the range 0x01 to 0x09 (standard Ethereum precompiles)
-        // Can be extended to include more precompiles as needed
-        addr_u64 >= 1 && addr_u64 <= 9
+    async fn fetch_account(
+        &self,
+        address: Address,
+        chain: &ChainContext,
+    ) -> Result<CachedAccount, Error> {
+        let block = BlockId::hash(chain.block_hash);
+        let (balance, nonce, code) = tokio::try_join!(
+            self.provider
+                .get_balance(address)
+                .block_id(block)
+                .into_future(),
+            self.provider
+                .get_transaction_count(address)
+                .block_id(block)
+                .into_future(),
+            self.provider
+                .get_code_at(address)
+                .block_id(block)
+                .into_future(),
+        )
+        .map_err(Error::RpcError)?;
+
+        let code_hash = if code.is_empty() {
+            KECCAK_EMPTY
+        } else {
+            let code_hash = keccak256(&code);
+            self.remember_bytecode(code_hash, code)?;
+            code_hash
+        };
+        let account = CachedAccount {
+            balance,
+            nonce,
+            code_hash,
+        };
+        self.remember_account(address, account, chain)?;
+        Ok(account)
+    }
+
+    fn remember_account(
+        &self,
+        address: Address,
+        account: CachedAccount,
+        chain: &ChainContext,
+    ) -> Result<(), Error> {
+        if let Some(store) = &self.config.state_store {
+            store.insert_account(chain.block_key(), address, account)?;
+        }
+        self.config.state_cache.insert_account(
+            chain.profile.chain_id,
+            chain.block_hash,
+            address,
+            account,
+        );
+        Ok(())
     }
 
-    pub async fn simulate_call(&self, tx_params: &Tx) -> Result<u128, Error> {
-        let current_gas_price = self
+    fn remember_bytecode(&self, code_hash: B256, code: Bytes) -> Result<Bytecode, Error> {
+        if let Some(store) = &self.config.state_store {
+            store.insert_bytecode(code_hash, &code)?;
+        }
+        let bytecode = Bytecode::new_raw(code);
+        self.config
+            .state_cache
+            .insert_bytecode(code_hash, bytecode.clone());
+        Ok(bytecode)
+    }
+
+    /// Code of an account, from the state cache or the state store by code hash, or the upstream.
+    /// Code is only cached under the hash it actually has, so cached code can be trusted in verified mode.
+    async fn bytecode(
+        &self,
+        address: Address,
+        account: &CachedAccount,
+        chain: &ChainContext,
+    ) -> Result<Option<Bytecode>, Error> {
+        if account.code_hash == KECCAK_EMPTY {
+            return Ok(None);
+        }
+        let cache = &self.config.state_cache;
+        if let Some(bytecode) = cache.bytecode(account.code_hash) {
+            return Ok(Some(bytecode));
+        }
+        if let Some(store) = &self.config.state_store {
+            if let Some(bytecode) = store.bytecode(account.code_hash)? {
+                if keccak256(bytecode.original_byte_slice()) == account.code_hash {
+                    cache.insert_bytecode(account.code_hash, bytecode.clone());
+                    return Ok(Some(bytecode));
+                }
+            }
+        }
+
+        let code = self
             .provider
-            .get_gas_price()
+            .get_code_at(address)
+            .block_id(BlockId::hash(chain.block_hash))
             .await
             .map_err(Error::RpcError)?;
-        let mut tracer = Tracer::new();
+        if keccak256(&code) == account.code_hash {
+            Ok(Some(self.remember_bytecode(account.code_hash, code)?))
+        } else if self.config.verify_state {
+            Err(Error::StateProofMismatch {
+                address,
+                block_hash: chain.block_hash,
+                reason: "code does not match the proven code hash".to_string(),
+            })
+        } else {
+            Ok(Some(Bytecode::new_raw(code)))
+        }
+    }
+
+    /// Simulate the call, loading the state each round discovers until a round discovers none
+    pub async fn simulate_call(
+        &self,
+        tx_params: &Tx,
+        chain: &ChainContext,
+    ) -> Result<Simulation, Error> {
+        let limits = &self.config.limits;
+        let deadline = Instant::now() + limits.max_duration;
+        let current_gas_price = self.gas_price(chain).await?;
+        let mut tracer = Tracer::new()
+            .with_step_limit(limits.max_steps)
+            .with_profile(self.config.profile);
 
         let mut cache_db = CacheDB::new(EmptyDB::default());
 
         // Get actual balance from the provider
-        let caller = tx_params.from.unwrap();
-        self.add_balance_to_db(&mut cache_db, caller).await?;
+        let caller = tx_params.from.unwrap_or(self.config.default_sender);
+        self.add_balance_to_db(&mut cache_db, caller, chain).await?;
+        if tx_params.from.is_none() {
+            // Like nodes do for `eth_estimateGas` without `from`, fund the default sender
+            cache_db.load_account(caller).unwrap().info.balance =
+                self.config.default_sender_balance;
+        }
+        let nonce = Self::apply_nonce(&mut cache_db, caller, tx_params.nonce)?;
+
+        // Get contract code from provider and add it to cache. A created contract has none
+        // yet, its address is picked up by the tracer in case something already lives there.
+        let (kind, callee) = match tx_params.to {
+            Some(to) => {
+                self.add_code_to_db(&mut cache_db, to, chain).await?;
+                (TxKind::Call(to), to)
+            }
+            None => (TxKind::Create, caller.create(nonce)),
+        };
 
-        // Get contract code from provider and add it to cache
-        let contract_address = tx_params.to.unwrap();
-        self.add_code_to_db(&mut cache_db, contract_address).await?;
+        // Authorizations are applied before execution, out of the tracer's sight, so the
+        // delegating accounts and the code they delegate to are loaded up front
+        if let Some(authorizations) = &tx_params.authorization_list {
+            let accounts = authorizations.iter().flat_map(|authorization| {
+                authorization
+                    .recover_authority()
+                    .ok()
+                    .into_iter()
+                    .chain([authorization.address])
+                    .filter(|address| *address != caller)
+            });
+            self.add_state_to_db(&mut cache_db, accounts, [], chain)
+                .await?;
+        }
 
-        let account = cache_db.load_account(caller).unwrap();
         // IMPROVEMENT
         // Add:
         // - gas_priority_fee
         // - max_fee_per_gas
         let tx_evm = TxEnvBuilder::new()
             .caller(caller)
-            .kind(TxKind::Call(tx_params.to.unwrap()))
-            .data(tx_params.data.clone().unwrap())
+            .kind(kind)
+            .data(tx_params.data.clone().unwrap_or_default())
             .value(tx_params.value)
             .gas_price(tx_params.gas_price.unwrap_or(current_gas_price))
-            .gas_limit(tx_params.gas_limit.unwrap_or(BLOCK_GAS_LIMIT))
-            .nonce(account.info.nonce)
-            .access_list(
+            .gas_limit(tx_params.gas_limit.unwrap_or(chain.block_gas_limit))
+            .chain_id(Some(chain.profile.chain_id))
+            .nonce(nonce)
+            .access_list(tx_params.access_list.clone().unwrap_or_default())
+            .blob_hashes(tx_params.blob_versioned_hashes.clone().unwrap_or_default())
+            .max_fee_per_blob_gas(tx_params.max_fee_per_blob_gas.unwrap_or_default())
+            .authorization_list(
                 tx_params
-                    .access_list
-                    .clone()
-                    .unwrap_or(AccessList::default()),
+                    .authorization_list
+                    .iter()
+                    .flatten()
+                    .cloned()
+                    .map(Either::Left)
+                    .collect(),
             )
             .build()
             .unwrap();
 
-        let mut latest_gas_costs = 0;
-        let mut max_gas_costs = 0;
-        while {
-            let mut evm = Context::mainnet()
-                .with_db(cache_db.clone())
-                .build_mainnet_with_inspector(&mut tracer);
-            // Execute transaction without writing to the DB
-            let gas_costs = match evm.inspect_tx(tx_evm.clone()) {
-                Ok(result) => {
-                    println!("result: {:?}", result);
-
-                    let tracer_after_call = evm.inspector.clone();
-                    println!("tracer: {:?}", tracer_after_call);
-
-                    result.gas_used() as u128
-                }
-                Err(e) => {
-                    println!("EVM execution error: {:?}", e);
-                    // Return a default gas cost for contract calls
-                    30_000
-                }
-            };
-            latest_gas_costs = gas_costs;
-            if gas_costs > max_gas_costs {
-                max_gas_costs = gas_costs;
-            }
+        // One `debug_traceCall` returns everything the transaction touches,
+        // so the discovery loop below usually finds its state already loaded
+        timeout_at(
+            deadline.into(),
+            self.prefetch_prestate(&mut cache_db, tx_params, caller, chain),
+        )
+        .await
+        .map_err(|_| Error::SimulationTimeout(limits.max_duration))??;
+        // The sender, the callee and the prestate are not new to the first round
+        tracer.mark_loaded(&cache_db);
 
-            tracer.has_new_accesses()
-        } {
-            for contract_address in &tracer.contract_addresses {
-                self.add_code_to_db(&mut cache_db, *contract_address)
-                    .await?;
+        let mut rounds = 0;
+        let mut fetched_slots = 0;
+        loop {
+            rounds += 1;
+            // The EVM runs on its own threads; dropping this future halts it
+            let cache_db_snapshot = cache_db.clone();
+            let tx = tx_evm.clone();
+            let round_chain = chain.clone();
+            let mut round_tracer = std::mem::take(&mut tracer);
+            let (result, round_tracer) = self
+                .config
+                .evm_pool
+                .run(move |cancelled| {
+                    round_tracer.set_cancellation(cancelled);
+                    let result =
+                        Self::execute(cache_db_snapshot, tx, &round_chain, &mut round_tracer);
+                    (result, round_tracer)
+                })
+                .await?;
+            tracer = round_tracer;
+            let result = result?;
+
+            if tracer.step_limit_exceeded() {
+                return Err(Error::TooManySteps(limits.max_steps));
             }
-            for (contract_address, storage_slot) in &tracer.storage_accesses {
-                self.populate_storage_slot(&mut cache_db, *contract_address, *storage_slot)
-                    .await?;
+            if !tracer.has_new_accesses() {
+                let gas_refunded = match result {
+                    ExecutionResult::Success { gas_refunded, .. } => gas_refunded,
+                    _ => 0,
+                };
+                return Ok(Simulation {
+                    gas_used: result.gas_used() as u128,
+                    rounds,
+                    profile: tracer.profile.take(),
+                    execution: Self::execution_summary(
+                        &tracer,
+                        tx_params,
+                        caller,
+                        callee,
+                        chain,
+                        gas_refunded,
+                    ),
+                    failure: failure_reason(&result),
+                });
+            }
+            if rounds >= limits.max_rounds {
+                return Err(Error::TooManyRounds(limits.max_rounds));
             }
+            fetched_slots += tracer.storage_accesses.len();
+            if fetched_slots > limits.max_fetched_slots {
+                return Err(Error::TooManySlots(limits.max_fetched_slots));
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::SimulationTimeout(limits.max_duration));
+            }
+
+            timeout_at(
+                deadline.into(),
+                self.add_state_to_db(
+                    &mut cache_db,
+                    tracer.contract_addresses.iter().copied(),
+                    tracer.storage_accesses.iter().copied(),
+                    chain,
+                ),
+            )
+            .await
+            .map_err(|_| Error::SimulationTimeout(limits.max_duration))??;
             tracer.reset_state();
         }
-        Ok(latest_gas_costs)
+    }
+
+    /// Run one simulation round against `cache_db`
+    fn execute(
+        cache_db: CacheDB<EmptyDB>,
+        tx: TxEnv,
+        chain: &ChainContext,
+        tracer: &mut Tracer,
+    ) -> Result<ExecutionResult, Error> {
+        let mut evm = Context::mainnet()
+            .with_db(cache_db)
+            .modify_cfg_chained(|cfg| {
+                cfg.spec = chain.spec;
+                cfg.chain_id = chain.profile.chain_id;
+                // Like eth_call, a gas price below the base fee is not an error
+                cfg.disable_base_fee = true;
+            })
+            .modify_block_chained(|block| {
+                block.number = U256::from(chain.execution_block_number);
+                block.timestamp = U256::from(chain.timestamp);
+                block.gas_limit = chain.block_gas_limit;
+                block.basefee = chain.base_fee;
+                block.beneficiary = chain.beneficiary;
+                block.prevrandao = Some(chain.prevrandao);
+                if let Some(excess_blob_gas) = chain.excess_blob_gas {
+                    let update_fraction = if chain.spec.is_enabled_in(SpecId::PRAGUE) {
+                        BLOB_BASE_FEE_UPDATE_FRACTION_PRAGUE
+                    } else {
+                        BLOB_BASE_FEE_UPDATE_FRACTION_CANCUN
+                    };
+                    block.set_blob_excess_gas_and_price(excess_blob_gas, update_fraction);
+                }
+            })
+            .build_mainnet_with_inspector(tracer)
+            .with_precompiles(EthPrecompiles {
+                precompiles: chain.precompiles,
+                spec: chain.spec,
+            });
+        // Execute transaction without writing to the DB
+        evm.inspect_tx(tx)
+            .map_err(|e| Error::EvmExecution(e.to_string()))
+    }
+
+    /// Summarise the final round: accesses outside the caller, the callee (or created
+    /// contract), precompiles and the access list are cold
+    fn execution_summary(
+        tracer: &Tracer,
+        tx_params: &Tx,
+        caller: Address,
+        callee: Address,
+        chain: &ChainContext,
+        gas_refunded: u64,
+    ) -> ExecutionSummary {
+        let access_list = tx_params
+            .access_list
+            .as_ref()
+            .map_or(&[][..], |list| &list.0);
+        let warm_account = |address: &Address| {
+            *address == caller
+                || *address == callee
+                || chain.is_precompile(*address)
+                || access_list.iter().any(|item| item.address == *address)
+        };
+        let warm_slot = |(address, slot): &(Address, U256)| {
+            access_list.iter().any(|item| {
+                item.address == *address && item.storage_keys.contains(&B256::from(*slot))
+            })
+        };
+        let cold_accounts = tracer
+            .touched_accounts
+            .iter()
+            .filter(|address| !warm_account(address))
+            .count();
+        let cold_slots = tracer
+            .touched_slots
+            .iter()
+            .filter(|slot| !warm_slot(slot))
+            .count();
+        ExecutionSummary {
+            cold_accesses: cold_accounts + cold_slots,
+            external_calls: tracer.external_calls,
+            gas_refunded,
+        }
+    }
+
+    /// Use the caller-supplied nonce for the simulation, falling back to the account nonce.
+    /// A nonce ahead of the account's is allowed, so queued transactions can be estimated.
+    fn apply_nonce(
+        cache_db: &mut CacheDB<EmptyDB>,
+        caller: Address,
+        nonce: Option<u64>,
+    ) -> Result<u64, Error> {
+        let account = cache_db.load_account(caller).unwrap();
+        let Some(nonce) = nonce else {
+            return Ok(account.info.nonce);
+        };
+        if nonce < account.info.nonce {
+            return Err(Error::NonceTooLow {
+                address: caller,
+                tx_nonce: nonce,
+                account_nonce: account.info.nonce,
+            });
+        }
+        account.info.nonce = nonce;
+        Ok(nonce)
     }
 
     pub async fn add_balance_to_db(
         &self,
         cache_db: &mut CacheDB<EmptyDB>,
         caller: Address,
+        chain: &ChainContext,
     ) -> Result<(), Error> {
-        let balance = self.provider.get_balance(caller).await.unwrap_or_else(|_| {
-            // Fallback to a reasonable amount if balance fetch fails
-            U256::from(10u128.pow(18) * 1000) // 1000 ETH
-        });
-        let nonce = self
-            .provider
-            .get_transaction_count(caller)
-            .await
-            .unwrap_or(0);
+        let (balance, nonce) = match self.account(caller, chain).await {
+            Ok(account) => (account.balance, account.nonce),
+            Err(e) if self.config.verify_state => return Err(e),
+            // Fallback to a reasonable amount if the account fetch fails
+            Err(_) => (U256::from(10u128.pow(18) * 1000), 0), // 1000 ETH
+        };
 
         cache_db.insert_account_info(
             caller,
             AccountInfo {
                 balance,
                 nonce,
-                code_hash: revm::primitives::KECCAK_EMPTY,
+                code_hash: KECCAK_EMPTY,
                 code: None,
             },
         );
@@ -293,44 +1545,358 @@ impl GasEstimator {
         &self,
         cache_db: &mut CacheDB<EmptyDB>,
         contract_address: Address,
+        chain: &ChainContext,
     ) -> Result<(), Error> {
-        let contract_code = self
+        if chain.is_precompile(contract_address) {
+            return Ok(());
+        }
+        let account = self.account(contract_address, chain).await?;
+        let code = self.bytecode(contract_address, &account, chain).await?;
+        cache_db.insert_account_info(
+            contract_address,
+            AccountInfo {
+                balance: account.balance,
+                nonce: account.nonce,
+                code_hash: account.code_hash,
+                code,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Warm `cache_db` with the state the upstream reports the transaction touches, from
+    /// `debug_traceCall` with `prestateTracer`. Upstreams without it are left to the discovery loop.
+    pub async fn prefetch_prestate(
+        &self,
+        cache_db: &mut CacheDB<EmptyDB>,
+        tx_params: &Tx,
+        caller: Address,
+        chain: &ChainContext,
+    ) -> Result<(), Error> {
+        let capability = &self.capabilities.prestate_trace;
+        // The prestate comes without proofs, so verified estimates fetch everything through `eth_getProof`.
+        // Replays need the state after the earlier transactions of the block, which it does not have.
+        // Blocks read back from the state store have theirs stored already.
+        if self.config.verify_state
+            || chain.overlay.is_some()
+            || chain.from_store
+            || !capability.is_available()
+        {
+            return Ok(());
+        }
+
+        let request = Self::upstream_request(tx_params)
+            .from(caller)
+            .gas_limit(tx_params.gas_limit.unwrap_or(chain.block_gas_limit));
+        let mut options = GethDebugTracingCallOptions::new(
+            GethDebugTracingOptions::prestate_tracer(PreStateConfig::default()),
+        );
+        if tx_params.from.is_none() {
+            // The default sender is funded for the upstream's execution too
+            let funding = AccountOverride {
+                balance: Some(self.config.default_sender_balance),
+                ..Default::default()
+            };
+            options = options.with_state_overrides(StateOverride::from_iter([(caller, funding)]));
+        }
+
+        let prestate: PreStateMode = match self
             .provider
-            .get_code_at(contract_address)
+            .raw_request(
+                "debug_traceCall".into(),
+                (request, BlockId::hash(chain.block_hash), options),
+            )
             .await
-            .unwrap_or_default();
-        if !Self::is_precompile(contract_address) {
-            assert!(!contract_code.is_empty());
-            cache_db.insert_account_info(
-                contract_address,
-                AccountInfo {
-                    balance: U256::ZERO,
-                    nonce: 0,
-                    code_hash: keccak256(&contract_code),
-                    code: Some(Bytecode::new_raw(contract_code)),
-                },
-            );
-        }
+        {
+            Ok(prestate) => prestate,
+            Err(e) => {
+                capability.record_failure(&e);
+                return Ok(());
+            }
+        };
 
+        for (address, state) in prestate.0 {
+            // The caller is set up by the simulation, precompiles need no state
+            if address != caller && !chain.is_precompile(address) {
+                let code = state.code.filter(|code| !code.is_empty());
+                let code_hash = code.as_ref().map_or(KECCAK_EMPTY, keccak256);
+                let account = CachedAccount {
+                    balance: state.balance.unwrap_or_default(),
+                    nonce: state.nonce.unwrap_or_default(),
+                    code_hash,
+                };
+                self.remember_account(address, account, chain)?;
+                cache_db.insert_account_info(
+                    address,
+                    AccountInfo {
+                        balance: account.balance,
+                        nonce: account.nonce,
+                        code_hash,
+                        code: code
+                            .map(|code| self.remember_bytecode(code_hash, code))
+                            .transpose()?,
+                    },
+                );
+            }
+            for (slot, value) in state.storage {
+                let slot = U256::from_be_bytes(slot.0);
+                let value = U256::from_be_bytes(value.0);
+                self.remember_storage(address, slot, value, chain)?;
+                cache_db
+                    .insert_account_storage(address, slot, value)
+                    .unwrap();
+            }
+        }
         Ok(())
     }
 
-    pub async fn populate_storage_slot(
+    /// Load the accounts and storage slots a simulation round discovered into `cache_db`.
+    /// Entries are fetched concurrently, grouped per account so a single `eth_getProof`
+    /// call covers an account and all of its slots.
+    pub async fn add_state_to_db(
         &self,
         cache_db: &mut CacheDB<EmptyDB>,
-        contract_address: Address,
-        storage_slot: U256,
+        addresses: impl IntoIterator<Item = Address>,
+        slots: impl IntoIterator<Item = (Address, U256)>,
+        chain: &ChainContext,
     ) -> Result<(), Error> {
-        let storage_val = self
+        // Per address: whether the account itself is needed, and which of its slots
+        let mut requests: BTreeMap<Address, (bool, Vec<U256>)> = BTreeMap::new();
+        for address in addresses {
+            if !chain.is_precompile(address) {
+                requests.entry(address).or_default().0 = true;
+            }
+        }
+        for (address, slot) in slots {
+            requests.entry(address).or_default().1.push(slot);
+        }
+
+        let fetched: Vec<_> = stream::iter(requests)
+            .map(|(address, (with_account, slots))| async move {
+                let state = self
+                    .fetch_account_state(address, with_account, slots, chain)
+                    .await?;
+                Ok::<_, Error>((address, state))
+            })
+            .buffer_unordered(MAX_CONCURRENT_FETCHES)
+            .try_collect()
+            .await?;
+
+        for (address, (info, storage)) in fetched {
+            if let Some(info) = info {
+                cache_db.insert_account_info(address, info);
+            }
+            for (slot, value) in storage {
+                cache_db
+                    .insert_account_storage(address, slot, value)
+                    .unwrap();
+            }
+        }
+        Ok(())
+    }
+
+    /// Account (when `with_account`) and storage slots of `address`, from the caches
+    /// where possible and from the upstream in as few calls as it supports
+    async fn fetch_account_state(
+        &self,
+        address: Address,
+        with_account: bool,
+        slots: Vec<U256>,
+        chain: &ChainContext,
+    ) -> Result<(Option<AccountInfo>, Vec<(U256, U256)>), Error> {
+        let mut account = if with_account {
+            self.cached_account(address, chain)?
+        } else {
+            None
+        };
+        let mut storage = Vec::with_capacity(slots.len());
+        let mut missing_slots = Vec::new();
+        for slot in slots {
+            match self.cached_storage(address, slot, chain)? {
+                Some(value) => storage.push((slot, value)),
+                None => missing_slots.push(slot),
+            }
+        }
+
+        let account_missing = with_account && account.is_none();
+        if account_missing || !missing_slots.is_empty() {
+            let proof = if self.config.verify_state {
+                // Without a proof there is nothing to verify, so upstream errors fail the estimate
+                Some(self.fetch_proof(address, &missing_slots, chain).await?)
+            } else if self.capabilities.get_proof.is_available() {
+                match self.fetch_proof(address, &missing_slots, chain).await {
+                    Ok(proof) => Some(proof),
+                    Err(Error::RpcError(e)) => {
+                        self.capabilities.get_proof.record_failure(&e);
+                        None
+                    }
+                    Err(e) => return Err(e),
+                }
+            } else {
+                None
+            };
+            match proof {
+                Some((proof_account, values)) => {
+                    account = account.or(with_account.then_some(proof_account));
+                    storage.extend(values);
+                }
+                None => {
+                    // Upstreams without `eth_getProof` get one call per field and slot, still concurrent
+                    let fetch_account = async {
+                        if account_missing {
+                            self.fetch_account(address, chain).await.map(Some)
+                        } else {
+                            Ok(account)
+                        }
+                    };
+                    let fetch_storage = try_join_all(missing_slots.iter().map(|slot| async {
+                        Ok::<_, Error>((*slot, self.fetch_storage(address, *slot, chain).await?))
+                    }));
+                    let (fetched_account, values) = tokio::try_join!(fetch_account, fetch_storage)?;
+                    account = fetched_account;
+                    storage.extend(values);
+                }
+            }
+        }
+
+        if let Some(overlay) = &chain.overlay {
+            for (slot, value) in &mut storage {
+                if let Some(changed) = overlay.storage(address, *slot) {
+                    *value = changed;
+                }
+            }
+        }
+        let account = account
+            .map(|account| self.overlaid_account(address, account, chain))
+            .transpose()?;
+        let info = match account {
+            Some(account) => Some(AccountInfo {
+                balance: account.balance,
+                nonce: account.nonce,
+                code_hash: account.code_hash,
+                code: self.bytecode(address, &account, chain).await?,
+            }),
+            None => None,
+        };
+        Ok((info, storage))
+    }
+
+    /// Account and storage slots of `address` from a single `eth_getProof` call,
+    /// checked against the pinned block's state root in verified mode
+    async fn fetch_proof(
+        &self,
+        address: Address,
+        slots: &[U256],
+        chain: &ChainContext,
+    ) -> Result<(CachedAccount, Vec<(U256, U256)>), Error> {
+        let keys = slots.iter().map(|slot| B256::from(*slot)).collect();
+        let proof = self
             .provider
-            .get_storage_at(contract_address, storage_slot)
+            .get_proof(address, keys)
+            .block_id(BlockId::hash(chain.block_hash))
             .await
             .map_err(Error::RpcError)?;
+        if self.config.verify_state {
+            verify_account_proof(&proof, chain.state_root, chain.block_hash)?;
+        }
 
-        cache_db
-            .insert_account_storage(contract_address, storage_slot, storage_val)
-            .unwrap();
+        // Accounts that do not exist are reported with a zero code hash
+        let code_hash = if proof.code_hash.is_zero() {
+            KECCAK_EMPTY
+        } else {
+            proof.code_hash
+        };
+        let account = CachedAccount {
+            balance: proof.balance,
+            nonce: proof.nonce,
+            code_hash,
+        };
+        self.remember_account(address, account, chain)?;
+
+        let mut storage = Vec::with_capacity(slots.len());
+        for slot in slots {
+            let key = B256::from(*slot);
+            let value = match proof
+                .storage_proof
+                .iter()
+                .find(|storage_proof| storage_proof.key.as_b256() == key)
+            {
+                Some(storage_proof) => {
+                    self.remember_storage(address, *slot, storage_proof.value, chain)?;
+                    storage_proof.value
+                }
+                None if self.config.verify_state => {
+                    return Err(Error::StateProofMismatch {
+                        address,
+                        block_hash: chain.block_hash,
+                        reason: format!("no proof for storage slot {slot}"),
+                    })
+                }
+                // Slots left out of the proof are fetched on their own
+                None => self.fetch_storage(address, *slot, chain).await?,
+            };
+            storage.push((*slot, value));
+        }
+        Ok((account, storage))
+    }
+
+    fn cached_storage(
+        &self,
+        address: Address,
+        slot: U256,
+        chain: &ChainContext,
+    ) -> Result<Option<U256>, Error> {
+        if self.config.verify_state {
+            return Ok(None);
+        }
+        let cache = &self.config.state_cache;
+        let chain_id = chain.profile.chain_id;
+        if let Some(value) = cache.storage(chain_id, chain.block_hash, address, slot) {
+            return Ok(Some(value));
+        }
+        if let Some(store) = &self.config.state_store {
+            if let Some(value) = store.storage(chain.block_key(), address, slot)? {
+                cache.insert_storage(chain_id, chain.block_hash, address, slot, value);
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn fetch_storage(
+        &self,
+        address: Address,
+        slot: U256,
+        chain: &ChainContext,
+    ) -> Result<U256, Error> {
+        let value = self
+            .provider
+            .get_storage_at(address, slot)
+            .block_id(BlockId::hash(chain.block_hash))
+            .await
+            .map_err(Error::RpcError)?;
+        self.remember_storage(address, slot, value, chain)?;
+        Ok(value)
+    }
 
+    fn remember_storage(
+        &self,
+        address: Address,
+        slot: U256,
+        value: U256,
+        chain: &ChainContext,
+    ) -> Result<(), Error> {
+        if let Some(store) = &self.config.state_store {
+            store.insert_storage(chain.block_key(), address, slot, value)?;
+        }
+        self.config.state_cache.insert_storage(
+            chain.profile.chain_id,
+            chain.block_hash,
+            address,
+            slot,
+            value,
+        );
         Ok(())
     }
 
@@ -0,0 +1,253 @@
+use crate::{
+    chain_profile::ChainProfile, error::Error, gas_estimator::Tx, provider_pool::Upstream,
+};
+use alloy::{
+    consensus::Header as ConsensusHeader,
+    eips::BlockNumberOrTag,
+    providers::RootProvider,
+    rpc::types::{Block, Header},
+};
+use jsonrpsee::{
+    server::{ServerBuilder, ServerHandle},
+    types::ErrorObjectOwned,
+    RpcModule,
+};
+use revm::{
+    context::{result::ExecutionResult, tx::TxEnvBuilder},
+    database::{CacheDB, EmptyDB},
+    primitives::{Address, Bytes, TxKind, B256, KECCAK_EMPTY, U256},
+    state::AccountInfo,
+    Context, DatabaseRef, ExecuteCommitEvm, MainBuilder, MainContext,
+};
+use serde_json::Value;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Chain id the local node reports, the one Anvil uses
+pub const LOCAL_CHAIN_ID: u64 = 31337;
+
+/// Gas price the local node reports and charges, which is also every block's base fee
+const GAS_PRICE: u128 = 1_000_000_000;
+const BLOCK_GAS_LIMIT: u64 = 30_000_000;
+const GENESIS_TIMESTAMP: u64 = 1_700_000_000;
+const BLOCK_TIME: u64 = 12;
+
+/// In-process chain over a revm database, served over JSON-RPC on a local port so an
+/// estimator can use it as its upstream. Every change to the state mines a block, so
+/// estimates pinned to a block never see state cached for an earlier one.
+pub struct LocalNode {
+    chain: Arc<Mutex<LocalChain>>,
+    handle: ServerHandle,
+    addr: SocketAddr,
+}
+
+struct LocalChain {
+    profile: ChainProfile,
+    db: CacheDB<EmptyDB>,
+    /// Headers of all mined blocks, the genesis first
+    headers: Vec<Header>,
+}
+
+impl LocalChain {
+    fn head(&self) -> &Header {
+        self.headers.last().unwrap()
+    }
+
+    fn mine(&mut self) {
+        let parent = self.head();
+        let header = ConsensusHeader {
+            parent_hash: parent.hash,
+            number: parent.number + 1,
+            timestamp: parent.timestamp + BLOCK_TIME,
+            gas_limit: BLOCK_GAS_LIMIT,
+            base_fee_per_gas: Some(GAS_PRICE as u64),
+            ..Default::default()
+        };
+        self.headers.push(Header::new(header));
+    }
+
+    fn block(&self, header: Option<&Header>) -> Option<Value> {
+        header.map(|header| serde_json::to_value(Block::<Value>::empty(header.clone())).unwrap())
+    }
+
+    fn account(&self, address: Address) -> AccountInfo {
+        self.db.basic_ref(address).unwrap().unwrap_or_default()
+    }
+
+    fn code(&self, address: Address) -> Bytes {
+        let account = self.account(address);
+        match account.code {
+            Some(code) => code.original_bytes(),
+            None if account.code_hash != KECCAK_EMPTY => self
+                .db
+                .code_by_hash_ref(account.code_hash)
+                .unwrap()
+                .original_bytes(),
+            None => Bytes::new(),
+        }
+    }
+}
+
+impl LocalNode {
+    /// Start a node with an empty genesis state, following the hardforks of `profile`
+    pub async fn start(profile: ChainProfile) -> Result<Self, Error> {
+        let genesis = ConsensusHeader {
+            timestamp: GENESIS_TIMESTAMP,
+            gas_limit: BLOCK_GAS_LIMIT,
+            base_fee_per_gas: Some(GAS_PRICE as u64),
+            ..Default::default()
+        };
+        let chain = Arc::new(Mutex::new(LocalChain {
+            profile,
+            db: CacheDB::new(EmptyDB::default()),
+            headers: vec![Header::new(genesis)],
+        }));
+
+        let start_failed = |e| Error::LocalExecution(format!("failed to start the node: {e}"));
+        let server = ServerBuilder::default()
+            .build("127.0.0.1:0")
+            .await
+            .map_err(start_failed)?;
+        let addr = server.local_addr().map_err(start_failed)?;
+        let handle = server.start(Self::rpc_module(chain.clone()));
+        Ok(Self {
+            chain,
+            handle,
+            addr,
+        })
+    }
+
+    /// Upstream to estimate against the node with
+    pub fn upstream(&self) -> Upstream {
+        let url = format!("http://{}", self.addr).parse().unwrap();
+        Upstream::new(RootProvider::new_http(url))
+    }
+
+    /// Set the balance of `address`, in a block of its own
+    pub fn fund(&self, address: Address, balance: U256) {
+        let mut chain = self.chain.lock().unwrap();
+        let account = chain.account(address);
+        chain
+            .db
+            .insert_account_info(address, AccountInfo { balance, ..account });
+        chain.mine();
+    }
+
+    /// Execute `tx` from its sender's next nonce and mine it in a block of its own
+    pub fn commit(&self, tx: &Tx) -> Result<ExecutionResult, Error> {
+        let mut chain = self.chain.lock().unwrap();
+        let caller = tx
+            .from
+            .ok_or_else(|| Error::LocalExecution("transaction without sender".to_string()))?;
+        let chain_id = chain.profile.chain_id;
+        let head = chain.head();
+        let (number, timestamp) = (head.number + 1, head.timestamp + BLOCK_TIME);
+        let spec = chain.profile.spec_at(number, timestamp)?;
+        let tx_env = TxEnvBuilder::new()
+            .caller(caller)
+            .kind(tx.to.map_or(TxKind::Create, TxKind::Call))
+            .value(tx.value)
+            .data(tx.data.clone().unwrap_or_default())
+            .nonce(chain.account(caller).nonce)
+            .gas_limit(BLOCK_GAS_LIMIT)
+            .gas_price(GAS_PRICE)
+            .chain_id(Some(chain_id))
+            .build()
+            .map_err(|e| Error::LocalExecution(format!("{e:?}")))?;
+
+        let result = Context::mainnet()
+            .with_db(&mut chain.db)
+            .modify_cfg_chained(|cfg| {
+                cfg.spec = spec;
+                cfg.chain_id = chain_id;
+            })
+            .modify_block_chained(|block| {
+                block.number = U256::from(number);
+                block.timestamp = U256::from(timestamp);
+                block.gas_limit = BLOCK_GAS_LIMIT;
+                block.basefee = GAS_PRICE as u64;
+            })
+            .build_mainnet()
+            .transact_commit(tx_env)
+            .map_err(|e| Error::LocalExecution(format!("{e:?}")))?;
+        chain.mine();
+        Ok(result)
+    }
+
+    pub async fn stop(self) -> Result<(), Error> {
+        self.handle
+            .stop()
+            .map_err(|e| Error::LocalExecution(format!("failed to stop the node: {e}")))
+    }
+
+    /// The methods estimators call. State is served at the head whatever block is asked for,
+    /// estimates only ever pin the latest one. `eth_getProof` and `debug_traceCall` are left
+    /// out, estimators fall back to fetching fields one by one.
+    fn rpc_module(chain: Arc<Mutex<LocalChain>>) -> RpcModule<Arc<Mutex<LocalChain>>> {
+        let mut module = RpcModule::new(chain);
+        module
+            .register_method("eth_chainId", |_, chain| {
+                Ok::<_, ErrorObjectOwned>(U256::from(chain.lock().unwrap().profile.chain_id))
+            })
+            .unwrap();
+        module
+            .register_method("eth_blockNumber", |_, chain| {
+                Ok::<_, ErrorObjectOwned>(U256::from(chain.lock().unwrap().head().number))
+            })
+            .unwrap();
+        module
+            .register_method("eth_gasPrice", |_, _| {
+                Ok::<_, ErrorObjectOwned>(U256::from(GAS_PRICE))
+            })
+            .unwrap();
+        module
+            .register_method("eth_getBlockByNumber", |params, chain| {
+                let chain = chain.lock().unwrap();
+                let header = match params.sequence().next::<BlockNumberOrTag>()? {
+                    BlockNumberOrTag::Number(number) => chain.headers.get(number as usize),
+                    BlockNumberOrTag::Earliest => chain.headers.first(),
+                    _ => Some(chain.head()),
+                };
+                Ok::<_, ErrorObjectOwned>(chain.block(header))
+            })
+            .unwrap();
+        module
+            .register_method("eth_getBlockByHash", |params, chain| {
+                let chain = chain.lock().unwrap();
+                let hash = params.sequence().next::<B256>()?;
+                let header = chain.headers.iter().find(|header| header.hash == hash);
+                Ok::<_, ErrorObjectOwned>(chain.block(header))
+            })
+            .unwrap();
+        module
+            .register_method("eth_getBalance", |params, chain| {
+                let address = params.sequence().next::<Address>()?;
+                Ok::<_, ErrorObjectOwned>(chain.lock().unwrap().account(address).balance)
+            })
+            .unwrap();
+        module
+            .register_method("eth_getTransactionCount", |params, chain| {
+                let address = params.sequence().next::<Address>()?;
+                Ok::<_, ErrorObjectOwned>(U256::from(chain.lock().unwrap().account(address).nonce))
+            })
+            .unwrap();
+        module
+            .register_method("eth_getCode", |params, chain| {
+                let address = params.sequence().next::<Address>()?;
+                Ok::<_, ErrorObjectOwned>(chain.lock().unwrap().code(address))
+            })
+            .unwrap();
+        module
+            .register_method("eth_getStorageAt", |params, chain| {
+                let mut params = params.sequence();
+                let address = params.next::<Address>()?;
+                let slot = params.next::<U256>()?;
+                let value = chain.lock().unwrap().db.storage_ref(address, slot).unwrap();
+                Ok::<_, ErrorObjectOwned>(B256::from(value))
+            })
+            .unwrap();
+        module
+    }
+}
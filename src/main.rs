@@ -1,29 +1,591 @@
+mod abi;
+mod arbitrum;
+mod benchmark;
+mod chain_profile;
+mod coalescer;
+mod deploy;
+mod divergence;
 mod error;
+mod evm_pool;
 mod gas_estimator;
+mod local_node;
+mod margin;
+mod op_stack;
+mod profile;
+mod provider_pool;
+mod replay;
 mod rpc_server;
+mod snapshot;
+mod state_cache;
+mod state_proof;
+mod state_store;
 mod tracer;
 mod utils;
 
 #[cfg(test)]
 mod tests;
 
-use gas_estimator::GasEstimator;
+use abi::FunctionSpec;
+use alloy::{
+    primitives::{Address, B256, U256},
+    providers::RootProvider,
+};
+use benchmark::{sample_blocks, AccuracyReport};
+use chain_profile::ChainRegistry;
+use clap::{Parser, Subcommand, ValueEnum};
+use coalescer::{CoalescerConfig, EstimateCoalescer};
+use deploy::{Artifact, Deployment, MAX_CODE_SIZE, MAX_INITCODE_SIZE};
+use divergence::DivergenceMonitor;
+use evm_pool::{EvmPool, EvmPoolConfig};
+use gas_estimator::{EstimatorConfig, GasEstimator, Tx};
+use margin::MarginPolicy;
+use profile::{ExecutionProfile, SourceProfile};
+use provider_pool::{ProviderPool, ProviderPoolConfig};
 use rpc_server::RpcServer;
-use std::net::SocketAddr;
+use snapshot::{take_snapshot, GasSnapshot, SnapshotSpec};
+use state_cache::{StateCache, StateCacheConfig};
+use state_store::StateStore;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::signal;
 
+#[derive(Parser)]
+#[command(about = "Gas estimation JSON-RPC server")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the JSON-RPC server (default)
+    Serve,
+    /// Inspect and maintain the on-disk state store
+    State {
+        /// Store directory
+        #[arg(long, env = "STATE_DIR")]
+        dir: PathBuf,
+        #[command(subcommand)]
+        action: StateCommand,
+    },
+    /// Estimate a mined transaction on the state it ran on and compare with its receipt
+    Replay {
+        /// Hash of the mined transaction
+        tx_hash: B256,
+        /// Node serving the transaction's block, with `debug_traceBlockByHash` when it is not the first in its block
+        #[arg(long, env = "ETH_RPC_URL")]
+        rpc_url: String,
+        /// Print the whole report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Estimate deploying a contract from its Foundry artifact
+    Deploy {
+        /// Artifact, e.g. contracts/out/Counter.sol/Counter.json
+        artifact: PathBuf,
+        /// Constructor arguments as a JSON array
+        #[arg(long, default_value = "[]")]
+        args: String,
+        /// Library address, as `<name>=<address>` or `<file>:<name>=<address>`
+        #[arg(long = "library", value_parser = parse_library)]
+        libraries: Vec<(String, Address)>,
+        #[arg(long)]
+        from: Option<Address>,
+        /// Wei sent to the constructor
+        #[arg(long, default_value_t = U256::ZERO)]
+        value: U256,
+        /// Nonce of the CREATE transaction, the sender's next one when absent
+        #[arg(long)]
+        nonce: Option<u64>,
+        /// Deploy through the CREATE2 deployer with this salt
+        #[arg(long)]
+        salt: Option<B256>,
+        #[arg(long, env = "ETH_RPC_URL")]
+        rpc_url: String,
+        /// Print the whole report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Simulate a call and report its gas per Solidity function and line of the called contract
+    Profile {
+        /// Artifact of the called contract, e.g. contracts/out/Counter.sol/Counter.json
+        artifact: PathBuf,
+        #[arg(long)]
+        to: Address,
+        /// Called function, e.g. `setNumber(uint256)`
+        #[arg(long)]
+        function: String,
+        /// Function arguments as a JSON array
+        #[arg(long, default_value = "[]")]
+        args: String,
+        #[arg(long)]
+        from: Option<Address>,
+        #[arg(long, default_value_t = U256::ZERO)]
+        value: U256,
+        /// Account whose code is the artifact's, when `to` is a proxy
+        #[arg(long)]
+        code_address: Option<Address>,
+        /// Directory the artifact's source paths are relative to
+        #[arg(long, default_value = ".")]
+        root: PathBuf,
+        /// Lines to print
+        #[arg(long, default_value_t = 20)]
+        top: usize,
+        #[arg(long, env = "ETH_RPC_URL")]
+        rpc_url: String,
+        /// Print the whole report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Replay the transactions of a block range and report the estimator's accuracy
+    Benchmark {
+        #[arg(long)]
+        from_block: u64,
+        #[arg(long)]
+        to_block: u64,
+        /// Only replay this many blocks, spread evenly over the range
+        #[arg(long)]
+        sample: Option<usize>,
+        /// Archive node with `debug_traceBlockByHash` serving the range
+        #[arg(long, env = "ETH_RPC_URL")]
+        rpc_url: String,
+        #[arg(long, value_enum, default_value_t = ReportFormat::Json)]
+        format: ReportFormat,
+        /// Worst offender groups to report
+        #[arg(long, default_value_t = 20)]
+        top: usize,
+        /// Output file, stdout when absent
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Deploy and call the contracts of a spec file on a local chain, write their estimated gas
+    /// to a `.gas-snapshot` file and fail on regressions against the previous one
+    Snapshot {
+        /// Spec file, e.g. contracts/gas-snapshot.toml
+        spec: PathBuf,
+        /// Snapshot file, `.gas-snapshot` next to the spec when absent
+        #[arg(long)]
+        snapshot: Option<PathBuf>,
+        /// Gas change in percent, either way, that is not reported
+        #[arg(long, default_value_t = 0.0)]
+        tolerance: f64,
+        /// Only compare with the snapshot file, without rewriting it
+        #[arg(long)]
+        check: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Subcommand)]
+enum StateCommand {
+    /// List stored blocks with their entry counts and sizes
+    Inspect {
+        #[arg(long)]
+        chain: Option<u64>,
+    },
+    /// Remove old blocks and the bytecode only they referred to
+    Prune {
+        /// Only prune this chain
+        #[arg(long)]
+        chain: Option<u64>,
+        /// Remove blocks below this number
+        #[arg(long, required_unless_present = "keep_last")]
+        before: Option<u64>,
+        /// Keep only the newest N blocks of each chain
+        #[arg(long)]
+        keep_last: Option<usize>,
+    },
+    /// Write the stored state of one block as JSON
+    Export {
+        #[arg(long)]
+        chain: u64,
+        #[arg(long)]
+        block: u64,
+        /// Block hash, when several blocks are stored at the same height
+        #[arg(long)]
+        hash: Option<B256>,
+        /// Output file, stdout when absent
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    match Cli::parse().command.unwrap_or(Command::Serve) {
+        Command::Serve => serve().await,
+        Command::State { dir, action } => state(StateStore::open(dir)?, action),
+        Command::Replay {
+            tx_hash,
+            rpc_url,
+            json,
+        } => replay(tx_hash, &rpc_url, json).await,
+        Command::Deploy {
+            artifact,
+            args,
+            libraries,
+            from,
+            value,
+            nonce,
+            salt,
+            rpc_url,
+            json,
+        } => {
+            let args: Vec<serde_json::Value> = serde_json::from_str(&args)?;
+            let deployment = Deployment::new(
+                &Artifact::load(&artifact)?,
+                &args,
+                &libraries.into_iter().collect(),
+            )?;
+            let deployment = Deployment {
+                from,
+                value,
+                nonce,
+                salt,
+                ..deployment
+            };
+            deploy(deployment, &rpc_url, json).await
+        }
+        Command::Profile {
+            artifact,
+            to,
+            function,
+            args,
+            from,
+            value,
+            code_address,
+            root,
+            top,
+            rpc_url,
+            json,
+        } => {
+            let artifact = Artifact::load(&artifact)?;
+            let source_path = artifact
+                .source_path()
+                .ok_or("the artifact names no source file")?;
+            let sources = HashMap::from([(
+                source_path.clone(),
+                std::fs::read_to_string(root.join(&source_path))?,
+            )]);
+            let args: Vec<serde_json::Value> = serde_json::from_str(&args)?;
+            let tx = Tx {
+                from,
+                to: Some(to),
+                value,
+                data: Some(FunctionSpec::Signature(function).encode_call(&args)?),
+                ..Default::default()
+            };
+
+            let pool = ProviderPool::new(provider_pool_config()?)?;
+            let mut config = estimator_config()?;
+            let code_address = code_address.unwrap_or(to);
+            config.profile = Some(code_address);
+            let estimator = GasEstimator::new(pool.get(&rpc_url)?, config);
+            let mut estimate = estimator.estimate_gas(tx).await?;
+            let profile = estimate
+                .profile
+                .take()
+                .unwrap_or_else(|| ExecutionProfile::new(code_address));
+            let report = SourceProfile::new(&profile, &artifact, &sources)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                return Ok(());
+            }
+            println!(
+                "Estimated gas {}, of which {} ran the code at {} ({} unmapped)",
+                estimate.estimated_gas, report.total_gas, report.code_address, report.unmapped_gas
+            );
+            println!();
+            println!("{:>10}  function", "gas");
+            for function in &report.functions {
+                println!("{:>10}  {}", function.gas, function.function);
+            }
+            println!();
+            println!("{:>10}  {:>10}  line", "gas", "executions");
+            for line in report.lines.iter().take(top) {
+                println!(
+                    "{:>10}  {:>10}  {}:{}  {}",
+                    line.gas, line.executions, line.file, line.line, line.source
+                );
+            }
+            Ok(())
+        }
+        Command::Benchmark {
+            from_block,
+            to_block,
+            sample,
+            rpc_url,
+            format,
+            top,
+            out,
+        } => {
+            let blocks = sample_blocks(from_block, to_block, sample);
+            let pool = ProviderPool::new(provider_pool_config()?)?;
+            let estimator = GasEstimator::new(pool.get(&rpc_url)?, estimator_config()?);
+            let mut outcomes = Vec::new();
+            for (done, block) in blocks.iter().enumerate() {
+                // Progress goes to stderr, so the report alone can be piped from stdout
+                eprintln!("Replaying block {} ({}/{})", block, done + 1, blocks.len());
+                for outcome in estimator.replay_block(*block).await? {
+                    if let Err(e) = &outcome.report {
+                        eprintln!("    Failed to estimate {}: {}", outcome.tx_hash, e);
+                    }
+                    outcomes.push(outcome);
+                }
+            }
+
+            let report = AccuracyReport::new(from_block, to_block, blocks.len(), &outcomes, top);
+            let report = match format {
+                ReportFormat::Json => serde_json::to_string_pretty(&report)?,
+                ReportFormat::Csv => report.to_csv(),
+            };
+            match out {
+                Some(path) => std::fs::write(path, report)?,
+                None => println!("{}", report),
+            }
+            Ok(())
+        }
+        Command::Snapshot {
+            spec,
+            snapshot,
+            tolerance,
+            check,
+        } => {
+            let root = spec.parent().unwrap_or(Path::new("."));
+            let path = snapshot.unwrap_or_else(|| root.join(".gas-snapshot"));
+            gas_snapshot(&SnapshotSpec::load(&spec)?, root, &path, tolerance, check).await
+        }
+    }
+}
+
+async fn replay(
+    tx_hash: B256,
+    rpc_url: &str,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = ProviderPool::new(provider_pool_config()?)?;
+    let estimator = GasEstimator::new(pool.get(rpc_url)?, estimator_config()?);
+    let report = estimator.replay_transaction(tx_hash).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    println!(
+        "Transaction {} (block {}, index {})",
+        report.tx_hash, report.block_number, report.transaction_index
+    );
+    println!(
+        "    Gas used:  {}{}",
+        report.gas_used,
+        if report.success { "" } else { " (reverted)" }
+    );
+    println!("    Estimated: {}", report.estimate.estimated_gas);
+    println!(
+        "    Difference: {} ({:+.2}%){}",
+        report.difference,
+        report.difference_percent,
+        if report.underestimated {
+            ", would have run out of gas"
+        } else {
+            ""
+        }
+    );
+    Ok(())
+}
+
+async fn deploy(
+    deployment: Deployment,
+    rpc_url: &str,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = ProviderPool::new(provider_pool_config()?)?;
+    let estimator = GasEstimator::new(pool.get(rpc_url)?, estimator_config()?);
+    let report = estimator.estimate_deployment(deployment).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    println!(
+        "Deploys at {}{}",
+        report.address,
+        if report.salt.is_some() {
+            " (CREATE2)"
+        } else {
+            ""
+        }
+    );
+    println!("    Gas:          {}", report.estimate.estimated_gas);
+    println!("    Cost:         {}", report.estimate.total_cost_native);
+    println!(
+        "    Runtime code: {} / {} bytes{}",
+        report.runtime_code_size,
+        MAX_CODE_SIZE,
+        if report.exceeds_code_size_limit {
+            ", over the EIP-170 limit"
+        } else {
+            ""
+        }
+    );
+    println!(
+        "    Initcode:     {} / {} bytes{}",
+        report.initcode_size,
+        MAX_INITCODE_SIZE,
+        if report.exceeds_initcode_size_limit {
+            ", over the EIP-3860 limit"
+        } else {
+            ""
+        }
+    );
+    Ok(())
+}
+
+async fn gas_snapshot(
+    spec: &SnapshotSpec,
+    root: &Path,
+    path: &Path,
+    tolerance: f64,
+    check: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let previous = match std::fs::read_to_string(path) {
+        Ok(previous) => Some(GasSnapshot::parse(&previous)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !check => None,
+        Err(e) => return Err(format!("{}: {e}", path.display()).into()),
+    };
+    let snapshot = take_snapshot(spec, root).await?;
+
+    let mut regressions = 0;
+    if let Some(previous) = previous {
+        let diff = snapshot.compare(&previous, tolerance);
+        for change in &diff.regressions {
+            println!(
+                "Regression  {} (gas: {} -> {}, {:+.3}%)",
+                change.name,
+                change.previous,
+                change.current,
+                change.percent()
+            );
+        }
+        for change in &diff.improvements {
+            println!(
+                "Improvement {} (gas: {} -> {}, {:+.3}%)",
+                change.name,
+                change.previous,
+                change.current,
+                change.percent()
+            );
+        }
+        for name in &diff.added {
+            println!("Added       {}", name);
+        }
+        for name in &diff.removed {
+            println!("Removed     {}", name);
+        }
+        regressions = diff.regressions.len();
+    }
+
+    if !check {
+        std::fs::write(path, snapshot.to_string())?;
+        println!("Wrote {} entries to {}", snapshot.0.len(), path.display());
+    }
+    if regressions > 0 {
+        return Err(format!("{regressions} gas regressions above {tolerance}%").into());
+    }
+    Ok(())
+}
+
+/// `<name>=<address>` of a `--library` argument
+fn parse_library(library: &str) -> Result<(String, Address), String> {
+    let (name, address) = library
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected <name>=<address>, got {library}"))?;
+    let address = address.parse().map_err(|e| format!("{address}: {e}"))?;
+    Ok((name.to_string(), address))
+}
+
+fn state(store: StateStore, action: StateCommand) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        StateCommand::Inspect { chain } => {
+            let blocks: Vec<_> = store
+                .blocks()?
+                .into_iter()
+                .filter(|block| chain.is_none_or(|chain| chain == block.chain_id))
+                .collect();
+            println!(
+                "{:>10} {:>12} {:<66} {:>9} {:>9} {:>10}",
+                "chain", "block", "hash", "accounts", "slots", "bytes"
+            );
+            for block in &blocks {
+                println!(
+                    "{:>10} {:>12} {:<66} {:>9} {:>9} {:>10}",
+                    block.chain_id,
+                    block.block_number,
+                    block.block_hash,
+                    block.accounts,
+                    block.storage_slots,
+                    block.size_bytes
+                );
+            }
+            println!(
+                "{} blocks, {} bytes",
+                blocks.len(),
+                blocks.iter().map(|block| block.size_bytes).sum::<u64>()
+            );
+        }
+        StateCommand::Prune {
+            chain,
+            before,
+            keep_last,
+        } => {
+            let report = store.prune(chain, before, keep_last)?;
+            println!(
+                "Removed {} blocks and {} bytecodes",
+                report.blocks_removed, report.bytecodes_removed
+            );
+        }
+        StateCommand::Export {
+            chain,
+            block,
+            hash,
+            out,
+        } => {
+            let export = serde_json::to_string_pretty(&store.export(chain, block, hash)?)?;
+            match out {
+                Some(path) => std::fs::write(path, export)?,
+                None => println!("{}", export),
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn serve() -> Result<(), Box<dyn std::error::Error>> {
     // Default configuration
     let bind_addr: SocketAddr = "127.0.0.1:3030".parse()?;
-    let default_rpc_url = std::env::var("ETH_RPC_URL")
-        .unwrap_or_else(|_| "https://eth-mainnet.alchemyapi.io/v2/demo".to_string());
+    // Without one, requests go to the default upstream of their chain's profile
+    let default_rpc_url = std::env::var("ETH_RPC_URL").ok();
 
-    println!("Using Ethereum RPC: {}", default_rpc_url);
+    let config = estimator_config()?;
+    let pool = Arc::new(ProviderPool::new(provider_pool_config()?)?);
+
+    let rpc_url = default_rpc_url
+        .clone()
+        .or(config.chains.get(1).default_rpc_url)
+        .ok_or("ETH_RPC_URL is not set and the mainnet profile has no default_rpc_url")?;
+    println!("Using Ethereum RPC: {}", rpc_url);
 
     // Test connection to the RPC endpoint
     println!("Testing connection to Ethereum network...");
-    let estimator = GasEstimator::new(&default_rpc_url);
+    let estimator = GasEstimator::new(pool.get(&rpc_url)?, config.clone());
     match estimator.get_network_gas_info().await {
         Ok(network_info) => {
             println!("    Connected to Ethereum network!");
@@ -44,12 +606,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Start the RPC server
     println!("Starting JSON-RPC server on {}", bind_addr);
-    let server = RpcServer::new(bind_addr, default_rpc_url).await?;
+    let server = RpcServer::new(bind_addr, default_rpc_url, config, pool).await?;
     let actual_addr = server.local_addr();
 
     println!("Gas Estimation JSON-RPC Server is running!");
     println!("Address: http://{}", actual_addr);
-    println!("Endpoint: estimate_gas");
+    println!(
+        "Endpoints: estimate_gas, estimate_deployment, replay_transaction, cache_stats, metrics"
+    );
     println!();
     println!("Example request:");
     println!(
@@ -81,3 +645,96 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+/// Estimator configuration from the environment. Notes go to stderr, leaving stdout to command output.
+fn estimator_config() -> Result<EstimatorConfig, Box<dyn std::error::Error>> {
+    let mut config = EstimatorConfig::default();
+    if let Ok(default_sender) = std::env::var("DEFAULT_SENDER") {
+        config.default_sender = default_sender.parse()?;
+    }
+    eprintln!("Default sender: {}", config.default_sender);
+    if let Ok(path) = std::env::var("CHAIN_PROFILES") {
+        config.chains = Arc::new(ChainRegistry::load(&path)?);
+        eprintln!("Loaded chain profiles from {}", path);
+    }
+
+    let mut cache_config = StateCacheConfig::default();
+    if let Ok(max_accounts) = std::env::var("STATE_CACHE_MAX_ACCOUNTS") {
+        cache_config.max_accounts = max_accounts.parse()?;
+    }
+    if let Ok(max_slots) = std::env::var("STATE_CACHE_MAX_STORAGE_SLOTS") {
+        cache_config.max_storage_slots = max_slots.parse()?;
+    }
+    config.state_cache = Arc::new(StateCache::new(cache_config));
+
+    if let Ok(dir) = std::env::var("STATE_DIR") {
+        config.state_store = Some(Arc::new(StateStore::open(&dir)?));
+        eprintln!("Persisting fetched state to {}", dir);
+    }
+    if let Ok(block) = std::env::var("ESTIMATE_BLOCK") {
+        config.block = Some(block.parse()?);
+        eprintln!("Estimating against block {}", block);
+    }
+    if let Ok(max_rounds) = std::env::var("SIM_MAX_ROUNDS") {
+        config.limits.max_rounds = max_rounds.parse()?;
+    }
+    if let Ok(timeout) = std::env::var("SIM_TIMEOUT_SECS") {
+        config.limits.max_duration = Duration::from_secs(timeout.parse()?);
+    }
+    if let Ok(max_slots) = std::env::var("SIM_MAX_SLOTS") {
+        config.limits.max_fetched_slots = max_slots.parse()?;
+    }
+    if let Ok(max_steps) = std::env::var("SIM_MAX_STEPS") {
+        config.limits.max_steps = max_steps.parse()?;
+    }
+    let mut evm_pool_config = EvmPoolConfig::default();
+    if let Ok(threads) = std::env::var("EVM_THREADS") {
+        evm_pool_config.threads = threads.parse()?;
+    }
+    if let Ok(queue_size) = std::env::var("EVM_QUEUE_SIZE") {
+        evm_pool_config.queue_size = queue_size.parse()?;
+    }
+    config.evm_pool = Arc::new(EvmPool::new(evm_pool_config));
+    let mut coalescer_config = CoalescerConfig::default();
+    if let Ok(ttl) = std::env::var("ESTIMATE_CACHE_TTL_MS") {
+        coalescer_config.result_ttl = Duration::from_millis(ttl.parse()?);
+    }
+    if let Ok(max_results) = std::env::var("ESTIMATE_CACHE_MAX_RESULTS") {
+        coalescer_config.max_results = max_results.parse()?;
+    }
+    config.coalescer = Arc::new(EstimateCoalescer::new(coalescer_config));
+    if let Ok(margin) = std::env::var("MARGIN_POLICY") {
+        let margin: MarginPolicy = serde_json::from_str(&margin)?;
+        margin.validate()?;
+        config.margin = margin;
+    }
+    if let Ok(threshold) = std::env::var("DIVERGENCE_THRESHOLD_PERCENT") {
+        config.divergence = Arc::new(DivergenceMonitor::new(Some(threshold.parse()?)));
+        eprintln!(
+            "Comparing every estimate with the upstream, threshold {}%",
+            threshold
+        );
+    }
+    if let Ok(verify_state) = std::env::var("VERIFY_STATE") {
+        config.verify_state = verify_state.parse()?;
+        eprintln!("Verified state by default: {}", config.verify_state);
+    }
+    if let Ok(url) = std::env::var("TRUSTED_RPC_URL") {
+        config.trusted_upstream = Some(RootProvider::new_http(url.parse()?));
+        eprintln!("Confirming verified blocks with {}", url);
+    }
+
+    Ok(config)
+}
+
+/// Upstream connection settings from the environment
+fn provider_pool_config() -> Result<ProviderPoolConfig, Box<dyn std::error::Error>> {
+    let mut pool_config = ProviderPoolConfig::default();
+    if let Ok(timeout) = std::env::var("UPSTREAM_TIMEOUT_SECS") {
+        pool_config.request_timeout = Duration::from_secs(timeout.parse()?);
+    }
+    if let Ok(max_idle) = std::env::var("UPSTREAM_MAX_IDLE_PER_HOST") {
+        pool_config.max_idle_per_host = max_idle.parse()?;
+    }
+    Ok(pool_config)
+}
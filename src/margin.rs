@@ -0,0 +1,98 @@
+use crate::{error::Error, gas_estimator::ExecutionSummary};
+use serde::{Deserialize, Serialize};
+
+/// Padding added on top of the raw estimate, so integrators get a gas limit they can send as is.
+/// The multiplier and additive amount apply first, together with those of every matching rule,
+/// then the floor and the ceiling, and the padded value never exceeds the block gas limit.
+/// Neither bound takes the padded value below the raw estimate.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MarginPolicy {
+    pub multiplier: Option<f64>,
+    pub additive: u64,
+    pub floor: Option<u64>,
+    pub ceiling: Option<u64>,
+    pub rules: Vec<MarginRule>,
+}
+
+/// Extra padding for transactions whose execution makes the estimate less certain
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarginRule {
+    pub when: MarginCondition,
+    #[serde(default)]
+    pub multiplier: Option<f64>,
+    #[serde(default)]
+    pub additive: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MarginCondition {
+    /// Accounts or storage slots accessed cold, outside the access list
+    ColdAccesses,
+    /// Contracts calling other contracts
+    ExternalCalls,
+    /// Storage cleared, earning a refund that depends on execution order
+    Refunds,
+}
+
+impl MarginCondition {
+    fn holds(self, execution: &ExecutionSummary) -> bool {
+        match self {
+            MarginCondition::ColdAccesses => execution.cold_accesses > 0,
+            MarginCondition::ExternalCalls => execution.external_calls > 0,
+            MarginCondition::Refunds => execution.gas_refunded > 0,
+        }
+    }
+}
+
+/// Largest multiplier a policy or rule may set, so padding stays within any gas limit in reach
+pub const MAX_MULTIPLIER: f64 = 10.0;
+
+impl MarginPolicy {
+    pub fn validate(&self) -> Result<(), Error> {
+        let multipliers = self
+            .multiplier
+            .into_iter()
+            .chain(self.rules.iter().filter_map(|rule| rule.multiplier));
+        for multiplier in multipliers {
+            if !(1.0..=MAX_MULTIPLIER).contains(&multiplier) {
+                return Err(Error::InvalidMarginPolicy(format!(
+                    "multiplier {multiplier} is not a number from 1 to {MAX_MULTIPLIER}"
+                )));
+            }
+        }
+        if let (Some(floor), Some(ceiling)) = (self.floor, self.ceiling) {
+            if floor > ceiling {
+                return Err(Error::InvalidMarginPolicy(format!(
+                    "floor {floor} is above ceiling {ceiling}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// `gas` padded for a transaction that executed as `execution` describes,
+    /// on a chain whose blocks hold `block_gas_limit`
+    pub fn apply(&self, gas: u128, execution: &ExecutionSummary, block_gas_limit: u64) -> u128 {
+        let mut multiplier = self.multiplier.unwrap_or(1.0);
+        let mut additive = self.additive as u128;
+        for rule in self.rules.iter().filter(|rule| rule.when.holds(execution)) {
+            multiplier *= rule.multiplier.unwrap_or(1.0);
+            additive = additive.saturating_add(rule.additive as u128);
+        }
+
+        // In parts per million, so 1.1 pads 100000 gas to 110000 and not 110001.
+        // Rules compound, so the product may still overflow, the limit bounds it then.
+        let multiplier_ppm = (multiplier * 1_000_000.0).round() as u128;
+        let mut padded = gas
+            .checked_mul(multiplier_ppm)
+            .map_or(u128::MAX, |gas| gas.div_ceil(1_000_000))
+            .saturating_add(additive);
+        if let Some(floor) = self.floor {
+            padded = padded.max(floor as u128);
+        }
+        let limit = self.ceiling.unwrap_or(u64::MAX).min(block_gas_limit);
+        padded.min(limit as u128).max(gas)
+    }
+}
@@ -0,0 +1,261 @@
+use crate::{error::Error, gas_estimator::Tx};
+use alloy::{
+    consensus::{SignableTransaction, TxEip1559, TxEip2930, TxEip4844, TxEip7702, TxLegacy},
+    primitives::{address, Address, Bytes, TxKind, U256},
+    sol,
+    sol_types::SolCall,
+};
+
+/// `GasPriceOracle` predeploy, present on every OP-stack chain
+pub const GAS_PRICE_ORACLE: Address = address!("0x420000000000000000000000000000000000000F");
+
+/// Bytes the oracle adds to an unsigned transaction to account for its signature
+const SIGNATURE_OVERHEAD: u64 = 68;
+
+// Fjord linear regression over the FastLZ size, scaled by 1e6
+const FJORD_COST_INTERCEPT: i128 = -42_585_600;
+const FJORD_COST_FASTLZ_COEF: i128 = 836_500;
+const FJORD_MIN_TRANSACTION_SIZE_SCALED: i128 = 100 * 1_000_000;
+
+sol! {
+    interface IGasPriceOracle {
+        function l1BaseFee() external view returns (uint256);
+        function blobBaseFee() external view returns (uint256);
+        function baseFeeScalar() external view returns (uint32);
+        function blobBaseFeeScalar() external view returns (uint32);
+        function isFjord() external view returns (bool);
+    }
+}
+
+/// L1 pricing parameters the `GasPriceOracle` exposes
+#[derive(Debug, Clone)]
+pub struct L1FeeParams {
+    pub l1_base_fee: U256,
+    pub blob_base_fee: U256,
+    pub base_fee_scalar: u32,
+    pub blob_base_fee_scalar: u32,
+    pub is_fjord: bool,
+}
+
+impl L1FeeParams {
+    /// Calldata of the `GasPriceOracle` view calls reading the parameters,
+    /// in the order `decode` takes their outputs
+    pub fn calls() -> Vec<Bytes> {
+        vec![
+            IGasPriceOracle::l1BaseFeeCall {}.abi_encode().into(),
+            IGasPriceOracle::blobBaseFeeCall {}.abi_encode().into(),
+            IGasPriceOracle::baseFeeScalarCall {}.abi_encode().into(),
+            IGasPriceOracle::blobBaseFeeScalarCall {}
+                .abi_encode()
+                .into(),
+            IGasPriceOracle::isFjordCall {}.abi_encode().into(),
+        ]
+    }
+
+    /// The parameters from the outputs of the calls of `calls`
+    pub fn decode(outputs: &[Bytes]) -> Result<Self, Error> {
+        let [l1_base_fee, blob_base_fee, base_fee_scalar, blob_base_fee_scalar, is_fjord] = outputs
+        else {
+            return Err(Error::ViewCallFailed {
+                address: GAS_PRICE_ORACLE,
+                reason: format!("expected 5 outputs, got {}", outputs.len()),
+            });
+        };
+        let undecodable = |e: alloy::sol_types::Error| Error::ViewCallFailed {
+            address: GAS_PRICE_ORACLE,
+            reason: format!("undecodable output: {e}"),
+        };
+        Ok(Self {
+            l1_base_fee: IGasPriceOracle::l1BaseFeeCall::abi_decode_returns(l1_base_fee)
+                .map_err(undecodable)?,
+            blob_base_fee: IGasPriceOracle::blobBaseFeeCall::abi_decode_returns(blob_base_fee)
+                .map_err(undecodable)?,
+            base_fee_scalar: IGasPriceOracle::baseFeeScalarCall::abi_decode_returns(
+                base_fee_scalar,
+            )
+            .map_err(undecodable)?,
+            blob_base_fee_scalar: IGasPriceOracle::blobBaseFeeScalarCall::abi_decode_returns(
+                blob_base_fee_scalar,
+            )
+            .map_err(undecodable)?,
+            is_fjord: IGasPriceOracle::isFjordCall::abi_decode_returns(is_fjord)
+                .map_err(undecodable)?,
+        })
+    }
+
+    /// L1 data fee in wei for an unsigned, RLP-encoded transaction.
+    /// Pre-Ecotone (Bedrock) pricing is not modelled, Ecotone is used instead.
+    pub fn l1_data_fee(&self, unsigned_tx: &[u8]) -> U256 {
+        let scaled_base_fee = U256::from(self.base_fee_scalar) * U256::from(16) * self.l1_base_fee;
+        let scaled_blob_base_fee = U256::from(self.blob_base_fee_scalar) * self.blob_base_fee;
+        let fee_scaled = scaled_base_fee + scaled_blob_base_fee;
+
+        if self.is_fjord {
+            let fastlz_size = flz_compress_len(unsigned_tx) as u64 + SIGNATURE_OVERHEAD;
+            let estimated_size = (FJORD_COST_INTERCEPT
+                + FJORD_COST_FASTLZ_COEF * fastlz_size as i128)
+                .max(FJORD_MIN_TRANSACTION_SIZE_SCALED);
+            U256::from(estimated_size) * fee_scaled / U256::from(1_000_000_000_000u64)
+        } else {
+            let zero_bytes = unsigned_tx.iter().filter(|byte| **byte == 0).count() as u64;
+            let non_zero_bytes = unsigned_tx.len() as u64 - zero_bytes;
+            let l1_gas_used = zero_bytes * 4 + (non_zero_bytes + SIGNATURE_OVERHEAD) * 16;
+            U256::from(l1_gas_used) * fee_scaled / U256::from(16_000_000u64)
+        }
+    }
+}
+
+/// RLP encoding of `tx` without signature, as it would be posted to L1
+pub fn encode_unsigned(
+    tx: &Tx,
+    chain_id: u64,
+    nonce: u64,
+    gas_limit: u64,
+    gas_price: u128,
+) -> Vec<u8> {
+    let to = tx.to.map(TxKind::Call).unwrap_or(TxKind::Create);
+    let input = tx.data.clone().unwrap_or_default();
+    let access_list = tx.access_list.clone().unwrap_or_default();
+    let mut out = Vec::new();
+
+    match tx.transaction_type.map(|tx_type| tx_type.to::<u8>()) {
+        Some(1) => TxEip2930 {
+            chain_id,
+            nonce,
+            gas_price: tx.gas_price.unwrap_or(gas_price),
+            gas_limit,
+            to,
+            value: tx.value,
+            access_list,
+            input,
+        }
+        .encode_for_signing(&mut out),
+        Some(2) => TxEip1559 {
+            chain_id,
+            nonce,
+            gas_limit,
+            max_fee_per_gas: tx.max_fee_per_gas.unwrap_or(gas_price),
+            max_priority_fee_per_gas: tx.max_priority_fee_per_gas.unwrap_or_default(),
+            to,
+            value: tx.value,
+            access_list,
+            input,
+        }
+        .encode_for_signing(&mut out),
+        // Blob and delegation transactions cannot create contracts
+        Some(3) => TxEip4844 {
+            chain_id,
+            nonce,
+            gas_limit,
+            max_fee_per_gas: tx.max_fee_per_gas.unwrap_or(gas_price),
+            max_priority_fee_per_gas: tx.max_priority_fee_per_gas.unwrap_or_default(),
+            to: tx.to.unwrap_or_default(),
+            value: tx.value,
+            access_list,
+            blob_versioned_hashes: tx.blob_versioned_hashes.clone().unwrap_or_default(),
+            max_fee_per_blob_gas: tx.max_fee_per_blob_gas.unwrap_or_default(),
+            input,
+        }
+        .encode_for_signing(&mut out),
+        Some(4) => TxEip7702 {
+            chain_id,
+            nonce,
+            gas_limit,
+            max_fee_per_gas: tx.max_fee_per_gas.unwrap_or(gas_price),
+            max_priority_fee_per_gas: tx.max_priority_fee_per_gas.unwrap_or_default(),
+            to: tx.to.unwrap_or_default(),
+            value: tx.value,
+            access_list,
+            authorization_list: tx.authorization_list.clone().unwrap_or_default(),
+            input,
+        }
+        .encode_for_signing(&mut out),
+        _ => TxLegacy {
+            chain_id: Some(chain_id),
+            nonce,
+            gas_price: tx.gas_price.unwrap_or(gas_price),
+            gas_limit,
+            to,
+            value: tx.value,
+            input,
+        }
+        .encode_for_signing(&mut out),
+    }
+
+    out
+}
+
+/// Length of `data` compressed with FastLZ level 1, as computed by the
+/// `GasPriceOracle` (Solady `LibZip.flzCompress`) and op-geth
+pub fn flz_compress_len(data: &[u8]) -> u32 {
+    let mut n: u32 = 0;
+    let mut hash_table = vec![0u32; 8192];
+
+    let u24 = |i: u32| -> u32 {
+        let i = i as usize;
+        data[i] as u32 | (data[i + 1] as u32) << 8 | (data[i + 2] as u32) << 16
+    };
+    let cmp = |p: u32, q: u32, e: u32| -> u32 {
+        let mut l = 0;
+        let mut e = e - q;
+        while l < e {
+            if data[(p + l) as usize] != data[(q + l) as usize] {
+                e = 0;
+            }
+            l += 1;
+        }
+        l
+    };
+    let literals = |n: &mut u32, mut r: u32| {
+        *n += 0x21 * (r / 0x20);
+        r %= 0x20;
+        if r != 0 {
+            *n += r + 1;
+        }
+    };
+    let match_len = |n: &mut u32, l: u32| {
+        let l = l - 1;
+        *n += 3 * (l / 262);
+        *n += if l % 262 >= 6 { 3 } else { 2 };
+    };
+    let hash = |v: u32| -> usize { ((2654435769u32.wrapping_mul(v) >> 19) & 0x1fff) as usize };
+
+    let len = data.len() as u32;
+    let ip_limit = len.saturating_sub(13);
+    let mut anchor = 0;
+    let mut ip = anchor + 2;
+    while ip < ip_limit {
+        let mut reference;
+        loop {
+            let seq = u24(ip);
+            let h = hash(seq);
+            reference = hash_table[h];
+            hash_table[h] = ip;
+            let distance = ip - reference;
+            if ip >= ip_limit {
+                break;
+            }
+            ip += 1;
+            if distance <= 0x1fff && seq == u24(reference) {
+                break;
+            }
+        }
+        if ip >= ip_limit {
+            break;
+        }
+        ip -= 1;
+        if ip > anchor {
+            literals(&mut n, ip - anchor);
+        }
+        let l = cmp(reference + 3, ip + 3, ip_limit + 9);
+        match_len(&mut n, l);
+        ip += l;
+        hash_table[hash(u24(ip))] = ip;
+        ip += 1;
+        hash_table[hash(u24(ip))] = ip;
+        ip += 1;
+        anchor = ip;
+    }
+    literals(&mut n, len - anchor);
+    n
+}
@@ -0,0 +1,474 @@
+use crate::{deploy::Artifact, error::Error};
+use revm::{bytecode::opcode, primitives::Address};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// Gas spent at one program counter of the profiled code
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PcGas {
+    pub gas: u64,
+    pub executions: u64,
+}
+
+/// Gas spent running the code of one account, by program counter. Each instruction is
+/// charged the gas its frame lost until the next one, so calls and creations include
+/// what the code they run spent.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExecutionProfile {
+    pub code_address: Address,
+    pub pcs: BTreeMap<usize, PcGas>,
+}
+
+impl ExecutionProfile {
+    pub fn new(code_address: Address) -> Self {
+        Self {
+            code_address,
+            pcs: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, pc: usize, gas: u64) {
+        let entry = self.pcs.entry(pc).or_default();
+        entry.gas += gas;
+        entry.executions += 1;
+    }
+}
+
+/// Source range an instruction was compiled from, one per instruction of a source map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceRange {
+    pub offset: usize,
+    pub length: usize,
+    /// Source file index, `None` for code the compiler generated
+    pub file: Option<u32>,
+}
+
+/// Decompress a solc source map: `offset:length:file:jump:modifier_depth` entries separated
+/// by `;`, where empty fields repeat the previous entry's
+pub fn parse_source_map(source_map: &str) -> Result<Vec<SourceRange>, Error> {
+    let mut fields = [0i64; 3];
+    source_map
+        .split(';')
+        .map(|entry| {
+            for (field, value) in fields.iter_mut().zip(entry.split(':')) {
+                if !value.is_empty() {
+                    *field = value.parse().map_err(|_| {
+                        Error::InvalidArtifact(format!("invalid source map entry {entry}"))
+                    })?;
+                }
+            }
+            let [offset, length, file] = fields;
+            Ok(SourceRange {
+                offset: offset.max(0) as usize,
+                length: length.max(0) as usize,
+                file: (offset >= 0 && file >= 0).then_some(file as u32),
+            })
+        })
+        .collect()
+}
+
+/// Program counter of each instruction, in the order of the source map
+pub fn instruction_pcs(code: &[u8]) -> Vec<usize> {
+    let mut pcs = Vec::new();
+    let mut pc = 0;
+    while pc < code.len() {
+        pcs.push(pc);
+        let op = code[pc];
+        pc += 1;
+        if (opcode::PUSH1..=opcode::PUSH32).contains(&op) {
+            pc += (op - opcode::PUSH0) as usize;
+        }
+    }
+    pcs
+}
+
+/// Contract or function body in a source file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Definition {
+    /// `Contract.function` for functions and modifiers, the contract's name for contracts
+    name: String,
+    is_contract: bool,
+    file: u32,
+    start: usize,
+    end: usize,
+}
+
+/// Definitions of the artifact's AST, when it was built with one
+fn ast_definitions(node: &Value, contract: Option<&str>, definitions: &mut Vec<Definition>) {
+    match node {
+        Value::Array(items) => {
+            for item in items {
+                ast_definitions(item, contract, definitions);
+            }
+        }
+        Value::Object(fields) => {
+            let text = |key| fields.get(key).and_then(Value::as_str).unwrap_or_default();
+            let src: Vec<usize> = text("src")
+                .split(':')
+                .filter_map(|field| field.parse().ok())
+                .collect();
+            let mut contract = contract;
+            if let [start, length, file] = src[..] {
+                let definition = |name: String, is_contract| Definition {
+                    name,
+                    is_contract,
+                    file: file as u32,
+                    start,
+                    end: start + length,
+                };
+                match text("nodeType") {
+                    "ContractDefinition" => {
+                        definitions.push(definition(text("name").to_string(), true));
+                        contract = Some(text("name"));
+                    }
+                    "FunctionDefinition" | "ModifierDefinition" => {
+                        // Constructors, fallback and receive functions have no name, only a kind
+                        let name = Some(text("name"))
+                            .filter(|name| !name.is_empty())
+                            .unwrap_or(text("kind"));
+                        let name = match contract {
+                            Some(contract) => format!("{contract}.{name}"),
+                            None => name.to_string(),
+                        };
+                        definitions.push(definition(name, false));
+                    }
+                    _ => {}
+                }
+            }
+            for value in fields.values() {
+                ast_definitions(value, contract, definitions);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// `source` with comments and string literals blanked out, keeping every byte offset
+fn code_only(source: &str) -> Vec<u8> {
+    let mut code = source.as_bytes().to_vec();
+    let mut i = 0;
+    while i < code.len() {
+        let end = match (code[i], code.get(i + 1)) {
+            (b'/', Some(b'/')) => code[i..]
+                .iter()
+                .position(|byte| *byte == b'\n')
+                .map_or(code.len(), |len| i + len),
+            (b'/', Some(b'*')) => code[i + 2..]
+                .windows(2)
+                .position(|window| window == b"*/")
+                .map_or(code.len(), |len| i + 2 + len + 2),
+            (quote @ (b'"' | b'\''), _) => {
+                let mut end = i + 1;
+                while end < code.len() && code[end] != quote {
+                    end += if code[end] == b'\\' { 2 } else { 1 };
+                }
+                (end + 1).min(code.len())
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        for byte in &mut code[i..end] {
+            if *byte != b'\n' {
+                *byte = b' ';
+            }
+        }
+        i = end;
+    }
+    code
+}
+
+/// Definitions found by reading the source itself, for artifacts built without an AST
+fn source_definitions(source: &str, file: u32) -> Vec<Definition> {
+    let code = code_only(source);
+    let is_ident = |byte: u8| byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'$';
+    let ident_end = |start: usize| {
+        start
+            + code[start..]
+                .iter()
+                .position(|byte| !is_ident(*byte))
+                .unwrap_or(code.len() - start)
+    };
+    let skip_space = |start: usize| {
+        start
+            + code[start..]
+                .iter()
+                .position(|byte| !byte.is_ascii_whitespace())
+                .unwrap_or(code.len() - start)
+    };
+
+    let mut definitions = Vec::new();
+    let mut i = 0;
+    while i < code.len() {
+        if !is_ident(code[i]) || (i > 0 && is_ident(code[i - 1])) {
+            i += 1;
+            continue;
+        }
+        let word_end = ident_end(i);
+        let next_name = || {
+            let start = skip_space(word_end);
+            &source[start..ident_end(start)]
+        };
+        let (is_contract, name) = match &code[i..word_end] {
+            b"contract" | b"library" | b"interface" => (true, next_name()),
+            b"function" | b"modifier" => (false, next_name()),
+            b"constructor" | b"fallback" | b"receive"
+                if code.get(skip_space(word_end)) == Some(&b'(') =>
+            {
+                (false, &source[i..word_end])
+            }
+            _ => (false, ""),
+        };
+        // Function types have no name, and declarations without a body are skipped
+        let body = code[word_end..]
+            .iter()
+            .position(|byte| matches!(byte, b'{' | b';'))
+            .map(|len| word_end + len)
+            .filter(|body| code[*body] == b'{');
+        if let Some(body) = body.filter(|_| !name.is_empty()) {
+            let mut depth = 0;
+            let end = code[body..]
+                .iter()
+                .position(|byte| {
+                    match byte {
+                        b'{' => depth += 1,
+                        b'}' => depth -= 1,
+                        _ => {}
+                    }
+                    depth == 0
+                })
+                .map_or(code.len(), |len| body + len + 1);
+            definitions.push(Definition {
+                name: name.to_string(),
+                is_contract,
+                file,
+                start: i,
+                end,
+            });
+        }
+        i = word_end;
+    }
+
+    // Functions are named after the contract they are defined in
+    let contracts: Vec<_> = definitions
+        .iter()
+        .filter(|definition| definition.is_contract)
+        .cloned()
+        .collect();
+    for definition in definitions.iter_mut().filter(|d| !d.is_contract) {
+        if let Some(contract) = contracts
+            .iter()
+            .filter(|contract| contract.start <= definition.start && definition.end <= contract.end)
+            .min_by_key(|contract| contract.end - contract.start)
+        {
+            definition.name = format!("{}.{}", contract.name, definition.name);
+        }
+    }
+    definitions
+}
+
+/// Gas spent in one Solidity function, modifier or constructor, or in a contract outside
+/// of them, such as its function dispatcher
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionGas {
+    pub function: String,
+    pub gas: u64,
+    /// Instructions executed
+    pub executions: u64,
+}
+
+/// Gas spent in instructions compiled from one source line
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineGas {
+    pub file: String,
+    /// 1-based line number
+    pub line: usize,
+    pub function: Option<String>,
+    /// The line's text, trimmed
+    pub source: String,
+    pub gas: u64,
+    pub executions: u64,
+}
+
+/// Gas of a simulated transaction attributed to the Solidity source of the profiled
+/// contract, through its runtime source map
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceProfile {
+    pub code_address: Address,
+    /// Gas spent running the profiled code
+    pub total_gas: u64,
+    /// Part of `total_gas` spent in compiler-generated code or sources that were not given
+    pub unmapped_gas: u64,
+    /// Most expensive first
+    pub functions: Vec<FunctionGas>,
+    /// Most expensive first
+    pub lines: Vec<LineGas>,
+}
+
+impl SourceProfile {
+    /// Map `profile` to the sources of `artifact`, given by path as in the artifact's metadata
+    pub fn new(
+        profile: &ExecutionProfile,
+        artifact: &Artifact,
+        sources: &HashMap<String, String>,
+    ) -> Result<Self, Error> {
+        let source_map = artifact
+            .deployed_bytecode
+            .source_map
+            .as_deref()
+            .ok_or_else(|| Error::InvalidArtifact("no runtime source map".to_string()))?;
+        let ranges = parse_source_map(source_map)?;
+        let code = artifact.deployed_bytecode.unlinked()?;
+        let pcs = instruction_pcs(&code);
+
+        // Only the artifact's own source file is known by its index
+        let files: HashMap<u32, (&str, &str)> = artifact
+            .id
+            .zip(artifact.source_path())
+            .and_then(|(id, path)| {
+                let (path, source) = sources.get_key_value(&path)?;
+                Some((id, (path.as_str(), source.as_str())))
+            })
+            .into_iter()
+            .collect();
+        let mut definitions = Vec::new();
+        match &artifact.ast {
+            Some(ast) => ast_definitions(ast, None, &mut definitions),
+            None => {
+                for (file, (_, source)) in &files {
+                    definitions.extend(source_definitions(source, *file));
+                }
+            }
+        }
+        let line_starts: HashMap<u32, Vec<usize>> = files
+            .iter()
+            .map(|(file, (_, source))| {
+                let starts = std::iter::once(0)
+                    .chain(source.match_indices('\n').map(|(index, _)| index + 1))
+                    .collect();
+                (*file, starts)
+            })
+            .collect();
+
+        // Each instruction's source offset, with the innermost function around its range,
+        // or else the innermost contract
+        let mut locations: Vec<Option<(u32, usize, Option<&Definition>)>> = ranges
+            .iter()
+            .map(|range| {
+                let file = range.file.filter(|file| files.contains_key(file))?;
+                let definition = definitions
+                    .iter()
+                    .filter(|definition| {
+                        definition.file == file
+                            && definition.start <= range.offset
+                            && range.offset + range.length <= definition.end
+                    })
+                    .min_by_key(|definition| {
+                        (definition.is_contract, definition.end - definition.start)
+                    });
+                Some((file, range.offset, definition))
+            })
+            .collect();
+        // The optimizer leaves inlined code, storage accesses included, mapped to the whole
+        // contract. Such instructions take the location of the nearest instruction of their
+        // basic block that is in a function, as the block always runs as a whole.
+        let in_function = |location: &Option<(u32, usize, Option<&Definition>)>| matches!(location, Some((_, _, Some(definition))) if !definition.is_contract);
+        let mut block_start = 0;
+        for index in 0..pcs.len().min(locations.len()) {
+            let op = code[pcs[index]];
+            if op == opcode::JUMPDEST {
+                block_start = index;
+            }
+            let block_ends = matches!(
+                op,
+                opcode::JUMP
+                    | opcode::JUMPI
+                    | opcode::STOP
+                    | opcode::RETURN
+                    | opcode::REVERT
+                    | opcode::INVALID
+                    | opcode::SELFDESTRUCT
+            ) || index + 1 == locations.len();
+            if !block_ends {
+                continue;
+            }
+            let block = block_start..index + 1;
+            for inlined in block.clone() {
+                if in_function(&locations[inlined]) {
+                    continue;
+                }
+                let nearest = block
+                    .clone()
+                    .filter(|other| in_function(&locations[*other]))
+                    .min_by_key(|other| (other.abs_diff(inlined), *other > inlined));
+                if let Some(nearest) = nearest {
+                    locations[inlined] = locations[nearest];
+                }
+            }
+            block_start = index + 1;
+        }
+
+        let mut total_gas = 0;
+        let mut unmapped_gas = 0;
+        let mut functions: BTreeMap<String, FunctionGas> = BTreeMap::new();
+        let mut lines: BTreeMap<(u32, usize), LineGas> = BTreeMap::new();
+        for (pc, spent) in &profile.pcs {
+            total_gas += spent.gas;
+            let location = pcs
+                .binary_search(pc)
+                .ok()
+                .and_then(|index| locations.get(index).copied().flatten());
+            let Some((file, offset, definition)) = location else {
+                unmapped_gas += spent.gas;
+                continue;
+            };
+            let (path, source) = files[&file];
+
+            let function = definition.map(|definition| definition.name.clone());
+            if let Some(function) = &function {
+                let entry = functions
+                    .entry(function.clone())
+                    .or_insert_with(|| FunctionGas {
+                        function: function.clone(),
+                        gas: 0,
+                        executions: 0,
+                    });
+                entry.gas += spent.gas;
+                entry.executions += spent.executions;
+            }
+
+            let starts = &line_starts[&file];
+            let line = starts.partition_point(|start| *start <= offset);
+            let entry = lines.entry((file, line)).or_insert_with(|| {
+                let text = source[starts[line - 1]..]
+                    .lines()
+                    .next()
+                    .unwrap_or_default();
+                LineGas {
+                    file: path.to_string(),
+                    line,
+                    function,
+                    source: text.trim().to_string(),
+                    gas: 0,
+                    executions: 0,
+                }
+            });
+            entry.gas += spent.gas;
+            entry.executions += spent.executions;
+        }
+
+        let mut functions: Vec<_> = functions.into_values().collect();
+        functions.sort_by_key(|function| std::cmp::Reverse(function.gas));
+        let mut lines: Vec<_> = lines.into_values().collect();
+        lines.sort_by_key(|line| std::cmp::Reverse(line.gas));
+        Ok(Self {
+            code_address: profile.code_address,
+            total_gas,
+            unmapped_gas,
+            functions,
+            lines,
+        })
+    }
+}
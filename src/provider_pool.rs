@@ -0,0 +1,137 @@
+use crate::error::Error;
+use alloy::{
+    providers::RootProvider,
+    rpc::client::RpcClient,
+    transports::{
+        http::{reqwest, Http},
+        RpcError, TransportErrorKind,
+    },
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+#[derive(Debug, Clone)]
+pub struct ProviderPoolConfig {
+    /// Idle connections kept open per upstream host
+    pub max_idle_per_host: usize,
+    /// How long an idle connection is kept before being closed
+    pub idle_timeout: Duration,
+    /// Timeout for establishing a connection
+    pub connect_timeout: Duration,
+    /// Timeout for a whole upstream request
+    pub request_timeout: Duration,
+    /// Upstream URLs kept in the pool; providers for further URLs are not cached
+    pub max_upstreams: usize,
+}
+
+impl Default for ProviderPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle_per_host: 32,
+            idle_timeout: Duration::from_secs(90),
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            max_upstreams: 64,
+        }
+    }
+}
+
+/// Optional upstream method, marked unavailable once the upstream rejects a call to it
+#[derive(Debug, Default)]
+pub struct Capability {
+    unavailable: AtomicBool,
+}
+
+impl Capability {
+    pub fn is_available(&self) -> bool {
+        !self.unavailable.load(Ordering::Relaxed)
+    }
+
+    /// Mark the method unavailable when the upstream answered with an error or an unexpected
+    /// result. Transport failures say nothing about the method, it is tried again next time.
+    pub fn record_failure(&self, error: &RpcError<TransportErrorKind>) {
+        if matches!(error, RpcError::ErrorResp(_) | RpcError::DeserError { .. }) {
+            self.unavailable.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Optional methods an upstream was found to support, shared by all requests to it
+#[derive(Debug, Default)]
+pub struct UpstreamCapabilities {
+    pub get_proof: Capability,
+    pub prestate_trace: Capability,
+}
+
+/// Provider for one upstream URL, with what is known about the upstream
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub provider: RootProvider,
+    pub capabilities: Arc<UpstreamCapabilities>,
+}
+
+impl Upstream {
+    pub fn new(provider: RootProvider) -> Self {
+        Self {
+            provider,
+            capabilities: Arc::default(),
+        }
+    }
+}
+
+/// Long-lived providers keyed by upstream URL, sharing one HTTP client and its connections
+pub struct ProviderPool {
+    client: reqwest::Client,
+    upstreams: RwLock<HashMap<String, Upstream>>,
+    config: ProviderPoolConfig,
+}
+
+impl ProviderPool {
+    pub fn new(config: ProviderPoolConfig) -> Result<Self, Error> {
+        let client = reqwest::Client::builder()
+            .pool_max_idle_per_host(config.max_idle_per_host)
+            .pool_idle_timeout(config.idle_timeout)
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.request_timeout)
+            .build()
+            .map_err(Error::HttpClient)?;
+
+        Ok(Self {
+            client,
+            upstreams: RwLock::new(HashMap::new()),
+            config,
+        })
+    }
+
+    /// Upstream for `rpc_url`, created on first use
+    pub fn get(&self, rpc_url: &str) -> Result<Upstream, Error> {
+        if let Some(upstream) = self.upstreams.read().unwrap().get(rpc_url) {
+            return Ok(upstream.clone());
+        }
+
+        let url: reqwest::Url = rpc_url
+            .parse()
+            .map_err(|_| Error::InvalidRpcUrl(rpc_url.to_string()))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(Error::InvalidRpcUrl(rpc_url.to_string()));
+        }
+        let is_local = matches!(url.host_str(), Some("localhost" | "127.0.0.1"));
+        let transport = Http::with_client(self.client.clone(), url);
+        let upstream = Upstream::new(RootProvider::new(RpcClient::new(transport, is_local)));
+
+        let mut upstreams = self.upstreams.write().unwrap();
+        if upstreams.len() >= self.config.max_upstreams && !upstreams.contains_key(rpc_url) {
+            return Ok(upstream);
+        }
+        Ok(upstreams
+            .entry(rpc_url.to_string())
+            .or_insert(upstream)
+            .clone())
+    }
+}
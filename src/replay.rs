@@ -0,0 +1,176 @@
+use crate::{
+    error::Error,
+    gas_estimator::{GasEstimate, Tx},
+    state_cache::CachedAccount,
+};
+use alloy::rpc::types::trace::geth::DiffMode;
+use revm::primitives::{keccak256, Address, Bytes, B256, KECCAK_EMPTY, U256};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Our estimate of a mined transaction, replayed on the state it ran on, next to what it actually used
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayReport {
+    pub tx_hash: B256,
+    pub block_number: u64,
+    pub transaction_index: u64,
+    /// The transaction rebuilt from the chain, as estimated
+    pub transaction: Tx,
+    pub estimate: GasEstimate,
+    /// Gas used according to the receipt
+    pub gas_used: u64,
+    /// Whether the transaction succeeded on chain
+    pub success: bool,
+    /// Our estimate minus `gas_used`
+    pub difference: i128,
+    /// `difference` relative to `gas_used`
+    pub difference_percent: f64,
+    /// Our estimate is below what the transaction used, so as its gas limit it would have run out of gas
+    pub underestimated: bool,
+}
+
+impl ReplayReport {
+    pub fn new(
+        tx_hash: B256,
+        block_number: u64,
+        transaction_index: u64,
+        transaction: Tx,
+        estimate: GasEstimate,
+        gas_used: u64,
+        success: bool,
+    ) -> Self {
+        let difference = estimate.estimated_gas as i128 - gas_used as i128;
+        Self {
+            tx_hash,
+            block_number,
+            transaction_index,
+            transaction,
+            difference,
+            difference_percent: if gas_used > 0 {
+                difference as f64 / gas_used as f64 * 100.0
+            } else {
+                0.0
+            },
+            underestimated: difference < 0,
+            estimate,
+            gas_used,
+            success,
+        }
+    }
+}
+
+/// Replay of one transaction of a block, which may have failed to estimate
+#[derive(Debug)]
+pub struct ReplayOutcome {
+    pub tx_hash: B256,
+    pub transaction: Tx,
+    pub report: Result<ReplayReport, Error>,
+}
+
+/// One entry of `debug_traceBlockByHash` with the prestate tracer in diff mode
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransactionDiff {
+    pub result: DiffMode,
+}
+
+/// State changes of the transactions mined before the replayed one in its block,
+/// read on top of the parent block's state
+#[derive(Debug, Clone, Default)]
+pub struct StateOverlay {
+    accounts: HashMap<Address, AccountChange>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct AccountChange {
+    /// Destroyed, so nothing of the account or the storage it had at the parent block is left
+    cleared: bool,
+    balance: Option<U256>,
+    nonce: Option<u64>,
+    code: Option<Bytes>,
+    storage: HashMap<U256, U256>,
+}
+
+impl StateOverlay {
+    /// Apply the changes of the next transaction in the block. Only changed fields are in
+    /// `post`, and accounts or slots that were deleted are only in `pre`.
+    pub fn apply(&mut self, diff: DiffMode) {
+        for (address, pre) in &diff.pre {
+            let change = self.accounts.entry(*address).or_default();
+            match diff.post.get(address) {
+                None => {
+                    *change = AccountChange {
+                        cleared: true,
+                        ..Default::default()
+                    }
+                }
+                Some(post) => {
+                    for slot in pre.storage.keys() {
+                        if !post.storage.contains_key(slot) {
+                            change
+                                .storage
+                                .insert(U256::from_be_bytes(slot.0), U256::ZERO);
+                        }
+                    }
+                }
+            }
+        }
+        for (address, post) in diff.post {
+            let change = self.accounts.entry(address).or_default();
+            if let Some(balance) = post.balance {
+                change.balance = Some(balance);
+            }
+            if let Some(nonce) = post.nonce {
+                change.nonce = Some(nonce);
+            }
+            if let Some(code) = post.code {
+                change.code = Some(code);
+            }
+            for (slot, value) in post.storage {
+                change
+                    .storage
+                    .insert(U256::from_be_bytes(slot.0), U256::from_be_bytes(value.0));
+            }
+        }
+    }
+
+    /// `account`, as read at the parent block, with the changes applied, and its code when it changed
+    pub fn account(
+        &self,
+        address: Address,
+        account: CachedAccount,
+    ) -> (CachedAccount, Option<Bytes>) {
+        let Some(change) = self.accounts.get(&address) else {
+            return (account, None);
+        };
+        let mut account = if change.cleared {
+            CachedAccount {
+                balance: U256::ZERO,
+                nonce: 0,
+                code_hash: KECCAK_EMPTY,
+            }
+        } else {
+            account
+        };
+        account.balance = change.balance.unwrap_or(account.balance);
+        account.nonce = change.nonce.unwrap_or(account.nonce);
+        let code = change.code.clone().filter(|code| !code.is_empty());
+        if let Some(code) = &change.code {
+            account.code_hash = if code.is_empty() {
+                KECCAK_EMPTY
+            } else {
+                keccak256(code)
+            };
+        }
+        (account, code)
+    }
+
+    /// Value of a storage slot the earlier transactions changed
+    pub fn storage(&self, address: Address, slot: U256) -> Option<U256> {
+        let change = self.accounts.get(&address)?;
+        match change.storage.get(&slot) {
+            Some(value) => Some(*value),
+            None if change.cleared => Some(U256::ZERO),
+            None => None,
+        }
+    }
+}
@@ -1,23 +1,119 @@
-use crate::gas_estimator::{GasEstimate, GasEstimator, Tx};
+use crate::{
+    abi::FunctionSpec,
+    coalescer::CoalescerStats,
+    deploy::{Artifact, Deployment, DeploymentEstimate},
+    divergence::DivergenceStats,
+    error::Error,
+    gas_estimator::{EstimatorConfig, GasEstimate, GasEstimator, Tx},
+    margin::MarginPolicy,
+    profile::SourceProfile,
+    provider_pool::{ProviderPool, Upstream},
+    replay::ReplayReport,
+    state_cache::StateCacheStats,
+};
+use alloy::primitives::{Address, Bytes, B256, U256};
 use jsonrpsee::{
     core::{async_trait, RpcResult},
     proc_macros::rpc,
     server::{ServerBuilder, ServerHandle},
-    types::ErrorObjectOwned,
+    types::{error::SERVER_IS_BUSY_CODE, ErrorObjectOwned},
 };
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use serde_json::Value;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 use tower_http::cors::{Any, CorsLayer};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EstimateGasRequest {
-    pub transaction: Tx,
+    /// The transaction to estimate, unless given as `raw_transaction`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction: Option<Tx>,
+    /// EIP-2718 encoded signed transaction of any type, estimated from the sender its signature recovers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_transaction: Option<Bytes>,
+    /// Function called by `transaction`, as a signature or a JSON ABI fragment.
+    /// The server encodes the calldata from it and `args`, in place of the transaction's `data`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function: Option<FunctionSpec>,
+    /// Arguments of `function`, in JSON
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<Value>,
     pub rpc_url: Option<String>,
+    /// Block to estimate against, overriding the server default of the latest one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<u64>,
+    /// Prove all simulated state against the block's state root, overriding the server default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_state: Option<bool>,
+    /// Padding reported in `padded_gas`, replacing the server's margin policy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub margin: Option<MarginPolicy>,
+    /// Also ask the upstream node for `eth_estimateGas` and report both
+    #[serde(default)]
+    pub compare: bool,
+    /// Report the gas the simulation spent per Solidity function and line of a contract
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<ProfileRequest>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileRequest {
+    /// Foundry artifact of the profiled contract, with its runtime source map
+    pub artifact: Artifact,
+    /// Source files by path, as in the artifact's metadata
+    pub sources: HashMap<String, String>,
+    /// Account whose code is the artifact's, the transaction's `to` when absent,
+    /// e.g. the implementation behind a proxy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<Address>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EstimateGasResponse {
     pub estimate: GasEstimate,
+    /// The transaction decoded from `raw_transaction`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction: Option<Tx>,
+    /// Calldata encoded from `function` and `args`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub calldata: Option<Bytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<SourceProfile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EstimateDeploymentRequest {
+    /// Foundry artifact JSON. Paths are only read by the CLI, never from the server's disk.
+    pub artifact: Artifact,
+    /// Constructor arguments, in JSON
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<Value>,
+    /// Library addresses, by `<file>:<name>` or name
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub libraries: HashMap<String, Address>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<Address>,
+    #[serde(default)]
+    pub value: U256,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<u64>,
+    /// Deploy through the CREATE2 deployer with this salt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<B256>,
+    pub rpc_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayTransactionRequest {
+    pub tx_hash: B256,
+    pub rpc_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metrics {
+    pub state_cache: StateCacheStats,
+    pub estimates: CoalescerStats,
+    pub divergence: DivergenceStats,
 }
 
 // Define the JSON-RPC interface
@@ -25,40 +121,233 @@ pub struct EstimateGasResponse {
 pub trait GasEstimationRpc {
     #[method(name = "estimate_gas")]
     async fn estimate_gas(&self, request: EstimateGasRequest) -> RpcResult<EstimateGasResponse>;
+
+    /// Estimate deploying a contract from its artifact, with its size and address
+    #[method(name = "estimate_deployment")]
+    async fn estimate_deployment(
+        &self,
+        request: EstimateDeploymentRequest,
+    ) -> RpcResult<DeploymentEstimate>;
+
+    /// Estimate a mined transaction on the state it ran on and compare with its receipt
+    #[method(name = "replay_transaction")]
+    async fn replay_transaction(
+        &self,
+        request: ReplayTransactionRequest,
+    ) -> RpcResult<ReplayReport>;
+
+    #[method(name = "cache_stats")]
+    async fn cache_stats(&self) -> RpcResult<StateCacheStats>;
+
+    #[method(name = "metrics")]
+    async fn metrics(&self) -> RpcResult<Metrics>;
 }
 
 pub struct GasEstimationRpcImpl {
-    default_rpc_url: String,
+    /// The operator's upstream, preferred over the default ones of chain profiles
+    default_rpc_url: Option<String>,
+    config: EstimatorConfig,
+    pool: Arc<ProviderPool>,
 }
 
 impl GasEstimationRpcImpl {
-    pub fn new(default_rpc_url: String) -> Self {
-        Self { default_rpc_url }
+    pub fn new(
+        default_rpc_url: Option<String>,
+        config: EstimatorConfig,
+        pool: Arc<ProviderPool>,
+    ) -> Self {
+        Self {
+            default_rpc_url,
+            config,
+            pool,
+        }
     }
+
+    /// Upstream URL of a request: its own `rpc_url`, then the server's, and only without one
+    /// the default of the profile of `chain_id`, mainnet's when the request names no chain
+    pub fn rpc_url(&self, requested: Option<&String>, chain_id: Option<u64>) -> RpcResult<String> {
+        requested
+            .or(self.default_rpc_url.as_ref())
+            .cloned()
+            .or_else(|| {
+                self.config
+                    .chains
+                    .get(chain_id.unwrap_or(1))
+                    .default_rpc_url
+            })
+            .ok_or_else(|| {
+                invalid_params("rpc_url is required, neither the server nor the chain has one")
+            })
+    }
+
+    /// Shared upstream provider for `rpc_url`
+    fn upstream(&self, rpc_url: &str) -> RpcResult<Upstream> {
+        match self.pool.get(rpc_url) {
+            Ok(upstream) => Ok(upstream),
+            Err(e @ Error::InvalidRpcUrl(_)) => Err(invalid_params(e.to_string())),
+            Err(e) => Err(estimation_error(e)),
+        }
+    }
+}
+
+fn invalid_params(message: impl Into<String>) -> ErrorObjectOwned {
+    ErrorObjectOwned::owned(-32602, message, None::<String>)
+}
+
+fn estimation_error(e: Error) -> ErrorObjectOwned {
+    if matches!(e.root(), Error::EvmQueueFull(_)) {
+        return ErrorObjectOwned::owned(SERVER_IS_BUSY_CODE, e.to_string(), None::<String>);
+    }
+    ErrorObjectOwned::owned(
+        -32603,
+        format!("Gas estimation failed: {}", e),
+        None::<String>,
+    )
 }
 
 #[async_trait]
 impl GasEstimationRpcServer for GasEstimationRpcImpl {
     async fn estimate_gas(&self, request: EstimateGasRequest) -> RpcResult<EstimateGasResponse> {
-        // Use provided RPC URL or fallback to default
-        let rpc_url = request.rpc_url.as_ref().unwrap_or(&self.default_rpc_url);
+        let (mut transaction, decoded) = match (request.transaction, &request.raw_transaction) {
+            (Some(transaction), None) => (transaction, false),
+            (None, Some(raw)) => match Tx::from_raw(raw) {
+                Ok(transaction) => (transaction, true),
+                Err(e) => return Err(invalid_params(e.to_string())),
+            },
+            _ => {
+                return Err(invalid_params(
+                    "exactly one of transaction and raw_transaction is required",
+                ))
+            }
+        };
+        let calldata = match &request.function {
+            Some(_) if decoded => {
+                return Err(invalid_params(
+                    "function cannot be combined with raw_transaction",
+                ))
+            }
+            Some(_)
+                if transaction
+                    .data
+                    .as_ref()
+                    .is_some_and(|data| !data.is_empty()) =>
+            {
+                return Err(invalid_params(
+                    "function cannot be combined with transaction data",
+                ))
+            }
+            Some(function) => match function.encode_call(&request.args) {
+                Ok(calldata) => Some(calldata),
+                Err(e) => return Err(invalid_params(e.to_string())),
+            },
+            None => None,
+        };
+        if calldata.is_some() {
+            transaction.data = calldata.clone();
+        }
 
-        // Create gas estimator instance
-        let estimator = GasEstimator::new(rpc_url);
+        // Create gas estimator instance on the shared upstream provider
+        let rpc_url = self.rpc_url(
+            request.rpc_url.as_ref(),
+            transaction.chain_id.map(|chain_id| chain_id.to()),
+        )?;
+        let upstream = self.upstream(&rpc_url)?;
+        let mut config = self.config.clone();
+        if let Some(block) = request.block {
+            config.block = Some(block);
+        }
+        if let Some(verify_state) = request.verify_state {
+            config.verify_state = verify_state;
+        }
+        config.compare = request.compare;
+        if let Some(profile) = &request.profile {
+            match profile.address.or(transaction.to) {
+                Some(address) => config.profile = Some(address),
+                None => {
+                    return Err(invalid_params(
+                        "profile needs an address for contract creations",
+                    ))
+                }
+            }
+        }
+        if let Some(margin) = request.margin {
+            if let Err(e) = margin.validate() {
+                return Err(invalid_params(e.to_string()));
+            }
+            config.margin = margin;
+        }
+        let estimator = GasEstimator::new(upstream, config);
 
         // Perform gas estimation
-        let estimate = match estimator.estimate_gas(request.transaction).await {
-            Ok(estimate) => estimate,
-            Err(e) => {
-                return Err(ErrorObjectOwned::owned(
-                    -32603,
-                    format!("Gas estimation failed: {}", e),
-                    None::<String>,
-                ))
+        let mut estimate = estimator
+            .estimate_gas(transaction.clone())
+            .await
+            .map_err(estimation_error)?;
+
+        // The per-instruction profile is only returned mapped to the sources
+        let profile = match (&request.profile, estimate.profile.take()) {
+            (Some(request), Some(profile)) => {
+                match SourceProfile::new(&profile, &request.artifact, &request.sources) {
+                    Ok(profile) => Some(profile),
+                    Err(e) => return Err(invalid_params(e.to_string())),
+                }
             }
+            _ => None,
+        };
+
+        Ok(EstimateGasResponse {
+            estimate,
+            transaction: decoded.then_some(transaction),
+            calldata,
+            profile,
+        })
+    }
+
+    async fn estimate_deployment(
+        &self,
+        request: EstimateDeploymentRequest,
+    ) -> RpcResult<DeploymentEstimate> {
+        let deployment = match Deployment::new(&request.artifact, &request.args, &request.libraries)
+        {
+            Ok(deployment) => Deployment {
+                from: request.from,
+                value: request.value,
+                nonce: request.nonce,
+                salt: request.salt,
+                ..deployment
+            },
+            Err(e) => return Err(invalid_params(e.to_string())),
         };
+        let rpc_url = self.rpc_url(request.rpc_url.as_ref(), None)?;
+        let estimator = GasEstimator::new(self.upstream(&rpc_url)?, self.config.clone());
+        estimator
+            .estimate_deployment(deployment)
+            .await
+            .map_err(estimation_error)
+    }
+
+    async fn replay_transaction(
+        &self,
+        request: ReplayTransactionRequest,
+    ) -> RpcResult<ReplayReport> {
+        let rpc_url = self.rpc_url(request.rpc_url.as_ref(), None)?;
+        let estimator = GasEstimator::new(self.upstream(&rpc_url)?, self.config.clone());
+        estimator
+            .replay_transaction(request.tx_hash)
+            .await
+            .map_err(estimation_error)
+    }
+
+    async fn cache_stats(&self) -> RpcResult<StateCacheStats> {
+        Ok(self.config.state_cache.stats())
+    }
 
-        Ok(EstimateGasResponse { estimate })
+    async fn metrics(&self) -> RpcResult<Metrics> {
+        Ok(Metrics {
+            state_cache: self.config.state_cache.stats(),
+            estimates: self.config.coalescer.stats(),
+            divergence: self.config.divergence.stats(),
+        })
     }
 }
 
@@ -70,7 +359,9 @@ pub struct RpcServer {
 impl RpcServer {
     pub async fn new(
         bind_addr: SocketAddr,
-        default_rpc_url: String,
+        default_rpc_url: Option<String>,
+        config: EstimatorConfig,
+        pool: Arc<ProviderPool>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         // Setup CORS
         let cors = CorsLayer::new()
@@ -86,7 +377,7 @@ impl RpcServer {
 
         let addr = server.local_addr()?;
         // Create the RPC implementation
-        let rpc_impl = GasEstimationRpcImpl::new(default_rpc_url);
+        let rpc_impl = GasEstimationRpcImpl::new(default_rpc_url, config, pool);
         // Start the server
         let handle = server.start(rpc_impl.into_rpc());
         Ok(Self { handle, addr })
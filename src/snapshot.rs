@@ -0,0 +1,283 @@
+use crate::{
+    abi::FunctionSpec,
+    deploy::{Artifact, Deployment},
+    error::Error,
+    gas_estimator::{failure_reason, EstimatorConfig, GasEstimator, Tx},
+    local_node::{LocalNode, LOCAL_CHAIN_ID},
+};
+use alloy::primitives::address;
+use revm::{
+    context::result::{ExecutionResult, Output},
+    primitives::{Address, U256},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+/// Sender of every deployment and call, the one Foundry uses by default
+pub const SNAPSHOT_SENDER: Address = address!("0x1804c8AB1F12E6bbf3894d4083f33e07309d1f38");
+
+/// Contracts to deploy and the calls to make on them, whose gas the snapshot tracks
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SnapshotSpec {
+    #[serde(default)]
+    pub contracts: Vec<ContractSpec>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContractSpec {
+    /// Name in the snapshot, in `$<name>` arguments and in library links,
+    /// the artifact's file name when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Foundry artifact, relative to the spec file
+    pub artifact: PathBuf,
+    /// Constructor arguments, in JSON
+    #[serde(default)]
+    pub args: Vec<Value>,
+    #[serde(default)]
+    pub value: U256,
+    /// Calls made in order once deployed, each on the state the ones before left
+    #[serde(default)]
+    pub calls: Vec<CallSpec>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CallSpec {
+    /// Called function, e.g. `setNumber(uint256)`
+    pub function: String,
+    /// Name in the snapshot, the function's signature when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Function arguments, in JSON. `$<name>` is the address of a contract deployed before.
+    #[serde(default)]
+    pub args: Vec<Value>,
+    #[serde(default)]
+    pub value: U256,
+}
+
+impl SnapshotSpec {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let spec = std::fs::read_to_string(path).map_err(Error::Io)?;
+        toml::from_str(&spec)
+            .map_err(|e| Error::InvalidSnapshot(format!("{}: {e}", path.display())))
+    }
+}
+
+/// Estimated gas of each deployment and call by `<contract>:<name>`, written like Foundry's
+/// `.gas-snapshot`: one `<contract>:<name> (gas: <gas>)` line each, sorted
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GasSnapshot(pub BTreeMap<String, u128>);
+
+impl GasSnapshot {
+    pub fn parse(snapshot: &str) -> Result<Self, Error> {
+        let mut entries = BTreeMap::new();
+        for (index, line) in snapshot.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = line
+                .strip_suffix(')')
+                .and_then(|line| line.rsplit_once(" (gas: "))
+                .and_then(|(name, gas)| Some((name.to_string(), gas.parse().ok()?)));
+            let Some((name, gas)) = entry else {
+                return Err(Error::InvalidSnapshot(format!(
+                    "line {}: expected `<contract>:<name> (gas: <gas>)`, got {line}",
+                    index + 1
+                )));
+            };
+            entries.insert(name, gas);
+        }
+        Ok(Self(entries))
+    }
+
+    /// What changed since `previous`. Changes of at most `tolerance_percent` either way are left out.
+    pub fn compare(&self, previous: &GasSnapshot, tolerance_percent: f64) -> SnapshotDiff {
+        let mut diff = SnapshotDiff::default();
+        for (name, &current) in &self.0 {
+            let Some(&previous) = previous.0.get(name) else {
+                diff.added.push(name.clone());
+                continue;
+            };
+            let change = GasChange {
+                name: name.clone(),
+                previous,
+                current,
+            };
+            if change.percent().abs() <= tolerance_percent {
+                continue;
+            }
+            if current > previous {
+                diff.regressions.push(change);
+            } else {
+                diff.improvements.push(change);
+            }
+        }
+        diff.removed = previous
+            .0
+            .keys()
+            .filter(|name| !self.0.contains_key(*name))
+            .cloned()
+            .collect();
+        diff
+    }
+
+    fn insert(&mut self, name: String, gas: u128) -> Result<(), Error> {
+        if self.0.contains_key(&name) {
+            return Err(Error::InvalidSnapshot(format!(
+                "{name} is listed twice, give the calls distinct names"
+            )));
+        }
+        self.0.insert(name, gas);
+        Ok(())
+    }
+}
+
+impl fmt::Display for GasSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, gas) in &self.0 {
+            writeln!(f, "{name} (gas: {gas})")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GasChange {
+    pub name: String,
+    pub previous: u128,
+    pub current: u128,
+}
+
+impl GasChange {
+    /// Change relative to the previous gas, positive when it went up
+    pub fn percent(&self) -> f64 {
+        if self.previous == 0 {
+            return if self.current == 0 {
+                0.0
+            } else {
+                f64::INFINITY
+            };
+        }
+        (self.current as f64 - self.previous as f64) / self.previous as f64 * 100.0
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotDiff {
+    /// Entries whose gas went up by more than the tolerance
+    pub regressions: Vec<GasChange>,
+    /// Entries whose gas went down by more than the tolerance
+    pub improvements: Vec<GasChange>,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// Deploy the contracts of `spec` on a fresh local chain, in order, and make their calls,
+/// estimating each through the estimator before executing it. Artifacts are read relative to `root`.
+pub async fn take_snapshot(spec: &SnapshotSpec, root: &Path) -> Result<GasSnapshot, Error> {
+    let config = EstimatorConfig::default();
+    let node = LocalNode::start(config.chains.get(LOCAL_CHAIN_ID)).await?;
+    node.fund(SNAPSHOT_SENDER, U256::MAX >> 1);
+    let estimator = GasEstimator::new(node.upstream(), config);
+
+    let mut snapshot = GasSnapshot::default();
+    let mut deployed = HashMap::new();
+    let result = async {
+        for contract in &spec.contracts {
+            let artifact = Artifact::load(root.join(&contract.artifact))?;
+            let name = match &contract.name {
+                Some(name) => name.clone(),
+                None => contract
+                    .artifact
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .ok_or_else(|| {
+                        Error::InvalidSnapshot(format!(
+                            "{} names no contract",
+                            contract.artifact.display()
+                        ))
+                    })?,
+            };
+
+            // Libraries are linked to the contracts of the same name deployed before
+            let args = resolve_args(&contract.args, &deployed)?;
+            let deployment = Deployment {
+                from: Some(SNAPSHOT_SENDER),
+                value: contract.value,
+                ..Deployment::new(&artifact, &args, &deployed)?
+            };
+            let inputs = artifact
+                .abi
+                .constructor
+                .iter()
+                .flat_map(|constructor| &constructor.inputs)
+                .map(|param| param.selector_type().into_owned())
+                .collect::<Vec<_>>();
+            let entry = format!("{name}:constructor({})", inputs.join(","));
+            let estimate = estimator.estimate_deployment(deployment.clone()).await?;
+            let address = match node.commit(&deployment.transaction())? {
+                ExecutionResult::Success {
+                    output: Output::Create(_, Some(address)),
+                    ..
+                } => address,
+                result => return Err(execution_failed(&entry, result)),
+            };
+            snapshot.insert(entry, estimate.estimate.estimated_gas)?;
+            deployed.insert(name.clone(), address);
+
+            for call in &contract.calls {
+                let function = FunctionSpec::Signature(call.function.clone());
+                let args = resolve_args(&call.args, &deployed)?;
+                let tx = Tx {
+                    from: Some(SNAPSHOT_SENDER),
+                    to: Some(address),
+                    value: call.value,
+                    data: Some(function.encode_call(&args)?),
+                    ..Default::default()
+                };
+                let call_name = match &call.name {
+                    Some(name) => name.clone(),
+                    None => function.function()?.signature(),
+                };
+                let entry = format!("{name}:{call_name}");
+                let estimate = estimator.estimate_gas(tx.clone()).await?;
+                let result = node.commit(&tx)?;
+                if !result.is_success() {
+                    return Err(execution_failed(&entry, result));
+                }
+                snapshot.insert(entry, estimate.estimated_gas)?;
+            }
+        }
+        Ok(())
+    }
+    .await;
+    node.stop().await?;
+    result.map(|_| snapshot)
+}
+
+fn execution_failed(entry: &str, result: ExecutionResult) -> Error {
+    let reason = failure_reason(&result).unwrap_or_else(|| "did not create a contract".to_string());
+    Error::LocalExecution(format!("{entry} {reason}"))
+}
+
+/// `args` with each `$<name>` string replaced by the address of the contract deployed as `name`
+fn resolve_args(args: &[Value], deployed: &HashMap<String, Address>) -> Result<Vec<Value>, Error> {
+    args.iter()
+        .map(|arg| match arg {
+            Value::String(text) if text.starts_with('$') => deployed
+                .get(&text[1..])
+                .map(|address| Value::String(address.to_string()))
+                .ok_or_else(|| {
+                    Error::InvalidSnapshot(format!("{text} names no contract deployed before"))
+                }),
+            Value::Array(items) => resolve_args(items, deployed).map(Value::Array),
+            arg => Ok(arg.clone()),
+        })
+        .collect()
+}
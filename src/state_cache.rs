@@ -0,0 +1,269 @@
+use lru::LruCache;
+use revm::{
+    primitives::{Address, B256, U256},
+    state::Bytecode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+#[derive(Debug, Clone)]
+pub struct StateCacheConfig {
+    pub max_accounts: usize,
+    pub max_storage_slots: usize,
+    pub max_bytecodes: usize,
+}
+
+impl Default for StateCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_accounts: 10_000,
+            max_storage_slots: 100_000,
+            max_bytecodes: 2_000,
+        }
+    }
+}
+
+/// Account fields as of a given block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachedAccount {
+    pub balance: U256,
+    pub nonce: u64,
+    pub code_hash: B256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct AccountKey {
+    chain_id: u64,
+    block_hash: B256,
+    address: Address,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct StorageKey {
+    chain_id: u64,
+    block_hash: B256,
+    address: Address,
+    slot: U256,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HitMiss {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateCacheStats {
+    pub accounts: HitMiss,
+    pub storage: HitMiss,
+    pub bytecodes: HitMiss,
+    pub cached_accounts: usize,
+    pub cached_storage_slots: usize,
+    pub cached_bytecodes: usize,
+    /// Times a chain moved to a new block and its account and storage entries were dropped
+    pub invalidations: u64,
+}
+
+/// LRU map with hit/miss counters
+struct Lru<K: Hash + Eq, V> {
+    entries: Mutex<LruCache<K, V>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN),
+            )),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        let value = self.entries.lock().unwrap().get(key).cloned();
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    fn insert(&self, key: K, value: V) {
+        self.entries.lock().unwrap().put(key, value);
+    }
+
+    fn retain(&self, keep: impl Fn(&K) -> bool) {
+        let mut entries = self.entries.lock().unwrap();
+        let stale: Vec<K> = entries
+            .iter()
+            .filter(|(key, _)| !keep(key))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            entries.pop(&key);
+        }
+    }
+
+    fn stats(&self) -> HitMiss {
+        HitMiss {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+/// Upstream state shared by all estimates.
+/// Accounts and storage are keyed by chain and block hash, bytecode by code hash.
+pub struct StateCache {
+    accounts: Lru<AccountKey, CachedAccount>,
+    storage: Lru<StorageKey, U256>,
+    bytecodes: Lru<B256, Bytecode>,
+    /// Latest block (number, hash) seen per chain
+    pinned: Mutex<HashMap<u64, (u64, B256)>>,
+    invalidations: AtomicU64,
+}
+
+impl StateCache {
+    pub fn new(config: StateCacheConfig) -> Self {
+        Self {
+            accounts: Lru::new(config.max_accounts),
+            storage: Lru::new(config.max_storage_slots),
+            bytecodes: Lru::new(config.max_bytecodes),
+            pinned: Mutex::new(HashMap::new()),
+            invalidations: AtomicU64::new(0),
+        }
+    }
+
+    /// Record the block estimates on `chain_id` run against. When the chain moves
+    /// past the pinned block, or the pinned block is reorged out, account and storage
+    /// entries of other blocks are dropped. Estimates against an older block leave the pin alone.
+    pub fn pin_block(&self, chain_id: u64, block_number: u64, block_hash: B256) {
+        let mut pinned = self.pinned.lock().unwrap();
+        let moved = match pinned.get(&chain_id) {
+            Some(&(number, hash)) if block_number < number || block_hash == hash => return,
+            Some(_) => true,
+            None => false,
+        };
+        pinned.insert(chain_id, (block_number, block_hash));
+        drop(pinned);
+
+        if moved {
+            self.accounts
+                .retain(|key| key.chain_id != chain_id || key.block_hash == block_hash);
+            self.storage
+                .retain(|key| key.chain_id != chain_id || key.block_hash == block_hash);
+            self.invalidations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn account(
+        &self,
+        chain_id: u64,
+        block_hash: B256,
+        address: Address,
+    ) -> Option<CachedAccount> {
+        self.accounts.get(&AccountKey {
+            chain_id,
+            block_hash,
+            address,
+        })
+    }
+
+    pub fn insert_account(
+        &self,
+        chain_id: u64,
+        block_hash: B256,
+        address: Address,
+        account: CachedAccount,
+    ) {
+        let key = AccountKey {
+            chain_id,
+            block_hash,
+            address,
+        };
+        self.accounts.insert(key, account);
+    }
+
+    pub fn storage(
+        &self,
+        chain_id: u64,
+        block_hash: B256,
+        address: Address,
+        slot: U256,
+    ) -> Option<U256> {
+        self.storage.get(&StorageKey {
+            chain_id,
+            block_hash,
+            address,
+            slot,
+        })
+    }
+
+    pub fn insert_storage(
+        &self,
+        chain_id: u64,
+        block_hash: B256,
+        address: Address,
+        slot: U256,
+        value: U256,
+    ) {
+        let key = StorageKey {
+            chain_id,
+            block_hash,
+            address,
+            slot,
+        };
+        self.storage.insert(key, value);
+    }
+
+    pub fn bytecode(&self, code_hash: B256) -> Option<Bytecode> {
+        self.bytecodes.get(&code_hash)
+    }
+
+    pub fn insert_bytecode(&self, code_hash: B256, bytecode: Bytecode) {
+        self.bytecodes.insert(code_hash, bytecode);
+    }
+
+    pub fn stats(&self) -> StateCacheStats {
+        StateCacheStats {
+            accounts: self.accounts.stats(),
+            storage: self.storage.stats(),
+            bytecodes: self.bytecodes.stats(),
+            cached_accounts: self.accounts.len(),
+            cached_storage_slots: self.storage.len(),
+            cached_bytecodes: self.bytecodes.len(),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for StateCache {
+    fn default() -> Self {
+        Self::new(StateCacheConfig::default())
+    }
+}
+
+impl std::fmt::Debug for StateCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StateCache")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
@@ -0,0 +1,63 @@
+use crate::error::Error;
+use alloy::{
+    consensus::TrieAccount,
+    primitives::{keccak256, B256, U256},
+    rlp,
+    rpc::types::EIP1186AccountProofResponse,
+};
+use alloy_trie::{proof::verify_proof, Nibbles, EMPTY_ROOT_HASH};
+use revm::primitives::KECCAK_EMPTY;
+
+/// Check an `eth_getProof` response against the state root of the block it was read at:
+/// the account against `state_root`, and every storage slot against the account's storage root
+pub fn verify_account_proof(
+    proof: &EIP1186AccountProofResponse,
+    state_root: B256,
+    block_hash: B256,
+) -> Result<(), Error> {
+    let mismatch = |reason: String| Error::StateProofMismatch {
+        address: proof.address,
+        block_hash,
+        reason,
+    };
+
+    // Accounts that do not exist are proven absent, and nodes report them with zero hashes
+    let exists = proof.nonce != 0
+        || !proof.balance.is_zero()
+        || !(proof.code_hash.is_zero() || proof.code_hash == KECCAK_EMPTY)
+        || !(proof.storage_hash.is_zero() || proof.storage_hash == EMPTY_ROOT_HASH);
+    let account = exists.then(|| {
+        rlp::encode(TrieAccount {
+            nonce: proof.nonce,
+            balance: proof.balance,
+            storage_root: proof.storage_hash,
+            code_hash: proof.code_hash,
+        })
+    });
+    verify_proof(
+        state_root,
+        Nibbles::unpack(keccak256(proof.address)),
+        account,
+        &proof.account_proof,
+    )
+    .map_err(|e| mismatch(format!("account proof: {e}")))?;
+
+    let storage_root = if exists {
+        proof.storage_hash
+    } else {
+        EMPTY_ROOT_HASH
+    };
+    for storage_proof in &proof.storage_proof {
+        let slot = storage_proof.key.as_b256();
+        // Zero slots are not in the trie, so they are proven absent
+        let value = (storage_proof.value != U256::ZERO).then(|| rlp::encode(storage_proof.value));
+        verify_proof(
+            storage_root,
+            Nibbles::unpack(keccak256(slot)),
+            value,
+            &storage_proof.proof,
+        )
+        .map_err(|e| mismatch(format!("storage proof of slot {slot}: {e}")))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,623 @@
+use crate::{error::Error, state_cache::CachedAccount};
+use lru::LruCache;
+use revm::{
+    primitives::{Address, Bytes, B256, U256},
+    state::Bytecode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+/// Headers stored for each height of each chain, by block hash
+type HeaderIndex = HashMap<(u64, u64), HashMap<B256, StoredHeader>>;
+
+const CODE_DIR: &str = "code";
+/// Blocks kept in memory after being read from disk
+const LOADED_BLOCKS: NonZeroUsize = NonZeroUsize::new(16).unwrap();
+/// Block files the writer keeps open
+const OPEN_FILES: NonZeroUsize = NonZeroUsize::new(16).unwrap();
+
+/// Block a set of state entries belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockKey {
+    pub chain_id: u64,
+    pub block_number: u64,
+    pub block_hash: B256,
+}
+
+/// One line of a block file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Record {
+    Account {
+        address: Address,
+        balance: U256,
+        nonce: u64,
+        code_hash: B256,
+    },
+    Storage {
+        address: Address,
+        slot: U256,
+        value: U256,
+    },
+    Header(StoredHeader),
+}
+
+/// Fields of a block header an estimate pinned to the block needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredHeader {
+    pub timestamp: u64,
+    pub gas_limit: u64,
+    pub state_root: B256,
+    pub base_fee_per_gas: Option<u64>,
+    /// Absent from headers stored before they were kept
+    #[serde(default)]
+    pub beneficiary: Address,
+    #[serde(default)]
+    pub prevrandao: B256,
+    #[serde(default)]
+    pub excess_blob_gas: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct BlockState {
+    header: Option<StoredHeader>,
+    accounts: HashMap<Address, CachedAccount>,
+    storage: HashMap<(Address, U256), U256>,
+}
+
+impl BlockState {
+    fn read(path: &Path) -> Result<Self, Error> {
+        let mut state = Self::default();
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(state),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        for line in BufReader::new(file).lines() {
+            // A partially written last line is skipped, its entry is fetched again
+            if let Ok(record) = serde_json::from_str(&line.map_err(Error::Io)?) {
+                state.apply(record);
+            }
+        }
+        Ok(state)
+    }
+
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Account {
+                address,
+                balance,
+                nonce,
+                code_hash,
+            } => {
+                self.accounts.insert(
+                    address,
+                    CachedAccount {
+                        balance,
+                        nonce,
+                        code_hash,
+                    },
+                );
+            }
+            Record::Storage {
+                address,
+                slot,
+                value,
+            } => {
+                self.storage.insert((address, slot), value);
+            }
+            Record::Header(header) => self.header = Some(header),
+        }
+    }
+}
+
+enum WriterMessage {
+    Append {
+        path: PathBuf,
+        line: String,
+    },
+    /// Write out everything sent before and close the open files, then reply
+    Sync(mpsc::Sender<()>),
+}
+
+/// Appends lines to block files off the caller's thread, keeping recently used files open
+fn run_writer(messages: mpsc::Receiver<WriterMessage>) {
+    let mut files: LruCache<PathBuf, File> = LruCache::new(OPEN_FILES);
+    for message in messages {
+        match message {
+            WriterMessage::Append { path, line } => {
+                if !files.contains(&path) {
+                    let file = fs::create_dir_all(path.parent().unwrap())
+                        .and_then(|_| OpenOptions::new().create(true).append(true).open(&path));
+                    match file {
+                        Ok(file) => {
+                            files.put(path.clone(), file);
+                        }
+                        Err(e) => {
+                            eprintln!("State store: failed to open {}: {e}", path.display());
+                            continue;
+                        }
+                    }
+                }
+                // A failed write leaves at most a partial line, which readers skip
+                if let Err(e) = files.get_mut(&path).unwrap().write_all(line.as_bytes()) {
+                    eprintln!("State store: failed to write {}: {e}", path.display());
+                }
+            }
+            WriterMessage::Sync(done) => {
+                files.clear();
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// Block file found in the store
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredBlock {
+    pub chain_id: u64,
+    pub block_number: u64,
+    pub block_hash: B256,
+    pub accounts: usize,
+    pub storage_slots: usize,
+    pub size_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PruneReport {
+    pub blocks_removed: usize,
+    pub bytecodes_removed: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountExport {
+    pub balance: U256,
+    pub nonce: u64,
+    pub code_hash: B256,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<Bytes>,
+    pub storage: BTreeMap<U256, U256>,
+}
+
+/// Self-contained state of one block, with code inlined
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockExport {
+    pub chain_id: u64,
+    pub block_number: u64,
+    pub block_hash: B256,
+    pub accounts: BTreeMap<Address, AccountExport>,
+}
+
+/// Upstream state persisted on disk, so estimates against blocks seen before need no upstream calls.
+///
+/// Layout: `<dir>/<chain_id>/<block_number>-<block_hash>.jsonl` holds the header, account and
+/// storage entries fetched at that block, one JSON record per line, and `<dir>/code/<code_hash>.bin`
+/// the raw bytecode. Records are appended by a writer thread, so callers never wait on the disk.
+pub struct StateStore {
+    dir: PathBuf,
+    loaded: Mutex<LruCache<BlockKey, BlockState>>,
+    /// Read from disk on first use, then kept up to date by every insert
+    headers: Mutex<Option<HeaderIndex>>,
+    writer: Option<mpsc::Sender<WriterMessage>>,
+    writer_thread: Option<JoinHandle<()>>,
+}
+
+impl StateStore {
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join(CODE_DIR)).map_err(Error::Io)?;
+        let (writer, messages) = mpsc::channel();
+        let writer_thread = thread::Builder::new()
+            .name("state-store-writer".to_string())
+            .spawn(move || run_writer(messages))
+            .map_err(Error::Io)?;
+        Ok(Self {
+            dir,
+            loaded: Mutex::new(LruCache::new(LOADED_BLOCKS)),
+            headers: Mutex::new(None),
+            writer: Some(writer),
+            writer_thread: Some(writer_thread),
+        })
+    }
+
+    /// Header stored for `block_number` on `chain_id`, and the block it belongs to.
+    /// `None` as well when several blocks are stored at that height, e.g. after a reorg,
+    /// which only the upstream can pick from.
+    pub async fn header(
+        self: &Arc<Self>,
+        chain_id: u64,
+        block_number: u64,
+    ) -> Result<Option<(BlockKey, StoredHeader)>, Error> {
+        self.load_headers().await?;
+        let headers = self.headers.lock().unwrap();
+        let Some(blocks) = headers.as_ref().unwrap().get(&(chain_id, block_number)) else {
+            return Ok(None);
+        };
+        if blocks.len() != 1 {
+            return Ok(None);
+        }
+        let (block_hash, header) = blocks.iter().next().unwrap();
+        let block = BlockKey {
+            chain_id,
+            block_number,
+            block_hash: *block_hash,
+        };
+        Ok(Some((block, *header)))
+    }
+
+    pub async fn insert_header(
+        self: &Arc<Self>,
+        block: BlockKey,
+        header: StoredHeader,
+    ) -> Result<(), Error> {
+        self.load_headers().await?;
+        let mut headers = self.headers.lock().unwrap();
+        let blocks = headers
+            .as_mut()
+            .unwrap()
+            .entry((block.chain_id, block.block_number))
+            .or_default();
+        // Every estimate pinned to the block inserts it again
+        if blocks.insert(block.block_hash, header) == Some(header) {
+            return Ok(());
+        }
+        self.append(block, Record::Header(header))
+    }
+
+    /// Read the headers of every stored block into the index, once, off the async runtime
+    async fn load_headers(self: &Arc<Self>) -> Result<(), Error> {
+        if self.headers.lock().unwrap().is_some() {
+            return Ok(());
+        }
+        let store = self.clone();
+        let index = tokio::task::spawn_blocking(move || store.read_headers())
+            .await
+            .map_err(|e| Error::StateStore(format!("header index: {e}")))??;
+        // A concurrent load may have won, its index is already being kept up to date
+        self.headers.lock().unwrap().get_or_insert(index);
+        Ok(())
+    }
+
+    fn read_headers(&self) -> Result<HeaderIndex, Error> {
+        self.sync()?;
+        let mut index = HeaderIndex::new();
+        for (block, path) in self.block_files()? {
+            if let Some(header) = BlockState::read(&path)?.header {
+                index
+                    .entry((block.chain_id, block.block_number))
+                    .or_default()
+                    .insert(block.block_hash, header);
+            }
+        }
+        Ok(index)
+    }
+
+    pub fn account(
+        &self,
+        block: BlockKey,
+        address: Address,
+    ) -> Result<Option<CachedAccount>, Error> {
+        self.with_block(block, |state| Ok(state.accounts.get(&address).copied()))
+    }
+
+    pub fn insert_account(
+        &self,
+        block: BlockKey,
+        address: Address,
+        account: CachedAccount,
+    ) -> Result<(), Error> {
+        self.append(
+            block,
+            Record::Account {
+                address,
+                balance: account.balance,
+                nonce: account.nonce,
+                code_hash: account.code_hash,
+            },
+        )
+    }
+
+    pub fn storage(
+        &self,
+        block: BlockKey,
+        address: Address,
+        slot: U256,
+    ) -> Result<Option<U256>, Error> {
+        self.with_block(block, |state| {
+            Ok(state.storage.get(&(address, slot)).copied())
+        })
+    }
+
+    pub fn insert_storage(
+        &self,
+        block: BlockKey,
+        address: Address,
+        slot: U256,
+        value: U256,
+    ) -> Result<(), Error> {
+        self.append(
+            block,
+            Record::Storage {
+                address,
+                slot,
+                value,
+            },
+        )
+    }
+
+    pub fn bytecode(&self, code_hash: B256) -> Result<Option<Bytecode>, Error> {
+        match fs::read(self.code_path(code_hash)) {
+            Ok(code) => Ok(Some(Bytecode::new_raw(code.into()))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
+    pub fn insert_bytecode(&self, code_hash: B256, code: &Bytes) -> Result<(), Error> {
+        let path = self.code_path(code_hash);
+        if path.exists() {
+            return Ok(());
+        }
+        // Written aside and renamed, so readers never see a partial file
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, code).map_err(Error::Io)?;
+        fs::rename(tmp_path, path).map_err(Error::Io)
+    }
+
+    /// Every block in the store, ordered by chain and block number
+    pub fn blocks(&self) -> Result<Vec<StoredBlock>, Error> {
+        self.sync()?;
+        let mut blocks = Vec::new();
+        for (block, path) in self.block_files()? {
+            let state = BlockState::read(&path)?;
+            blocks.push(StoredBlock {
+                chain_id: block.chain_id,
+                block_number: block.block_number,
+                block_hash: block.block_hash,
+                accounts: state.accounts.len(),
+                storage_slots: state.storage.len(),
+                size_bytes: fs::metadata(&path).map_err(Error::Io)?.len(),
+            });
+        }
+        Ok(blocks)
+    }
+
+    /// Remove blocks of `chain_id` (every chain when `None`) older than `before`,
+    /// and all but the newest `keep_last` ones, then bytecode no remaining block refers to
+    pub fn prune(
+        &self,
+        chain_id: Option<u64>,
+        before: Option<u64>,
+        keep_last: Option<usize>,
+    ) -> Result<PruneReport, Error> {
+        // Open files of removed blocks would keep taking records nobody reads
+        self.sync()?;
+        let mut report = PruneReport::default();
+        let mut by_chain: BTreeMap<u64, Vec<(BlockKey, PathBuf)>> = BTreeMap::new();
+        for (block, path) in self.block_files()? {
+            by_chain
+                .entry(block.chain_id)
+                .or_default()
+                .push((block, path));
+        }
+
+        let mut code_in_use = HashSet::new();
+        for (chain, blocks) in by_chain {
+            let selected = chain_id.is_none_or(|chain_id| chain_id == chain);
+            let kept_from = keep_last.map_or(0, |keep_last| blocks.len().saturating_sub(keep_last));
+            for (index, (block, path)) in blocks.into_iter().enumerate() {
+                let too_old = before.is_some_and(|before| block.block_number < before);
+                if selected && (too_old || index < kept_from) {
+                    fs::remove_file(&path).map_err(Error::Io)?;
+                    self.loaded.lock().unwrap().pop(&block);
+                    if let Some(headers) = self.headers.lock().unwrap().as_mut() {
+                        if let Some(blocks) = headers.get_mut(&(block.chain_id, block.block_number))
+                        {
+                            blocks.remove(&block.block_hash);
+                        }
+                    }
+                    report.blocks_removed += 1;
+                } else {
+                    let state = BlockState::read(&path)?;
+                    code_in_use.extend(state.accounts.values().map(|account| account.code_hash));
+                }
+            }
+        }
+
+        for entry in fs::read_dir(self.dir.join(CODE_DIR)).map_err(Error::Io)? {
+            let path = entry.map_err(Error::Io)?.path();
+            let code_hash = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<B256>().ok());
+            if !code_hash.is_some_and(|code_hash| code_in_use.contains(&code_hash)) {
+                fs::remove_file(&path).map_err(Error::Io)?;
+                report.bytecodes_removed += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    /// State stored for `block_number` on `chain_id`. `block_hash` picks one block
+    /// when the store holds several at that height, e.g. after a reorg.
+    pub fn export(
+        &self,
+        chain_id: u64,
+        block_number: u64,
+        block_hash: Option<B256>,
+    ) -> Result<BlockExport, Error> {
+        self.sync()?;
+        let mut matching: Vec<_> = self
+            .block_files()?
+            .into_iter()
+            .filter(|(block, _)| {
+                block.chain_id == chain_id
+                    && block.block_number == block_number
+                    && block_hash.is_none_or(|hash| hash == block.block_hash)
+            })
+            .collect();
+        let (block, path) = match matching.len() {
+            1 => matching.remove(0),
+            0 => {
+                return Err(Error::StateStore(format!(
+                    "no state stored for block {block_number} of chain {chain_id}"
+                )))
+            }
+            _ => {
+                return Err(Error::StateStore(format!(
+                    "several blocks stored at height {block_number} of chain {chain_id}, pick one by hash"
+                )))
+            }
+        };
+
+        let state = BlockState::read(&path)?;
+        let mut accounts = BTreeMap::new();
+        for (address, account) in &state.accounts {
+            accounts.insert(
+                *address,
+                AccountExport {
+                    balance: account.balance,
+                    nonce: account.nonce,
+                    code_hash: account.code_hash,
+                    code: self
+                        .bytecode(account.code_hash)?
+                        .map(|bytecode| bytecode.original_bytes()),
+                    storage: BTreeMap::new(),
+                },
+            );
+        }
+        for ((address, slot), value) in &state.storage {
+            // Slots read before their account was fetched still belong in the export
+            let account = accounts.entry(*address).or_insert_with(|| AccountExport {
+                balance: U256::ZERO,
+                nonce: 0,
+                code_hash: B256::ZERO,
+                code: None,
+                storage: BTreeMap::new(),
+            });
+            account.storage.insert(*slot, *value);
+        }
+
+        Ok(BlockExport {
+            chain_id,
+            block_number,
+            block_hash: block.block_hash,
+            accounts,
+        })
+    }
+
+    fn with_block<T>(
+        &self,
+        block: BlockKey,
+        f: impl FnOnce(&mut BlockState) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut loaded = self.loaded.lock().unwrap();
+        if !loaded.contains(&block) {
+            // Records of the block may still be on their way to the file
+            self.sync()?;
+            loaded.put(block, BlockState::read(&self.block_path(block))?);
+        }
+        f(loaded.get_mut(&block).unwrap())
+    }
+
+    fn append(&self, block: BlockKey, record: Record) -> Result<(), Error> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let mut line = serde_json::to_string(&record).unwrap();
+        line.push('\n');
+        // Sent while the blocks are locked, so a load never misses it. A block not loaded
+        // yet is read with it later, the writer is synced before reading.
+        self.send(WriterMessage::Append {
+            path: self.block_path(block),
+            line,
+        })?;
+        if let Some(state) = loaded.get_mut(&block) {
+            state.apply(record);
+        }
+        Ok(())
+    }
+
+    /// Wait until the writer wrote out every record sent so far
+    fn sync(&self) -> Result<(), Error> {
+        let (done, written) = mpsc::channel();
+        self.send(WriterMessage::Sync(done))?;
+        written
+            .recv()
+            .map_err(|_| Error::StateStore("writer stopped".to_string()))
+    }
+
+    fn send(&self, message: WriterMessage) -> Result<(), Error> {
+        self.writer
+            .as_ref()
+            .and_then(|writer| writer.send(message).ok())
+            .ok_or_else(|| Error::StateStore("writer stopped".to_string()))
+    }
+
+    fn block_path(&self, block: BlockKey) -> PathBuf {
+        self.dir
+            .join(block.chain_id.to_string())
+            .join(format!("{}-{}.jsonl", block.block_number, block.block_hash))
+    }
+
+    fn code_path(&self, code_hash: B256) -> PathBuf {
+        self.dir.join(CODE_DIR).join(format!("{code_hash}.bin"))
+    }
+
+    /// Block files ordered by chain and block number
+    fn block_files(&self) -> Result<Vec<(BlockKey, PathBuf)>, Error> {
+        let mut files = Vec::new();
+        for chain_dir in fs::read_dir(&self.dir).map_err(Error::Io)? {
+            let chain_dir = chain_dir.map_err(Error::Io)?.path();
+            let Some(chain_id) = chain_dir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            for file in fs::read_dir(&chain_dir).map_err(Error::Io)? {
+                let path = file.map_err(Error::Io)?.path();
+                let parsed = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.split_once('-'))
+                    .and_then(|(number, hash)| Some((number.parse().ok()?, hash.parse().ok()?)));
+                if let Some((block_number, block_hash)) = parsed {
+                    let block = BlockKey {
+                        chain_id,
+                        block_number,
+                        block_hash,
+                    };
+                    files.push((block, path));
+                }
+            }
+        }
+        files.sort_by_key(|(block, _)| (block.chain_id, block.block_number));
+        Ok(files)
+    }
+}
+
+impl Drop for StateStore {
+    fn drop(&mut self) {
+        // The writer finishes what was sent once the channel closes
+        self.writer.take();
+        if let Some(writer_thread) = self.writer_thread.take() {
+            let _ = writer_thread.join();
+        }
+    }
+}
+
+impl std::fmt::Debug for StateStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StateStore")
+            .field("dir", &self.dir)
+            .finish()
+    }
+}
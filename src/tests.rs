@@ -1,21 +1,68 @@
-use crate::{gas_estimator::Tx, rpc_server::RpcServer};
+use crate::{
+    abi::FunctionSpec,
+    benchmark::{sample_blocks, AccuracyReport},
+    chain_profile::{BlockGasLimitSource, ChainRegistry, GasModel},
+    coalescer::{CoalescerConfig, EstimateCoalescer, EstimateKey},
+    deploy::{Artifact, Deployment},
+    divergence::{Comparison, DivergenceMonitor},
+    error::Error,
+    evm_pool::{EvmPool, EvmPoolConfig},
+    gas_estimator::{
+        ChainContext, EstimatorConfig, ExecutionSummary, GasBreakdown, GasEstimate, GasEstimator,
+        SimulationLimits, Tx, DEFAULT_SENDER,
+    },
+    margin::{MarginCondition, MarginPolicy, MarginRule},
+    op_stack::{encode_unsigned, flz_compress_len, L1FeeParams, GAS_PRICE_ORACLE},
+    profile::SourceProfile,
+    provider_pool::{ProviderPool, ProviderPoolConfig, Upstream},
+    replay::{ReplayOutcome, ReplayReport},
+    rpc_server::{EstimateGasRequest, GasEstimationRpcImpl, GasEstimationRpcServer},
+    snapshot::{take_snapshot, GasChange, GasSnapshot, SnapshotSpec},
+    state_cache::{CachedAccount, StateCache, StateCacheConfig},
+    state_store::{BlockKey, StateStore},
+    tracer::Tracer,
+    utils::precompile_gas,
+};
 use alloy::{
-    eips::BlockId,
-    primitives::{address, U256, U64},
-    providers::{Provider, ProviderBuilder},
-    rpc::types::Block,
-    signers::local::{coins_bip39::English, MnemonicBuilder},
+    consensus::{
+        SignableTransaction, Signed, TrieAccount, TxEip1559, TxEip2930, TxEip4844, TxEip7702,
+        TxEnvelope, TxLegacy,
+    },
+    eips::{
+        eip2718::Encodable2718,
+        eip2930::{AccessList, AccessListItem},
+        eip7702::Authorization,
+    },
+    network::TxSignerSync,
+    primitives::{address, FixedBytes, U256, U64},
+    providers::RootProvider,
+    rpc::client::RpcClient,
+    signers::{local::PrivateKeySigner, SignerSync},
     sol,
     sol_types::SolCall,
+    transports::mock::Asserter,
+};
+use alloy_trie::{proof::ProofRetainer, HashBuilder, Nibbles, EMPTY_ROOT_HASH};
+use revm::{
+    context::tx::TxEnvBuilder,
+    database::{CacheDB, EmptyDB},
+    inspector::InspectEvm,
+    precompile::{u64_to_address, PrecompileSpecId, Precompiles},
+    primitives::{hardfork::SpecId, keccak256, Address, Bytes, TxKind, B256, KECCAK_EMPTY},
+    state::{AccountInfo, Bytecode},
+    Context, ExecuteEvm, MainBuilder, MainContext,
+};
+use serde_json::json;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
 };
-use reqwest::Client;
-use revm::primitives::Bytes;
-use serde_json::{json, Value};
-use std::{str::FromStr, time::Duration};
 use tokio::time::sleep;
 
 const ETH_RPC_URL: &str = "http://localhost:8545";
-const MNEMONIC: &str = "test test test test test test test test test test test junk";
 
 sol!(
     #[allow(missing_docs)]
@@ -24,13 +71,6 @@ sol!(
     "./contracts/out/Counter.sol/Counter.json"
 );
 
-sol!(
-    #[allow(missing_docs)]
-    #[sol(rpc)]
-    Caller,
-    "./contracts/out/Caller.sol/Caller.json"
-);
-
 sol!(
     #[allow(missing_docs)]
     #[sol(rpc)]
@@ -38,345 +78,2665 @@ sol!(
     "./contracts/out/ERC20.sol/ERC20.json"
 );
 
-async fn setup_test_server() -> (RpcServer, String) {
-    let bind_addr = "127.0.0.1:0".parse().unwrap();
+/// Cache an account of the mainnet block `push_chain_head` pins, with `code` if any
+fn cache_account(
+    config: &EstimatorConfig,
+    block_number: u64,
+    address: Address,
+    balance: U256,
+    nonce: u64,
+    code: &[u8],
+) {
+    let code_hash = if code.is_empty() {
+        KECCAK_EMPTY
+    } else {
+        let bytecode = Bytecode::new_raw(Bytes::copy_from_slice(code));
+        let code_hash = bytecode.hash_slow();
+        config.state_cache.insert_bytecode(code_hash, bytecode);
+        code_hash
+    };
+    config.state_cache.insert_account(
+        1,
+        B256::with_last_byte(block_number as u8),
+        address,
+        CachedAccount {
+            balance,
+            nonce,
+            code_hash,
+        },
+    );
+}
+
+/// Queue the upstream's answers to a simulated estimate, by an upstream without `debug_traceCall`
+fn push_simulated_estimate(asserter: &Asserter, block_number: u64) {
+    push_chain_head(asserter, block_number);
+    asserter.push_success(&U256::from(1_000_000_000));
+    asserter.push_failure_msg("the method debug_traceCall does not exist");
+    asserter.push_success(&U256::from(1_000_000_000));
+}
+
+#[tokio::test]
+async fn test_sender_nonce_and_default_sender() {
+    let config = EstimatorConfig::default();
+    let sender = Address::repeat_byte(0x5e);
+    let contract = Address::repeat_byte(0xc);
+    cache_account(&config, 7, sender, U256::from(10u128.pow(18)), 5, &[]);
+    // STOP
+    cache_account(&config, 7, contract, U256::ZERO, 0, &[0x00]);
+    cache_account(&config, 7, DEFAULT_SENDER, U256::ZERO, 0, &[]);
+
+    // A nonce below the sender's is rejected before simulating
+    let call = Tx {
+        from: Some(sender),
+        to: Some(contract),
+        nonce: Some(3),
+        ..Default::default()
+    };
+    let asserter = Asserter::new();
+    push_chain_head(&asserter, 7);
+    asserter.push_success(&U256::from(1_000_000_000));
+    let error = mocked_estimator(&asserter, &config)
+        .estimate_gas(call)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error.root(),
+        Error::NonceTooLow {
+            address,
+            tx_nonce: 3,
+            account_nonce: 5,
+        } if *address == sender
+    ));
 
-    let server = RpcServer::new(bind_addr, ETH_RPC_URL.to_string())
+    // A higher nonce is simulated as given: the CREATE lands at the address it derives,
+    // the only one cached, so any other would be fetched from the drained upstream
+    cache_account(&config, 7, sender.create(9), U256::ZERO, 0, &[]);
+    let create = Tx {
+        from: Some(sender),
+        data: Some(Bytes::from(vec![0x00])),
+        nonce: Some(9),
+        ..Default::default()
+    };
+    let asserter = Asserter::new();
+    push_simulated_estimate(&asserter, 7);
+    let estimate = mocked_estimator(&asserter, &config)
+        .estimate_gas(create)
         .await
         .unwrap();
-    let server_url = format!("http://{}", server.local_addr());
+    assert!(asserter.read_q().is_empty());
+    // 21000 and 32000 for the CREATE, 4 for the zero byte of initcode and 2 for its word
+    assert_eq!(estimate.estimated_gas, 53_000 + 4 + 2);
+
+    // Without `from`, the default sender is funded for the value it sends, like nodes do
+    let unfunded = Tx {
+        to: Some(contract),
+        value: U256::from(10u128.pow(18)),
+        ..Default::default()
+    };
+    let asserter = Asserter::new();
+    push_simulated_estimate(&asserter, 7);
+    let estimate = mocked_estimator(&asserter, &config)
+        .estimate_gas(unfunded)
+        .await
+        .unwrap();
+    assert!(asserter.read_q().is_empty());
+    assert_eq!(estimate.estimated_gas, 21_000);
+}
 
-    // Give server a moment to fully start
-    sleep(Duration::from_millis(100)).await;
+#[tokio::test]
+async fn test_value_transfers_simulated_only_into_contracts() {
+    let config = EstimatorConfig::default();
+    let sender = Address::repeat_byte(0x5e);
+    let eoa = Address::repeat_byte(0xe);
+    let receiver = Address::repeat_byte(0xc);
+    cache_account(&config, 7, sender, U256::from(10u128.pow(18)), 0, &[]);
+    cache_account(&config, 7, eoa, U256::ZERO, 0, &[]);
+    // Like a `receive()` recording the value: SSTORE(0, CALLVALUE)
+    cache_account(
+        &config,
+        7,
+        receiver,
+        U256::ZERO,
+        0,
+        &[0x34, 0x60, 0x00, 0x55, 0x00],
+    );
+    config
+        .state_cache
+        .insert_storage(1, B256::with_last_byte(7), receiver, U256::ZERO, U256::ZERO);
+    let transfer = |to| Tx {
+        from: Some(sender),
+        to: Some(to),
+        value: U256::from(1),
+        ..Default::default()
+    };
+
+    // Into an account without code, the intrinsic gas alone. The drained upstream
+    // shows no state was fetched for a simulation.
+    let asserter = Asserter::new();
+    push_chain_head(&asserter, 7);
+    asserter.push_success(&U256::from(1_000_000_000));
+    let estimate = mocked_estimator(&asserter, &config)
+        .estimate_gas(transfer(eoa))
+        .await
+        .unwrap();
+    assert!(asserter.read_q().is_empty());
+    assert_eq!(estimate.estimated_gas, 21_000);
+    assert_eq!(estimate.simulation_rounds, 0);
+
+    // Calldata and access lists sent to an account without code are still charged,
+    // with the total lifted to the EIP-7623 floor under Prague
+    let with_data = |access_list| Tx {
+        data: Some(Bytes::from_static(&[1, 2, 0, 0])),
+        value: U256::ZERO,
+        access_list,
+        ..transfer(eoa)
+    };
+    let access_list = AccessList(vec![AccessListItem {
+        address: receiver,
+        storage_keys: vec![B256::ZERO],
+    }]);
+    for (tx, data_cost, expected) in [
+        (with_data(None), 2 * 16 + 2 * 4, 21_000 + 10 * (2 * 4 + 2)),
+        (
+            with_data(Some(access_list)),
+            2 * 16 + 2 * 4,
+            21_000 + 2 * 16 + 2 * 4 + 2400 + 1900,
+        ),
+    ] {
+        let asserter = Asserter::new();
+        push_chain_head(&asserter, 7);
+        asserter.push_success(&U256::from(1_000_000_000));
+        let estimate = mocked_estimator(&asserter, &config)
+            .estimate_gas(tx)
+            .await
+            .unwrap();
+        assert!(asserter.read_q().is_empty());
+        assert_eq!(estimate.breakdown.data_cost, data_cost);
+        assert_eq!(estimate.estimated_gas, expected);
+        assert_eq!(estimate.simulation_rounds, 0);
+    }
 
-    (server, server_url)
+    // Into a contract, its code runs. Without a prestate its slot is discovered by a first round.
+    let asserter = Asserter::new();
+    push_simulated_estimate(&asserter, 7);
+    let estimate = mocked_estimator(&asserter, &config)
+        .estimate_gas(transfer(receiver))
+        .await
+        .unwrap();
+    assert!(asserter.read_q().is_empty());
+    assert_eq!(estimate.simulation_rounds, 2);
+    assert_eq!(estimate.estimated_gas, 21_000 + 2 + 3 + 22_100);
 }
 
-async fn estimate_gas_via_rpc(server_url: &str, tx: Tx) -> Result<u64, Box<dyn std::error::Error>> {
-    let client = Client::new();
+#[tokio::test]
+async fn test_evm_rejection_fails_the_estimate() {
+    let config = EstimatorConfig::default();
+    let sender = Address::repeat_byte(0x5e);
+    let contract = Address::repeat_byte(0xc);
+    cache_account(&config, 7, sender, U256::ZERO, 0, &[]);
+    // STOP
+    cache_account(&config, 7, contract, U256::ZERO, 0, &[0x00]);
+
+    // A sender without the funds for the value and the fees
+    let asserter = Asserter::new();
+    push_simulated_estimate(&asserter, 7);
+    let error = mocked_estimator(&asserter, &config)
+        .estimate_gas(Tx {
+            from: Some(sender),
+            to: Some(contract),
+            value: U256::from(1),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(matches!(error.root(), Error::EvmExecution(_)));
+}
 
-    let request_body = json!({
-        "jsonrpc": "2.0",
-        "method": "estimate_gas",
-        "params": [{
-            "transaction": tx,
-            "rpc_url": null
-        }],
-        "id": 1
-    });
+#[test]
+fn test_chain_profile_hardfork_schedule() {
+    let registry = ChainRegistry::builtin();
+
+    let mainnet = registry.get(1);
+    assert_eq!(mainnet.spec_at(0, 1_438_269_973).unwrap(), SpecId::FRONTIER);
+    assert_eq!(
+        mainnet.spec_at(4_370_000, 1_508_131_331).unwrap(),
+        SpecId::BYZANTIUM
+    );
+    assert_eq!(
+        mainnet.spec_at(9_069_000, 1_575_764_709).unwrap(),
+        SpecId::ISTANBUL
+    );
+    assert_eq!(
+        mainnet.spec_at(12_965_000, 1_628_166_822).unwrap(),
+        SpecId::LONDON
+    );
+    assert_eq!(
+        mainnet.spec_at(17_034_870, 1_681_338_455).unwrap(),
+        SpecId::SHANGHAI
+    );
+    assert_eq!(
+        mainnet.spec_at(19_426_587, 1_710_338_135).unwrap(),
+        SpecId::CANCUN
+    );
+    assert_eq!(
+        mainnet.spec_at(22_431_084, 1_746_612_311).unwrap(),
+        SpecId::PRAGUE
+    );
+
+    // OP Mainnet's profile starts at Bedrock, legacy blocks are not covered
+    assert!(matches!(
+        registry.get(10).spec_at(100, 1_610_000_000),
+        Err(Error::BlockNotCovered {
+            chain_id: 10,
+            block_number: 100
+        })
+    ));
 
-    let response = client.post(server_url).json(&request_body).send().await?;
+    // Unknown chains follow Ethereum at the latest hardfork
+    let unknown = registry.get(123_456_789);
+    assert_eq!(unknown.spec_at(0, 0).unwrap(), SpecId::default());
+    assert_eq!(unknown.block_gas_limit(36_000_000), 36_000_000);
+}
 
-    let response_body: Value = response.json().await?;
+#[tokio::test]
+async fn test_arbitrum_l1_gas_from_node_interface() {
+    let config = EstimatorConfig::default();
+    assert_eq!(config.chains.get(42161).gas_model, GasModel::Arbitrum);
+    let to = Address::repeat_byte(0xe);
+    config.state_cache.insert_account(
+        42161,
+        B256::with_last_byte(7),
+        to,
+        CachedAccount {
+            balance: U256::ZERO,
+            nonce: 0,
+            code_hash: KECCAK_EMPTY,
+        },
+    );
+
+    let asserter = Asserter::new();
+    push_chain_head_of(&asserter, 42161, 7);
+    // gasEstimateL1Component returns (gasEstimateForL1, baseFee, l1BaseFeeEstimate)
+    let l1_component = [
+        U256::from(1_234).to_be_bytes::<32>(),
+        U256::from(10_000_000).to_be_bytes::<32>(),
+        U256::from(30_000_000_000u64).to_be_bytes::<32>(),
+    ]
+    .concat();
+    asserter.push_success(&Bytes::from(l1_component));
+    asserter.push_success(&U256::from(10_000_000));
+    let estimate = mocked_estimator(&asserter, &config)
+        .estimate_gas(Tx {
+            to: Some(to),
+            value: U256::from(1),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert!(asserter.read_q().is_empty());
+    assert_eq!(estimate.chain_id, 42161);
+    assert_eq!(estimate.breakdown.l1_gas, Some(1_234));
+    assert_eq!(estimate.estimated_gas, 21_000 + 1_234);
+}
 
-    if !response_body["error"].is_null() {
-        return Err(format!("RPC error: {}", response_body["error"]).into());
-    }
+#[test]
+fn test_chain_profile_file_overrides_builtin() {
+    let path = std::env::temp_dir().join("gas-estimator-chain-profiles.toml");
+    std::fs::write(
+        &path,
+        r#"
+[[chains]]
+chain_id = 31337
+name = "Anvil (pinned)"
+block_gas_limit = { fixed = 30000000 }
+native_currency = { symbol = "TEST", decimals = 6 }
+hardforks = [{ spec = "CANCUN", block = 0 }]
+intrinsic_gas = { tx_base_cost = 0 }
+"#,
+    )
+    .unwrap();
+
+    let registry = ChainRegistry::load(&path).unwrap();
+    let anvil = registry.get(31337);
+    assert_eq!(
+        anvil.block_gas_limit,
+        BlockGasLimitSource::Fixed(30_000_000)
+    );
+    assert_eq!(anvil.block_gas_limit(36_000_000), 30_000_000);
+    assert_eq!(anvil.spec_at(100, 100).unwrap(), SpecId::CANCUN);
+    assert_eq!(anvil.native_currency.symbol, "TEST");
+    assert_eq!(anvil.intrinsic_gas.tx_base_cost, Some(0));
+    assert_eq!(anvil.intrinsic_gas.base_cost(), 0);
+    // Constants left out follow the hardfork
+    assert_eq!(anvil.intrinsic_gas.calldata_non_zero_byte_cost, None);
+    assert_eq!(
+        anvil.intrinsic_gas.calldata_byte_costs(SpecId::CANCUN),
+        (4, 16)
+    );
+    assert_eq!(
+        anvil.intrinsic_gas.calldata_byte_costs(SpecId::BYZANTIUM),
+        (4, 68)
+    );
+    // Other built-in profiles are kept
+    assert_eq!(registry.get(1).name, "Ethereum");
+}
 
-    let estimated_gas = response_body["result"]["estimate"]["estimated_gas"]
-        .as_u64()
-        .ok_or("Failed to parse estimated_gas")?;
+#[tokio::test]
+async fn test_profile_intrinsic_gas_applies_to_simulated_gas() {
+    let path = std::env::temp_dir().join("gas-estimator-intrinsic-gas.toml");
+    std::fs::write(
+        &path,
+        r#"
+[[chains]]
+chain_id = 1
+name = "Ethereum (cheap)"
+block_gas_limit = "latest"
+hardforks = [{ spec = "CANCUN", block = 0 }]
+intrinsic_gas = { tx_base_cost = 9000, calldata_non_zero_byte_cost = 8 }
+"#,
+    )
+    .unwrap();
+    let config = EstimatorConfig {
+        chains: Arc::new(ChainRegistry::load(&path).unwrap()),
+        ..Default::default()
+    };
+    let sender = Address::repeat_byte(0x5e);
+    let contract = Address::repeat_byte(0xc);
+    cache_account(&config, 7, sender, U256::from(10u128.pow(18)), 0, &[]);
+    // STOP
+    cache_account(&config, 7, contract, U256::ZERO, 0, &[0x00]);
+
+    let asserter = Asserter::new();
+    push_simulated_estimate(&asserter, 7);
+    let estimate = mocked_estimator(&asserter, &config)
+        .estimate_gas(Tx {
+            from: Some(sender),
+            to: Some(contract),
+            data: Some(Bytes::from_static(&[0x01, 0x00])),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    // The EVM charged 21000 + 16 + 4, the chain charges 9000 + 8 + 4
+    assert_eq!(estimate.breakdown.base_cost, 9_000);
+    assert_eq!(estimate.breakdown.data_cost, 12);
+    assert_eq!(estimate.estimated_gas, 9_012);
+}
+
+#[test]
+fn test_precompile_sets_follow_spec_and_profile() {
+    let registry = ChainRegistry::builtin();
+    let mainnet = registry.get(1);
+
+    let cancun = mainnet.precompiles(SpecId::CANCUN);
+    assert!(cancun.contains(&u64_to_address(0x0a)));
+    assert!(!cancun.contains(&u64_to_address(0x0b)));
+
+    let prague = mainnet.precompiles(SpecId::PRAGUE);
+    assert!((0x01..=0x11).all(|address| prague.contains(&u64_to_address(address))));
+    assert!(!prague.contains(&u64_to_address(0x100)));
+    // Only the low byte used to be checked
+    let high_address = "0xffffffffffffffffffffffffffffffffffffff01"
+        .parse()
+        .unwrap();
+    assert!(!prague.contains(&high_address));
+    assert_eq!(precompile_gas(high_address, &[], SpecId::PRAGUE), None);
 
-    Ok(estimated_gas)
+    // RIP-7212 P256VERIFY on OP-stack chains
+    let base = registry.get(8453).precompiles(SpecId::PRAGUE);
+    assert!(base.contains(&u64_to_address(0x100)));
 }
 
-#[tokio::test]
-async fn test_all_gas_estimation_approaches() {
-    println!("\n=== Testing All Gas Estimation Approaches ===\n");
+#[test]
+fn test_precompile_gas_matches_revm() {
+    let precompiles = Precompiles::new(PrecompileSpecId::from_spec_id(SpecId::PRAGUE));
+    let mut modexp_input = vec![0u8; 96];
+    modexp_input[31] = 64; // base length
+    modexp_input[63] = 40; // exponent length
+    modexp_input[95] = 64; // modulus length
+    modexp_input.extend(std::iter::repeat_n(0xab, 64 + 40 + 64));
+    let inputs: Vec<(u64, Vec<u8>)> = vec![
+        (0x01, vec![0u8; 128]),
+        (0x02, vec![1u8; 100]),
+        (0x03, vec![1u8; 33]),
+        (0x04, vec![1u8; 65]),
+        (0x05, modexp_input),
+        (0x05, vec![0u8; 10]),
+    ];
+
+    for (address, input) in inputs {
+        let address = u64_to_address(address);
+        let run = precompiles.get(&address).unwrap();
+        let expected = run(&input, u64::MAX).unwrap().gas_used;
+        assert_eq!(
+            precompile_gas(address, &input, SpecId::PRAGUE),
+            Some(expected),
+            "precompile {}",
+            address
+        );
+    }
 
-    // Setup RPC server for EVM-based estimation
-    let (_server, server_url) = setup_test_server().await;
+    // Linear and flat pricing of the remaining precompiles
+    let pairing = u64_to_address(0x08);
+    assert_eq!(
+        precompile_gas(pairing, &[0u8; 384], SpecId::PRAGUE),
+        Some(113_000)
+    );
+    assert_eq!(
+        precompile_gas(pairing, &[0u8; 384], SpecId::BYZANTIUM),
+        Some(260_000)
+    );
+    // Malformed input fails the precompile, using up all its gas, so the call is simulated
+    assert_eq!(precompile_gas(pairing, &[0u8; 200], SpecId::PRAGUE), None);
+    let point_evaluation = u64_to_address(0x0a);
+    assert_eq!(
+        precompile_gas(point_evaluation, &[0u8; 192], SpecId::PRAGUE),
+        Some(50_000)
+    );
+    assert_eq!(
+        precompile_gas(point_evaluation, &[0u8; 100], SpecId::PRAGUE),
+        None
+    );
+    let mut blake2f_input = vec![0u8; 213];
+    blake2f_input[3] = 12;
+    assert_eq!(
+        precompile_gas(u64_to_address(0x09), &blake2f_input, SpecId::PRAGUE),
+        Some(12)
+    );
+    assert_eq!(
+        precompile_gas(u64_to_address(0x09), &[0u8; 10], SpecId::PRAGUE),
+        None
+    );
+    assert_eq!(
+        precompile_gas(u64_to_address(0x0f), &[0u8; 768], SpecId::PRAGUE),
+        Some(102_900)
+    );
+    assert_eq!(
+        precompile_gas(u64_to_address(0x100), &[0u8; 160], SpecId::PRAGUE),
+        Some(3_450)
+    );
+}
 
-    // Setup wallet and provider for contract operations
-    let wallet = MnemonicBuilder::<English>::default()
-        .phrase(MNEMONIC)
-        .index(0)
-        .unwrap()
-        .build()
+#[test]
+fn test_op_stack_l1_data_fee() {
+    // Literal runs cost one extra byte per 32, repetitive data compresses
+    assert_eq!(flz_compress_len(&[]), 0);
+    assert_eq!(flz_compress_len(b"hello world"), 12);
+    assert!(flz_compress_len(&[0u8; 1000]) < 50);
+
+    let mut params = L1FeeParams {
+        l1_base_fee: U256::from(10_000_000_000u64),
+        blob_base_fee: U256::from(1),
+        base_fee_scalar: 1368,
+        blob_base_fee_scalar: 810949,
+        is_fjord: false,
+    };
+    // Ecotone: (4 * zero bytes + 16 * (non-zero bytes + 68)) priced with the scaled fees
+    assert_eq!(
+        params.l1_data_fee(&[0, 1, 2]),
+        U256::from(15_376_320_056u64)
+    );
+    // Fjord: small transactions are priced at the minimum size of 100 bytes
+    params.is_fjord = true;
+    assert_eq!(
+        params.l1_data_fee(&[0, 1, 2]),
+        U256::from(21_888_000_081u64)
+    );
+
+    let tx = Tx {
+        to: Some(address!("0x1234567890123456789012345678901234567890")),
+        value: U256::from(1u64),
+        data: Some(Bytes::from(vec![0xab; 100])),
+        max_fee_per_gas: Some(1_000_000),
+        max_priority_fee_per_gas: Some(1_000),
+        transaction_type: Some(U64::from(2)),
+        ..Default::default()
+    };
+    let unsigned_tx = encode_unsigned(&tx, 10, 7, 50_000, 1_000_000);
+    assert_eq!(unsigned_tx[0], 2);
+    assert!(unsigned_tx.windows(100).any(|window| window == [0xab; 100]));
+
+    // Blob and delegation transactions keep their typed envelopes
+    let to = address!("0x1234567890123456789012345678901234567890");
+    let blob_hash = B256::repeat_byte(0x01);
+    let blob_tx = Tx {
+        to: Some(to),
+        data: Some(Bytes::from_static(&[0xab, 0xcd])),
+        max_fee_per_gas: Some(1_000_000),
+        max_priority_fee_per_gas: Some(1_000),
+        transaction_type: Some(U64::from(3)),
+        blob_versioned_hashes: Some(vec![blob_hash]),
+        max_fee_per_blob_gas: Some(9),
+        ..Default::default()
+    };
+    let expected = TxEip4844 {
+        chain_id: 10,
+        nonce: 7,
+        gas_limit: 50_000,
+        max_fee_per_gas: 1_000_000,
+        max_priority_fee_per_gas: 1_000,
+        to,
+        blob_versioned_hashes: vec![blob_hash],
+        max_fee_per_blob_gas: 9,
+        input: Bytes::from_static(&[0xab, 0xcd]),
+        ..Default::default()
+    };
+    let unsigned_tx = encode_unsigned(&blob_tx, 10, 7, 50_000, 1_000_000);
+    assert_eq!(unsigned_tx[0], 3);
+    assert_eq!(unsigned_tx, expected.encoded_for_signing());
+
+    let signer = PrivateKeySigner::from_bytes(&B256::repeat_byte(0x42)).unwrap();
+    let authorization = Authorization {
+        chain_id: U256::from(10),
+        address: Address::repeat_byte(0xd),
+        nonce: 8,
+    };
+    let signature = signer
+        .sign_hash_sync(&authorization.signature_hash())
         .unwrap();
+    let authorization = authorization.into_signed(signature);
+    let delegation_tx = Tx {
+        to: Some(to),
+        max_fee_per_gas: Some(1_000_000),
+        transaction_type: Some(U64::from(4)),
+        authorization_list: Some(vec![authorization.clone()]),
+        ..Default::default()
+    };
+    let expected = TxEip7702 {
+        chain_id: 10,
+        nonce: 7,
+        gas_limit: 50_000,
+        max_fee_per_gas: 1_000_000,
+        to,
+        authorization_list: vec![authorization],
+        ..Default::default()
+    };
+    let unsigned_tx = encode_unsigned(&delegation_tx, 10, 7, 50_000, 1_000_000);
+    assert_eq!(unsigned_tx[0], 4);
+    assert_eq!(unsigned_tx, expected.encoded_for_signing());
+}
 
-    let provider = ProviderBuilder::new()
-        .wallet(wallet.clone())
-        .connect(ETH_RPC_URL)
+#[tokio::test]
+async fn test_op_stack_l1_fee_params_read_on_pinned_state() {
+    let config = EstimatorConfig::default();
+    let block_hash = B256::with_last_byte(7);
+    let sender = Address::repeat_byte(0x5e);
+    let receiver = Address::repeat_byte(0xe);
+    // Answers every view call with SLOAD(0)
+    let oracle_code = Bytecode::new_raw(Bytes::from_static(&[
+        0x60, 0x00, 0x54, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3,
+    ]));
+    let code_hash = oracle_code.hash_slow();
+    config.state_cache.insert_bytecode(code_hash, oracle_code);
+    for (address, code_hash) in [
+        (sender, KECCAK_EMPTY),
+        (receiver, KECCAK_EMPTY),
+        (GAS_PRICE_ORACLE, code_hash),
+    ] {
+        config.state_cache.insert_account(
+            10,
+            block_hash,
+            address,
+            CachedAccount {
+                balance: U256::from(10u128.pow(18)),
+                nonce: 0,
+                code_hash,
+            },
+        );
+    }
+    config.state_cache.insert_storage(
+        10,
+        block_hash,
+        GAS_PRICE_ORACLE,
+        U256::ZERO,
+        U256::from(1000),
+    );
+
+    // The oracle runs in the local EVM on the cached state, no `eth_call` reaches the upstream
+    let asserter = Asserter::new();
+    push_chain_head_of(&asserter, 10, 7);
+    asserter.push_success(&U256::from(1_000_000));
+    let tx = Tx {
+        from: Some(sender),
+        to: Some(receiver),
+        value: U256::from(1),
+        nonce: Some(0),
+        ..Default::default()
+    };
+    let estimate = mocked_estimator(&asserter, &config)
+        .estimate_gas(tx.clone())
         .await
         .unwrap();
-    let block: Block = provider
-        .get_block(BlockId::latest())
+    assert!(asserter.read_q().is_empty());
+
+    let params = L1FeeParams {
+        l1_base_fee: U256::from(1000),
+        blob_base_fee: U256::from(1000),
+        base_fee_scalar: 1000,
+        blob_base_fee_scalar: 1000,
+        is_fjord: true,
+    };
+    let unsigned_tx = encode_unsigned(&tx, 10, 0, 21_000, 1_000_000);
+    assert_eq!(
+        estimate.breakdown.l1_data_fee,
+        Some(params.l1_data_fee(&unsigned_tx).to())
+    );
+}
+
+#[tokio::test]
+async fn test_verified_op_stack_estimate_proves_the_oracle_state() {
+    let receiver = Address::repeat_byte(0xe);
+    // Answers every view call with SLOAD(0)
+    let oracle_code = Bytes::from_static(&[
+        0x60, 0x00, 0x54, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3,
+    ]);
+    let slot_leaves = [(B256::ZERO, alloy::rlp::encode(U256::from(1000)))];
+    let (storage_root, slot_proof) = trie_proof(&slot_leaves, B256::ZERO);
+    let account_leaves = [
+        (
+            receiver,
+            alloy::rlp::encode(TrieAccount {
+                nonce: 0,
+                balance: U256::from(1),
+                storage_root: EMPTY_ROOT_HASH,
+                code_hash: KECCAK_EMPTY,
+            }),
+        ),
+        (
+            GAS_PRICE_ORACLE,
+            alloy::rlp::encode(TrieAccount {
+                nonce: 0,
+                balance: U256::ZERO,
+                storage_root,
+                code_hash: keccak256(&oracle_code),
+            }),
+        ),
+    ];
+    let (state_root, receiver_proof) = trie_proof(&account_leaves, receiver);
+    let (_, oracle_proof) = trie_proof(&account_leaves, GAS_PRICE_ORACLE);
+    let block = alloy::rpc::types::Block::<alloy::rpc::types::Transaction> {
+        header: alloy::rpc::types::Header::new(alloy::consensus::Header {
+            number: 7,
+            timestamp: 1_750_000_000,
+            gas_limit: 36_000_000,
+            state_root,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let upstream = Asserter::new();
+    let trusted = Asserter::new();
+    let config = EstimatorConfig {
+        verify_state: true,
+        trusted_upstream: Some(RootProvider::new(RpcClient::mocked(trusted.clone()))),
+        ..Default::default()
+    };
+    upstream.push_success(&U64::from(10));
+    upstream.push_success(&block);
+    trusted.push_success(&block);
+    upstream.push_success(&json!({
+        "address": receiver,
+        "balance": "0x1",
+        "codeHash": KECCAK_EMPTY,
+        "nonce": "0x0",
+        "storageHash": EMPTY_ROOT_HASH,
+        "accountProof": receiver_proof,
+        "storageProof": [],
+    }));
+    upstream.push_success(&U256::from(1_000_000));
+    // The oracle's code, then its slot 0 as the view calls read it, both proven
+    let oracle_response = |storage_proof| {
+        json!({
+            "address": GAS_PRICE_ORACLE,
+            "balance": "0x0",
+            "codeHash": keccak256(&oracle_code),
+            "nonce": "0x0",
+            "storageHash": storage_root,
+            "accountProof": oracle_proof,
+            "storageProof": storage_proof,
+        })
+    };
+    upstream.push_success(&oracle_response(json!([])));
+    upstream.push_success(&oracle_code);
+    upstream.push_success(&oracle_response(json!([
+        { "key": B256::ZERO, "value": "0x3e8", "proof": slot_proof },
+    ])));
+
+    let tx = Tx {
+        to: Some(receiver),
+        value: U256::from(1),
+        nonce: Some(0),
+        ..Default::default()
+    };
+    let estimate = mocked_estimator(&upstream, &config)
+        .estimate_gas(tx.clone())
         .await
-        .unwrap()
         .unwrap();
-    // block.header.base_fee_per_gas
-    // let block_body = block.into_block_body_unchecked();
+    // No `eth_call` was made for the L1 fee parameters
+    assert!(upstream.read_q().is_empty());
+    assert!(estimate.verified);
+    let params = L1FeeParams {
+        l1_base_fee: U256::from(1000),
+        blob_base_fee: U256::from(1000),
+        base_fee_scalar: 1000,
+        blob_base_fee_scalar: 1000,
+        is_fjord: true,
+    };
+    let unsigned_tx = encode_unsigned(&tx, 10, 0, 21_000, 1_000_000);
+    assert_eq!(
+        estimate.breakdown.l1_data_fee,
+        Some(params.l1_data_fee(&unsigned_tx).to())
+    );
+}
 
-    // Deploy contract once for testing contract calls
-    let contract = Counter::deploy(&provider).await.unwrap();
-    let counter_contract_address = *contract.address();
+#[test]
+fn test_provider_pool_reuses_providers_and_rejects_bad_urls() {
+    let pool = ProviderPool::new(ProviderPoolConfig {
+        max_upstreams: 1,
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert!(matches!(
+        pool.get("not a url"),
+        Err(Error::InvalidRpcUrl(_))
+    ));
+    assert!(matches!(
+        pool.get("ws://localhost:8546"),
+        Err(Error::InvalidRpcUrl(_))
+    ));
 
-    let contract = Caller::deploy(&provider).await.unwrap();
-    let caller_contract_address = *contract.address();
+    // Over the limit providers are still handed out, only not kept
+    assert!(pool.get(ETH_RPC_URL).is_ok());
+    assert!(pool.get("https://mainnet.optimism.io").is_ok());
+    assert!(pool.get(ETH_RPC_URL).is_ok());
+}
 
-    let contract = ERC20::deploy(&provider).await.unwrap();
-    let erc20_contract_address = *contract.address();
+#[test]
+fn test_state_cache_evicts_and_invalidates_on_new_block() {
+    let cache = StateCache::new(StateCacheConfig {
+        max_accounts: 2,
+        max_storage_slots: 2,
+        max_bytecodes: 2,
+    });
+    let block = B256::repeat_byte(1);
+    let next_block = B256::repeat_byte(2);
+    let account = CachedAccount {
+        balance: U256::from(1),
+        nonce: 2,
+        code_hash: B256::repeat_byte(3),
+    };
+    let [a, b, c] = [
+        Address::repeat_byte(0xa),
+        Address::repeat_byte(0xb),
+        Address::repeat_byte(0xc),
+    ];
+
+    cache.pin_block(1, 100, block);
+    cache.insert_account(1, block, a, account);
+    cache.insert_account(1, block, b, account);
+    assert_eq!(cache.account(1, block, a), Some(account));
+    // `b` is now the least recently used and makes room for `c`
+    cache.insert_account(1, block, c, account);
+    assert_eq!(cache.account(1, block, b), None);
+    // Same address on another chain is a different entry
+    assert_eq!(cache.account(10, block, a), None);
+
+    cache.insert_storage(1, block, a, U256::from(7), U256::from(42));
+    cache.insert_bytecode(
+        account.code_hash,
+        Bytecode::new_raw(Bytes::from(vec![0x00])),
+    );
+
+    // An estimate against an older block does not invalidate anything
+    cache.pin_block(1, 99, B256::repeat_byte(9));
+    assert_eq!(
+        cache.storage(1, block, a, U256::from(7)),
+        Some(U256::from(42))
+    );
+
+    // Moving to the next block drops account and storage entries, code stays valid
+    cache.pin_block(1, 101, next_block);
+    assert_eq!(cache.account(1, block, a), None);
+    assert_eq!(cache.storage(1, block, a, U256::from(7)), None);
+    assert!(cache.bytecode(account.code_hash).is_some());
+
+    let stats = cache.stats();
+    assert_eq!((stats.accounts.hits, stats.accounts.misses), (1, 3));
+    assert_eq!((stats.storage.hits, stats.storage.misses), (1, 1));
+    assert_eq!((stats.bytecodes.hits, stats.bytecodes.misses), (1, 0));
+    assert_eq!(stats.cached_accounts, 0);
+    assert_eq!(stats.invalidations, 1);
+}
 
-    // Test Case 1: Simple Transfer
-    let simple_transfer_tx = Tx {
-        from: Some(wallet.address()),
-        to: Some(address!("0x1234567890123456789012345678901234567890")),
-        value: U256::from(1u64), // 1 ETH
-        data: None,
-        nonce: Some(1),
-        chain_id: Some(U64::from(31337)), // Anvil default chain ID
-        gas_limit: Some(21000),
-        gas_price: Some(20000000000), // 20 gwei
-        max_fee_per_gas: None,
-        max_priority_fee_per_gas: None,
-        access_list: None,
-        transaction_type: Some(U64::from(0)),
-    };
-
-    // Test EVM-based estimation for simple transfer via RPC
-    let evm_transfer_estimate = estimate_gas_via_rpc(&server_url, simple_transfer_tx.clone())
+#[test]
+fn test_state_store_persists_and_prunes() {
+    let dir = std::env::temp_dir().join("gas-estimator-state-store");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let code = Bytes::from(vec![0x60, 0x00]);
+    let code_hash = keccak256(&code);
+    let account = CachedAccount {
+        balance: U256::from(5),
+        nonce: 1,
+        code_hash,
+    };
+    let contract = Address::repeat_byte(0xc);
+    let block = |block_number: u64| BlockKey {
+        chain_id: 1,
+        block_number,
+        block_hash: B256::with_last_byte(block_number as u8),
+    };
+
+    {
+        let store = StateStore::open(&dir).unwrap();
+        store.insert_account(block(10), contract, account).unwrap();
+        store
+            .insert_storage(block(10), contract, U256::from(1), U256::from(2))
+            .unwrap();
+        store.insert_bytecode(code_hash, &code).unwrap();
+        store
+            .insert_account(block(11), Address::repeat_byte(0xe), account)
+            .unwrap();
+    }
+
+    // A fresh store reads back what an earlier one wrote
+    let store = StateStore::open(&dir).unwrap();
+    assert_eq!(store.account(block(10), contract).unwrap(), Some(account));
+    assert_eq!(
+        store.storage(block(10), contract, U256::from(1)).unwrap(),
+        Some(U256::from(2))
+    );
+    assert_eq!(
+        store.storage(block(11), contract, U256::from(1)).unwrap(),
+        None
+    );
+    assert_eq!(
+        store.bytecode(code_hash).unwrap().unwrap().original_bytes(),
+        code
+    );
+
+    let blocks = store.blocks().unwrap();
+    assert_eq!(blocks.len(), 2);
+    assert_eq!((blocks[0].accounts, blocks[0].storage_slots), (1, 1));
+
+    let export = store.export(1, 10, None).unwrap();
+    assert_eq!(export.accounts[&contract].code, Some(code));
+    assert_eq!(
+        export.accounts[&contract].storage[&U256::from(1)],
+        U256::from(2)
+    );
+    assert!(store.export(1, 12, None).is_err());
+
+    // Block 11 still refers to the code, so only block 10 goes
+    let report = store.prune(None, None, Some(1)).unwrap();
+    assert_eq!((report.blocks_removed, report.bytecodes_removed), (1, 0));
+    assert_eq!(store.account(block(10), contract).unwrap(), None);
+    let report = store.prune(Some(1), Some(12), None).unwrap();
+    assert_eq!((report.blocks_removed, report.bytecodes_removed), (1, 1));
+    assert!(store.blocks().unwrap().is_empty());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_state_store_serves_reruns_at_past_block() {
+    let dir = std::env::temp_dir().join("gas-estimator-state-store-rerun");
+    let _ = std::fs::remove_dir_all(&dir);
+    let store = Arc::new(StateStore::open(&dir).unwrap());
+    let config = || EstimatorConfig {
+        state_store: Some(store.clone()),
+        block: Some(7),
+        ..Default::default()
+    };
+    let sender = Address::repeat_byte(0x5e);
+    let contract = Address::repeat_byte(0xc);
+    let tx = Tx {
+        from: Some(sender),
+        to: Some(contract),
+        chain_id: Some(U64::from(1)),
+        ..Default::default()
+    };
+
+    // The first run fetches the block and the state from the upstream
+    let asserter = Asserter::new();
+    let mut block = alloy::rpc::types::Block::<alloy::rpc::types::Transaction>::default();
+    block.header.hash = B256::with_last_byte(7);
+    block.header.inner.number = 7;
+    block.header.inner.timestamp = 1_750_000_000;
+    block.header.inner.gas_limit = 36_000_000;
+    block.header.inner.base_fee_per_gas = Some(2_000_000_000);
+    asserter.push_success(&U64::from(1));
+    asserter.push_success(&block);
+    // Balance, nonce and code of the contract (STOP), then of the sender
+    asserter.push_success(&U256::ZERO);
+    asserter.push_success(&U64::ZERO);
+    asserter.push_success(&Bytes::from_static(&[0x00]));
+    asserter.push_success(&U256::from(10u128.pow(18)));
+    asserter.push_success(&U64::ZERO);
+    asserter.push_success(&Bytes::new());
+    asserter.push_failure_msg("the method debug_traceCall does not exist");
+    let first = mocked_estimator(&asserter, &config())
+        .estimate_gas(tx.clone())
+        .await
+        .unwrap();
+    assert!(asserter.read_q().is_empty());
+    assert_eq!(first.estimated_gas, 21_000);
+    assert_eq!(first.gas_price, 2_000_000_000);
+
+    // A re-run with fresh caches needs nothing from the upstream
+    let asserter = Asserter::new();
+    let rerun = mocked_estimator(&asserter, &config())
+        .estimate_gas(tx.clone())
         .await
         .unwrap();
+    assert_eq!(rerun.estimated_gas, first.estimated_gas);
+    assert_eq!(rerun.gas_price, first.gas_price);
+
+    // The header index of a reopened store is read back from disk
+    let reopened = Arc::new(StateStore::open(&dir).unwrap());
+    let (stored_block, stored_header) = reopened.header(1, 7).await.unwrap().unwrap();
+    assert_eq!(stored_block.block_hash, B256::with_last_byte(7));
+    assert_eq!(stored_header.base_fee_per_gas, Some(2_000_000_000));
+    drop(reopened);
+
+    // After a reorg two blocks are stored at the height, the upstream picks the one to use
+    let reorged = BlockKey {
+        block_hash: B256::with_last_byte(0x77),
+        ..stored_block
+    };
+    store.insert_header(reorged, stored_header).await.unwrap();
+    assert!(store.header(1, 7).await.unwrap().is_none());
+    let asserter = Asserter::new();
+    asserter.push_success(&U64::from(1));
+    asserter.push_success(&block);
+    asserter.push_failure_msg("the method debug_traceCall does not exist");
+    let refetched = mocked_estimator(&asserter, &config())
+        .estimate_gas(tx)
+        .await
+        .unwrap();
+    assert!(asserter.read_q().is_empty());
+    assert_eq!(refetched.estimated_gas, first.estimated_gas);
 
-    // Test Alloy provider estimation for simple transfer
-    let alloy_transfer_tx = alloy::rpc::types::TransactionRequest::default()
-        .from(wallet.address())
-        .to(address!("0x1234567890123456789012345678901234567890"))
-        .value(U256::from(1u64));
+    drop(store);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
 
-    let alloy_transfer_result = provider.estimate_gas(alloy_transfer_tx).await;
-    let alloy_transfer_estimate = alloy_transfer_result.unwrap();
+fn mocked_estimator(asserter: &Asserter, config: &EstimatorConfig) -> GasEstimator {
+    let provider = RootProvider::new(RpcClient::mocked(asserter.clone()));
+    GasEstimator::new(Upstream::new(provider), config.clone())
+}
 
-    // Test Case 2: Contract Deployment
-    let deployment_bytecode = Bytes::from_str("0x60808060405234601957602a5f55610106908161001e8239f35b5f80fdfe608060405260043610156010575f80fd5b5f3560e01c80633fb5c1cb1460af5780638381f58a146094578063d09de08a14605e5763d5556544146040575f80fd5b34605a575f366003190112605a5760205f54604051908152f35b5f80fd5b34605a575f366003190112605a576001545f1981146080576001016001555f80f35b634e487b7160e01b5f52601160045260245ffd5b34605a575f366003190112605a576020600154604051908152f35b34605a576020366003190112605a575f54600435810180911160805760015500fea2646970667358221220e470db5efcff30a5d2bf2dfc5c01072c1364af37644d14ea4b2c86293086d86664736f6c634300081e0033").unwrap();
+fn mainnet_context(block_number: u64) -> ChainContext {
+    let profile = ChainRegistry::builtin().get(1);
+    ChainContext {
+        spec: SpecId::PRAGUE,
+        precompiles: profile.precompiles(SpecId::PRAGUE),
+        block_number,
+        block_hash: B256::with_last_byte(block_number as u8),
+        state_root: B256::ZERO,
+        execution_block_number: block_number,
+        timestamp: 1_750_000_000,
+        block_gas_limit: 36_000_000,
+        base_fee: 0,
+        beneficiary: Address::ZERO,
+        prevrandao: B256::ZERO,
+        excess_blob_gas: None,
+        overlay: None,
+        gas_price: None,
+        from_store: false,
+        profile,
+    }
+}
 
-    let contract_deployment_tx = Tx {
-        from: Some(wallet.address()),
-        to: None, // Contract deployment
-        value: U256::ZERO,
-        data: Some(deployment_bytecode.clone()),
-        nonce: Some(2),
-        chain_id: Some(U64::from(31337)),
-        gas_limit: None,
-        gas_price: Some(20000000000),
-        max_fee_per_gas: None,
-        max_priority_fee_per_gas: None,
-        access_list: None,
-        transaction_type: Some(U64::from(0)),
-    };
-
-    // Test EVM-based estimation for contract deployment via RPC
-    let evm_deploy_estimate = estimate_gas_via_rpc(&server_url, contract_deployment_tx.clone())
+#[tokio::test]
+async fn test_round_state_fetched_with_one_proof_per_account() {
+    let config = EstimatorConfig::default();
+    let chain = mainnet_context(1);
+    let contract = Address::repeat_byte(0xc);
+    let slots = [(contract, U256::from(1)), (contract, U256::from(2))];
+    let code = Bytes::from(vec![0x60, 0x00]);
+
+    let asserter = Asserter::new();
+    asserter.push_success(&json!({
+        "address": contract,
+        "balance": "0x5",
+        "codeHash": keccak256(&code),
+        "nonce": "0x1",
+        "storageHash": B256::ZERO,
+        "accountProof": [],
+        "storageProof": [
+            { "key": B256::with_last_byte(1), "value": "0x2a", "proof": [] },
+            { "key": B256::with_last_byte(2), "value": "0x0", "proof": [] },
+        ],
+    }));
+    asserter.push_success(&code);
+
+    let mut cache_db = CacheDB::new(EmptyDB::default());
+    mocked_estimator(&asserter, &config)
+        .add_state_to_db(&mut cache_db, [contract], slots, &chain)
+        .await
+        .unwrap();
+    assert!(asserter.read_q().is_empty());
+    let account = cache_db.load_account(contract).unwrap();
+    assert_eq!(account.info.balance, U256::from(5));
+    assert_eq!(account.info.code.as_ref().unwrap().original_bytes(), code);
+    assert_eq!(account.storage[&U256::from(1)], U256::from(42));
+
+    // A later estimate on the same block is served from the shared state cache
+    let mut cache_db = CacheDB::new(EmptyDB::default());
+    mocked_estimator(&Asserter::new(), &config)
+        .add_state_to_db(&mut cache_db, [contract], slots, &chain)
+        .await
+        .unwrap();
+    assert_eq!(
+        cache_db.load_account(contract).unwrap().storage[&U256::from(1)],
+        U256::from(42)
+    );
+
+    // Upstreams without `eth_getProof` fall back to `eth_getStorageAt`
+    let asserter = Asserter::new();
+    asserter.push_failure_msg("the method eth_getProof does not exist");
+    asserter.push_success(&U256::from(7));
+    let mut cache_db = CacheDB::new(EmptyDB::default());
+    mocked_estimator(&asserter, &config)
+        .add_state_to_db(&mut cache_db, [], [(contract, U256::from(3))], &chain)
         .await
         .unwrap();
+    assert!(asserter.read_q().is_empty());
+    assert_eq!(
+        cache_db.load_account(contract).unwrap().storage[&U256::from(3)],
+        U256::from(7)
+    );
+}
 
-    // Test Alloy provider estimation for contract deployment
-    let alloy_deploy_tx = alloy::rpc::types::TransactionRequest::default()
-        .from(wallet.address())
-        .input(deployment_bytecode.into());
+/// Root of a trie holding `leaves` (keyed by unhashed key), and the proof of `key` in it
+fn trie_proof<K: AsRef<[u8]>>(leaves: &[(K, Vec<u8>)], key: K) -> (B256, Vec<Bytes>) {
+    let target = Nibbles::unpack(keccak256(key));
+    let mut leaves: Vec<_> = leaves
+        .iter()
+        .map(|(key, value)| (Nibbles::unpack(keccak256(key)), value))
+        .collect();
+    leaves.sort();
+    let mut builder =
+        HashBuilder::default().with_proof_retainer(ProofRetainer::new(vec![target.clone()]));
+    for (path, value) in leaves {
+        builder.add_leaf(path, value);
+    }
+    let root = builder.root();
+    let proof = builder
+        .take_proof_nodes()
+        .matching_nodes_sorted(&target)
+        .into_iter()
+        .map(|(_, node)| node)
+        .collect();
+    (root, proof)
+}
 
-    let alloy_deploy_result = provider.estimate_gas(alloy_deploy_tx).await;
-    let alloy_deploy_estimate = alloy_deploy_result.unwrap();
+#[tokio::test]
+async fn test_verified_state_checked_against_state_root() {
+    let config = EstimatorConfig {
+        verify_state: true,
+        ..Default::default()
+    };
+    let contract = Address::repeat_byte(0xc);
+    let other = Address::repeat_byte(0xd);
+    let code = Bytes::from(vec![0x60, 0x00]);
+
+    // Slot 1 holds 42, slot 2 is empty and proven absent
+    let slot_leaves = [
+        (B256::with_last_byte(1), alloy::rlp::encode(U256::from(42))),
+        (B256::with_last_byte(5), alloy::rlp::encode(U256::from(7))),
+    ];
+    let (storage_root, slot_1_proof) = trie_proof(&slot_leaves, B256::with_last_byte(1));
+    let (_, slot_2_proof) = trie_proof(&slot_leaves, B256::with_last_byte(2));
+    let account = |address: Address, balance: u64, storage_root: B256, code_hash: B256| {
+        (
+            address,
+            alloy::rlp::encode(TrieAccount {
+                nonce: 1,
+                balance: U256::from(balance),
+                storage_root,
+                code_hash,
+            }),
+        )
+    };
+    let (state_root, account_proof) = trie_proof(
+        &[
+            account(contract, 5, storage_root, keccak256(&code)),
+            account(other, 9, EMPTY_ROOT_HASH, KECCAK_EMPTY),
+        ],
+        contract,
+    );
+    let chain = ChainContext {
+        state_root,
+        ..mainnet_context(3)
+    };
+    let proof_response = |slot_1_value: &str| {
+        json!({
+            "address": contract,
+            "balance": "0x5",
+            "codeHash": keccak256(&code),
+            "nonce": "0x1",
+            "storageHash": storage_root,
+            "accountProof": account_proof,
+            "storageProof": [
+                { "key": B256::with_last_byte(1), "value": slot_1_value, "proof": slot_1_proof },
+                { "key": B256::with_last_byte(2), "value": "0x0", "proof": slot_2_proof },
+            ],
+        })
+    };
+    let slots = [(contract, U256::from(1)), (contract, U256::from(2))];
+
+    let asserter = Asserter::new();
+    asserter.push_success(&proof_response("0x2a"));
+    asserter.push_success(&code);
+    let mut cache_db = CacheDB::new(EmptyDB::default());
+    mocked_estimator(&asserter, &config)
+        .add_state_to_db(&mut cache_db, [contract], slots, &chain)
+        .await
+        .unwrap();
+    let loaded = cache_db.load_account(contract).unwrap();
+    assert_eq!(loaded.info.balance, U256::from(5));
+    assert_eq!(loaded.storage[&U256::from(1)], U256::from(42));
+
+    // A tampered slot fails the estimate, even though the state cache holds the real value
+    let asserter = Asserter::new();
+    asserter.push_success(&proof_response("0x2b"));
+    let mut cache_db = CacheDB::new(EmptyDB::default());
+    let result = mocked_estimator(&asserter, &config)
+        .add_state_to_db(&mut cache_db, [contract], slots, &chain)
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::StateProofMismatch { address, .. }) if address == contract
+    ));
+}
 
-    // Test Case 3: Contract Call
-    // Call 1 ------------------------------------------------------
-    let call_data = Counter::setNumberCall::new((U256::from(20),));
-    let encoded_call_data = Bytes::from(call_data.abi_encode());
+#[tokio::test]
+async fn test_verified_block_confirmed_by_trusted_upstream() {
+    let header = alloy::consensus::Header {
+        number: 20_000_000,
+        timestamp: 1_750_000_000,
+        gas_limit: 36_000_000,
+        state_root: B256::repeat_byte(0x5),
+        ..Default::default()
+    };
+    let block = |header| alloy::rpc::types::Block::<alloy::rpc::types::Transaction> {
+        header: alloy::rpc::types::Header::new(header),
+        ..Default::default()
+    };
+    let upstream = Asserter::new();
+    let trusted = Asserter::new();
+    let config = EstimatorConfig {
+        verify_state: true,
+        trusted_upstream: Some(RootProvider::new(RpcClient::mocked(trusted.clone()))),
+        ..Default::default()
+    };
 
-    let contract_call_tx = Tx {
-        from: Some(wallet.address()),
-        to: Some(counter_contract_address),
+    // The trusted upstream knows the block
+    upstream.push_success(&U64::from(1));
+    upstream.push_success(&block(header.clone()));
+    trusted.push_success(&block(header.clone()));
+    let chain = mocked_estimator(&upstream, &config)
+        .chain_context(&Tx::default())
+        .await
+        .unwrap();
+    assert_eq!(chain.block_hash, header.hash_slow());
+    assert_eq!(chain.state_root, B256::repeat_byte(0x5));
+
+    // A consistent header the trusted upstream does not have is rejected
+    upstream.push_success(&U64::from(1));
+    upstream.push_success(&block(header.clone()));
+    trusted.push_success(&block(alloy::consensus::Header {
+        state_root: B256::repeat_byte(0x6),
+        ..header.clone()
+    }));
+    let result = mocked_estimator(&upstream, &config)
+        .chain_context(&Tx::default())
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::UntrustedBlock { block_number: 20_000_000, block_hash, .. })
+            if block_hash == header.hash_slow()
+    ));
+}
+
+#[tokio::test]
+async fn test_prestate_prefetch_warms_state_and_detects_support() {
+    let config = EstimatorConfig::default();
+    let chain = mainnet_context(2);
+    let caller = Address::repeat_byte(0xa);
+    let token = Address::repeat_byte(0xb);
+    let code = Bytes::from(vec![0x60, 0x00]);
+    let tx = Tx {
+        from: Some(caller),
+        to: Some(token),
         value: U256::ZERO,
-        data: Some(encoded_call_data.clone()),
-        nonce: Some(3),
-        chain_id: Some(U64::from(31337)),
-        gas_limit: None,
-        gas_price: Some(20000000000),
-        max_fee_per_gas: None,
-        max_priority_fee_per_gas: None,
-        access_list: None,
-        transaction_type: Some(U64::from(2)), // EIP-1559
+        ..Default::default()
     };
 
-    // Test EVM-based estimation for contract call via RPC
-    let evm_call_estimate_1 = estimate_gas_via_rpc(&server_url, contract_call_tx.clone())
+    let asserter = Asserter::new();
+    asserter.push_success(&json!({
+        caller.to_string(): { "balance": "0x100", "nonce": 3 },
+        token.to_string(): {
+            "balance": "0x0",
+            "code": code,
+            "storage": { B256::with_last_byte(1).to_string(): B256::with_last_byte(9) },
+        },
+    }));
+    let upstream = Upstream::new(RootProvider::new(RpcClient::mocked(asserter.clone())));
+    let estimator = GasEstimator::new(upstream.clone(), config.clone());
+    let mut cache_db = CacheDB::new(EmptyDB::default());
+    estimator
+        .prefetch_prestate(&mut cache_db, &tx, caller, &chain)
+        .await
+        .unwrap();
+    let account = cache_db.load_account(token).unwrap();
+    assert_eq!(account.info.code_hash, keccak256(&code));
+    assert_eq!(account.storage[&U256::from(1)], U256::from(9));
+    // The caller is left to the simulation setup
+    assert!(cache_db
+        .load_account(caller)
+        .unwrap()
+        .info
+        .balance
+        .is_zero());
+    // Fetched state is shared with later estimates
+    let account = estimator.account(token, &chain).await.unwrap();
+    assert_eq!(account.code_hash, keccak256(&code));
+
+    // An upstream rejecting the method is not asked again
+    asserter.push_failure_msg("the method debug_traceCall does not exist");
+    estimator
+        .prefetch_prestate(&mut cache_db, &tx, caller, &chain)
         .await
         .unwrap();
+    assert!(!upstream.capabilities.prestate_trace.is_available());
+    asserter.push_success(&json!({}));
+    let estimator = GasEstimator::new(upstream, config);
+    estimator
+        .prefetch_prestate(&mut cache_db, &tx, caller, &chain)
+        .await
+        .unwrap();
+    assert_eq!(asserter.read_q().len(), 1);
+
+    // With everything the transaction touches prefetched, one EVM round is enough.
+    // The token reads slot 1: SLOAD(1)
+    let config = EstimatorConfig::default();
+    let code = [0x60, 0x01, 0x54, 0x00];
+    cache_account(&config, 2, caller, U256::from(10u128.pow(18)), 3, &[]);
+    cache_account(&config, 2, token, U256::ZERO, 0, &code);
+    let asserter = Asserter::new();
+    asserter.push_success(&U256::from(1_000_000_000));
+    asserter.push_success(&json!({
+        caller.to_string(): { "balance": "0x100", "nonce": 3 },
+        token.to_string(): {
+            "balance": "0x0",
+            "code": Bytes::copy_from_slice(&code),
+            "storage": { B256::with_last_byte(1).to_string(): B256::with_last_byte(9) },
+        },
+    }));
+    let simulation = mocked_estimator(&asserter, &config)
+        .simulate_call(&tx, &chain)
+        .await
+        .unwrap();
+    assert!(asserter.read_q().is_empty());
+    assert_eq!(simulation.rounds, 1);
+    assert_eq!(simulation.gas_used, 21_000 + 3 + 2_100);
+}
 
-    // Test Alloy provider estimation for contract call
-    let alloy_call_tx = alloy::rpc::types::TransactionRequest::default()
-        .from(wallet.address())
-        .to(counter_contract_address)
-        .input(encoded_call_data.into());
+#[test]
+fn test_tracer_attributes_delegatecall_storage_to_proxy() {
+    let proxy = Address::repeat_byte(0xa);
+    let implementation = Address::repeat_byte(0xb);
+    let queried = Address::repeat_byte(0xc);
+
+    // SLOAD(1), BALANCE(queried), SSTORE(2, 5)
+    let mut implementation_code = vec![0x60, 0x01, 0x54, 0x50, 0x73];
+    implementation_code.extend_from_slice(queried.as_slice());
+    implementation_code.extend_from_slice(&[0x31, 0x50, 0x60, 0x05, 0x60, 0x02, 0x55, 0x00]);
+    // DELEGATECALL(gas, implementation, 0, 0, 0, 0)
+    let mut proxy_code = vec![0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x73];
+    proxy_code.extend_from_slice(implementation.as_slice());
+    proxy_code.extend_from_slice(&[0x5a, 0xf4, 0x00]);
+
+    let mut cache_db = CacheDB::new(EmptyDB::default());
+    for (address, code) in [(proxy, proxy_code), (implementation, implementation_code)] {
+        let bytecode = Bytecode::new_raw(code.into());
+        cache_db.insert_account_info(
+            address,
+            AccountInfo {
+                code_hash: bytecode.hash_slow(),
+                code: Some(bytecode),
+                ..Default::default()
+            },
+        );
+    }
 
-    let alloy_call_result = provider.estimate_gas(alloy_call_tx).await;
-    let alloy_call_estimate_1 = alloy_call_result.unwrap();
+    let run = |tracer: &mut Tracer| {
+        let tx = TxEnvBuilder::new()
+            .caller(Address::repeat_byte(0x1))
+            .kind(TxKind::Call(proxy))
+            .gas_limit(1_000_000)
+            .gas_price(0)
+            .build()
+            .unwrap();
+        let mut evm = Context::mainnet()
+            .with_db(cache_db.clone())
+            .build_mainnet_with_inspector(tracer);
+        assert!(evm.inspect_tx(tx).unwrap().is_success());
+    };
+    let mut tracer = Tracer::new();
+    run(&mut tracer);
+
+    // Storage the implementation touches belongs to the proxy
+    assert_eq!(
+        tracer.storage_accesses,
+        HashSet::from([(proxy, U256::from(1)), (proxy, U256::from(2))])
+    );
+    for address in [proxy, implementation, queried] {
+        assert!(tracer.contract_addresses.contains(&address));
+    }
+    assert_eq!(tracer.external_calls, 1);
 
-    // Call 2 ------------------------------------------------------
-    let call_data = Counter::complexCall::new(());
-    let encoded_call_data = Bytes::from(call_data.abi_encode());
+    // Accesses already reported are not reported again
+    tracer.reset_state();
+    run(&mut tracer);
+    assert!(!tracer.has_new_accesses());
+}
 
-    let contract_call_tx = Tx {
-        from: Some(wallet.address()),
-        to: Some(counter_contract_address),
+#[tokio::test]
+async fn test_simulation_limits() {
+    let chain = mainnet_context(4);
+    let caller = Address::repeat_byte(0xa);
+    let contract = Address::repeat_byte(0xb);
+    // SLOAD(1)
+    let code = Bytecode::new_raw(vec![0x60, 0x01, 0x54, 0x00].into());
+    let state_cache = Arc::new(StateCache::default());
+    let account = |code_hash| CachedAccount {
+        balance: U256::from(10).pow(U256::from(18)),
+        nonce: 0,
+        code_hash,
+    };
+    state_cache.insert_account(1, chain.block_hash, caller, account(KECCAK_EMPTY));
+    state_cache.insert_account(1, chain.block_hash, contract, account(code.hash_slow()));
+    state_cache.insert_bytecode(code.hash_slow(), code.clone());
+    state_cache.insert_storage(1, chain.block_hash, contract, U256::from(1), U256::from(7));
+    let tx = Tx {
+        from: Some(caller),
+        to: Some(contract),
         value: U256::ZERO,
-        data: Some(encoded_call_data.clone()),
-        nonce: Some(1),
-        chain_id: Some(U64::from(31337)), // Anvil default chain ID
-        gas_limit: None,
-        gas_price: Some(20000000000),
-        max_fee_per_gas: None,
-        max_priority_fee_per_gas: None,
-        access_list: None,
-        transaction_type: Some(U64::from(2)), // EIP-1559
-    };
-
-    // Test EVM-based estimation for contract call via RPC
-    let evm_call_estimate_2 = estimate_gas_via_rpc(&server_url, contract_call_tx.clone())
-        .await
-        .unwrap();
+        ..Default::default()
+    };
+    let simulate = |config: EstimatorConfig| {
+        let asserter = Asserter::new();
+        asserter.push_success(&U256::from(1_000_000_000));
+        asserter.push_failure_msg("the method debug_traceCall does not exist");
+        let estimator = mocked_estimator(&asserter, &config);
+        let tx = tx.clone();
+        let chain = chain.clone();
+        async move { estimator.simulate_call(&tx, &chain).await }
+    };
 
-    // Test Alloy provider estimation for contract call
-    let alloy_call_tx = alloy::rpc::types::TransactionRequest::default()
-        .from(wallet.address())
-        .to(counter_contract_address)
-        .input(encoded_call_data.into());
+    // The slot read in the first round is loaded for a second one, which discovers nothing new
+    let mut config = EstimatorConfig {
+        state_cache,
+        ..Default::default()
+    };
+    let simulation = simulate(config.clone()).await.unwrap();
+    assert_eq!(simulation.rounds, 2);
+    // The slot is read cold; the caller and the callee are warm
+    assert_eq!(simulation.execution.cold_accesses, 1);
+
+    config.limits.max_rounds = 1;
+    assert!(matches!(
+        simulate(config.clone()).await,
+        Err(Error::TooManyRounds(1))
+    ));
 
-    let alloy_call_result = provider.estimate_gas(alloy_call_tx).await;
-    let alloy_call_estimate_2 = alloy_call_result.unwrap();
+    config.limits = SimulationLimits {
+        max_fetched_slots: 0,
+        ..Default::default()
+    };
+    assert!(matches!(
+        simulate(config.clone()).await,
+        Err(Error::TooManySlots(0))
+    ));
 
-    // Call 3 ------------------------------------------------------
-    let call_data = Caller::precompileCall::new((U256::from(123456),));
-    let encoded_call_data = Bytes::from(call_data.abi_encode());
+    config.limits = SimulationLimits {
+        max_steps: 2,
+        ..Default::default()
+    };
+    assert!(matches!(
+        simulate(config).await,
+        Err(Error::TooManySteps(2))
+    ));
+}
 
-    let contract_call_tx = Tx {
-        from: Some(wallet.address()),
-        to: Some(caller_contract_address),
+#[tokio::test]
+async fn test_evm_pool_bounds_queue_and_cancels_dropped_executions() {
+    let pool = Arc::new(EvmPool::new(EvmPoolConfig {
+        threads: 1,
+        queue_size: 1,
+    }));
+
+    // An execution runs until its caller goes away
+    let (finished_tx, finished_rx) = std::sync::mpsc::channel();
+    let running = tokio::spawn({
+        let pool = pool.clone();
+        async move {
+            pool.run(move |cancelled| {
+                while !cancelled.load(Ordering::Relaxed) {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                finished_tx.send(()).unwrap();
+            })
+            .await
+        }
+    });
+    sleep(Duration::from_millis(50)).await;
+
+    // One more waits in the queue, the next is rejected
+    let queued = tokio::spawn({
+        let pool = pool.clone();
+        async move { pool.run(|_| 42).await }
+    });
+    sleep(Duration::from_millis(50)).await;
+    assert!(matches!(pool.run(|_| 0).await, Err(Error::EvmQueueFull(1))));
+
+    running.abort();
+    assert!(running.await.unwrap_err().is_cancelled());
+    finished_rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("cancelled execution did not stop");
+    assert_eq!(queued.await.unwrap().unwrap(), 42);
+}
+
+#[tokio::test]
+async fn test_identical_estimates_are_coalesced_and_cached() {
+    let coalescer = EstimateCoalescer::new(CoalescerConfig {
+        result_ttl: Duration::from_millis(200),
+        max_results: 16,
+    });
+    let chain = mainnet_context(5);
+    let tx = Tx {
+        to: Some(Address::repeat_byte(0xb)),
         value: U256::ZERO,
-        data: Some(encoded_call_data.clone()),
-        nonce: Some(1),
-        chain_id: Some(U64::from(31337)), // Anvil default chain ID
-        gas_limit: None,
-        gas_price: Some(20000000000),
-        max_fee_per_gas: None,
-        max_priority_fee_per_gas: None,
-        access_list: None,
-        transaction_type: Some(U64::from(2)), // EIP-1559
-    };
-
-    // Test EVM-based estimation for contract call via RPC
-    let evm_call_estimate_3 = estimate_gas_via_rpc(&server_url, contract_call_tx.clone())
-        .await
-        .unwrap();
+        data: Some(Bytes::from(vec![0x12, 0x34])),
+        ..Default::default()
+    };
+    let key = EstimateKey::new(&tx, &chain, false);
+    let estimate = |estimated_gas| GasEstimate {
+        estimated_gas,
+        gas_price: 1,
+        total_cost_wei: estimated_gas,
+        total_cost_native: String::new(),
+        chain_id: 1,
+        breakdown: GasBreakdown {
+            base_cost: 21_000,
+            data_cost: 0,
+            contract_creation_cost: 0,
+            execution_cost: estimated_gas - 21_000,
+            l1_gas: None,
+            l1_data_fee: None,
+        },
+        verified: false,
+        simulation_rounds: 1,
+        padded_gas: estimated_gas,
+        execution: ExecutionSummary::default(),
+        failure: None,
+        comparison: None,
+        profile: None,
+    };
+    let slow_estimate = |estimated_gas| async move {
+        sleep(Duration::from_millis(50)).await;
+        Ok(estimate(estimated_gas))
+    };
 
-    // Test Alloy provider estimation for contract call
-    let alloy_call_tx = alloy::rpc::types::TransactionRequest::default()
-        .from(wallet.address())
-        .to(caller_contract_address)
-        .input(encoded_call_data.into());
+    // Requests in flight at the same time share the first one's computation
+    let (first, second) = tokio::join!(
+        coalescer.run(key.clone(), None, || slow_estimate(30_000)),
+        coalescer.run(key.clone(), None, || slow_estimate(40_000)),
+    );
+    assert_eq!(first.unwrap().estimated_gas, 30_000);
+    assert_eq!(second.unwrap().estimated_gas, 30_000);
+
+    // Later ones are served the result while it is fresh, bounded by the block time
+    let cached = coalescer.run(key.clone(), None, || slow_estimate(50_000));
+    assert_eq!(cached.await.unwrap().estimated_gas, 30_000);
+    sleep(Duration::from_millis(20)).await;
+    let block_time = Some(Duration::from_millis(10));
+    let recomputed = coalescer.run(key.clone(), block_time, || slow_estimate(60_000));
+    assert_eq!(recomputed.await.unwrap().estimated_gas, 60_000);
+
+    // Errors are shared but not cached
+    let other = EstimateKey::new(&tx, &mainnet_context(6), false);
+    let (first, second) = tokio::join!(
+        coalescer.run(other.clone(), None, || async {
+            sleep(Duration::from_millis(50)).await;
+            Err(Error::EvmQueueFull(1))
+        }),
+        coalescer.run(other.clone(), None, || slow_estimate(30_000)),
+    );
+    assert!(matches!(first.unwrap_err().root(), Error::EvmQueueFull(1)));
+    assert!(matches!(second.unwrap_err().root(), Error::EvmQueueFull(1)));
+
+    let stats = coalescer.stats();
+    assert_eq!((stats.executed, stats.coalesced), (3, 2));
+    assert_eq!((stats.results.hits, stats.results.misses), (1, 5));
+    assert_eq!(stats.in_flight, 0);
+}
+
+#[test]
+fn test_margin_policy() {
+    let policy: MarginPolicy = serde_json::from_value(json!({
+        "multiplier": 1.1,
+        "additive": 1000,
+        "floor": 30000,
+        "ceiling": 500000,
+        "rules": [
+            { "when": "cold_accesses", "additive": 50000 },
+            { "when": "refunds", "multiplier": 1.2 },
+        ],
+    }))
+    .unwrap();
+    policy.validate().unwrap();
+
+    let quiet = ExecutionSummary::default();
+    assert_eq!(policy.apply(100_000, &quiet, 30_000_000), 111_000);
+    // The floor and the ceiling bound the padded value, but never below the raw one
+    assert_eq!(policy.apply(21_000, &quiet, 30_000_000), 30_000);
+    assert_eq!(policy.apply(480_000, &quiet, 30_000_000), 500_000);
+    assert_eq!(policy.apply(600_000, &quiet, 30_000_000), 600_000);
+
+    let cold = ExecutionSummary {
+        cold_accesses: 3,
+        ..Default::default()
+    };
+    assert_eq!(policy.apply(100_000, &cold, 30_000_000), 161_000);
+    let cold_with_refund = ExecutionSummary {
+        gas_refunded: 4800,
+        ..cold
+    };
+    assert_eq!(
+        policy.apply(100_000, &cold_with_refund, 30_000_000),
+        183_000
+    );
+
+    // Without a policy the padded value is the raw one
+    assert_eq!(
+        MarginPolicy::default().apply(100_000, &cold, 30_000_000),
+        100_000
+    );
+
+    let shrinking = MarginPolicy {
+        multiplier: Some(0.9),
+        ..Default::default()
+    };
+    assert!(matches!(
+        shrinking.validate(),
+        Err(Error::InvalidMarginPolicy(_))
+    ));
+
+    // Compounding rules cannot overflow, the block gas limit bounds the padded value
+    let compounding = MarginPolicy {
+        multiplier: Some(10.0),
+        additive: u64::MAX,
+        rules: vec![
+            MarginRule {
+                when: MarginCondition::ColdAccesses,
+                multiplier: Some(10.0),
+                additive: u64::MAX,
+            };
+            40
+        ],
+        ..Default::default()
+    };
+    compounding.validate().unwrap();
+    assert_eq!(compounding.apply(100_000, &cold, 30_000_000), 30_000_000);
+    assert_eq!(compounding.apply(u128::MAX, &cold, 30_000_000), u128::MAX);
+}
 
-    let alloy_call_result = provider.estimate_gas(alloy_call_tx).await;
-    let alloy_call_estimate_3 = alloy_call_result.unwrap();
+#[tokio::test]
+async fn test_rpc_rejects_unbounded_margin_multiplier() {
+    let pool = Arc::new(ProviderPool::new(ProviderPoolConfig::default()).unwrap());
+    let rpc = GasEstimationRpcImpl::new(
+        Some("http://127.0.0.1:1".to_string()),
+        EstimatorConfig::default(),
+        pool,
+    );
+    let request: EstimateGasRequest = serde_json::from_value(json!({
+        "transaction": Tx {
+            to: Some(Address::repeat_byte(0xe)),
+            ..Default::default()
+        },
+        "rpc_url": null,
+        "margin": { "multiplier": 1e300 },
+    }))
+    .unwrap();
+
+    let error = rpc.estimate_gas(request).await.unwrap_err();
+    assert_eq!(error.code(), -32602);
+    assert!(
+        error.message().contains("multiplier"),
+        "{}",
+        error.message()
+    );
+}
+
+#[test]
+fn test_rpc_url_prefers_the_server_upstream_over_profiles() {
+    let pool = Arc::new(ProviderPool::new(ProviderPoolConfig::default()).unwrap());
+    let config = EstimatorConfig::default();
+    let server = "http://upstream.internal:8545".to_string();
+    let requested = "http://requested.internal:8545".to_string();
+
+    let rpc = GasEstimationRpcImpl::new(Some(server.clone()), config.clone(), pool.clone());
+    assert_eq!(rpc.rpc_url(Some(&requested), Some(1)).unwrap(), requested);
+    for chain_id in [Some(1), Some(31337), Some(10), None] {
+        assert_eq!(rpc.rpc_url(None, chain_id).unwrap(), server);
+    }
+
+    // Without a server upstream, the profile of the chain, mainnet's when none is named
+    let rpc = GasEstimationRpcImpl::new(None, config.clone(), pool);
+    assert_eq!(rpc.rpc_url(Some(&requested), Some(1)).unwrap(), requested);
+    for (chain_id, profile) in [(Some(31337), 31337), (Some(10), 10), (None, 1)] {
+        assert_eq!(
+            Some(rpc.rpc_url(None, chain_id).unwrap()),
+            config.chains.get(profile).default_rpc_url
+        );
+    }
+    assert_eq!(rpc.rpc_url(None, Some(999_999)).unwrap_err().code(), -32602);
+}
+
+/// Queue the upstream's answers to `GasEstimator::chain_context` for a mainnet block
+fn push_chain_head(asserter: &Asserter, block_number: u64) {
+    push_chain_head_of(asserter, 1, block_number);
+}
 
-    // Call 4 ------------------------------------------------------
-    let call_data = Caller::call_counterCall::new((counter_contract_address,));
-    let encoded_call_data = Bytes::from(call_data.abi_encode());
+fn push_chain_head_of(asserter: &Asserter, chain_id: u64, block_number: u64) {
+    let mut block = alloy::rpc::types::Block::<alloy::rpc::types::Transaction>::default();
+    block.header.hash = B256::with_last_byte(block_number as u8);
+    block.header.inner.number = block_number;
+    block.header.inner.timestamp = 1_750_000_000;
+    block.header.inner.gas_limit = 36_000_000;
+    asserter.push_success(&U64::from(chain_id));
+    asserter.push_success(&block);
+}
+
+#[tokio::test]
+async fn test_estimate_compared_with_upstream() {
+    let to = Address::repeat_byte(0xe);
+    let config = EstimatorConfig {
+        compare: true,
+        divergence: Arc::new(DivergenceMonitor::new(Some(5.0))),
+        ..Default::default()
+    };
+    let block_hash = B256::with_last_byte(7);
+    config.state_cache.insert_account(
+        1,
+        block_hash,
+        to,
+        CachedAccount {
+            balance: U256::ZERO,
+            nonce: 0,
+            code_hash: KECCAK_EMPTY,
+        },
+    );
+    let tx = Tx {
+        from: Some(Address::repeat_byte(0xa)),
+        to: Some(to),
+        value: U256::from(1),
+        ..Default::default()
+    };
+
+    // A plain transfer, where the upstream agrees
+    let asserter = Asserter::new();
+    push_chain_head(&asserter, 7);
+    asserter.push_success(&U256::from(1_000_000_000));
+    asserter.push_success(&U64::from(21_000));
+    let estimate = mocked_estimator(&asserter, &config)
+        .estimate_gas(tx.clone())
+        .await
+        .unwrap();
+    let comparison = estimate.comparison.unwrap();
+    assert_eq!(comparison.upstream_gas, Some(21_000));
+    assert_eq!(comparison.difference, Some(0));
+
+    // The same estimate, served from the result cache, against an upstream asking for more
+    let asserter = Asserter::new();
+    push_chain_head(&asserter, 7);
+    asserter.push_success(&U64::from(25_200));
+    let estimate = mocked_estimator(&asserter, &config)
+        .estimate_gas(tx)
+        .await
+        .unwrap();
+    let comparison = estimate.comparison.unwrap();
+    assert_eq!(comparison.difference, Some(-4_200));
+    assert_eq!(
+        comparison.difference_percent,
+        Some(-4_200.0 / 25_200.0 * 100.0)
+    );
+
+    let stats = config.divergence.stats();
+    assert_eq!((stats.compared, stats.divergent), (2, 1));
+
+    // Upstream failures are reported and counted, not divergent
+    let monitor = DivergenceMonitor::new(Some(5.0));
+    let failed = Comparison::new(21_000, Err("execution reverted".to_string()));
+    assert!(!monitor.record(&failed));
+    assert_eq!(monitor.stats().upstream_errors, 1);
+}
+
+#[tokio::test]
+async fn test_replay_applies_earlier_transactions_of_the_block() {
+    let sender = Address::repeat_byte(0xa);
+    let to = Address::repeat_byte(0xc);
+    // PUSH1 1 PUSH1 0 SSTORE STOP
+    let code = Bytes::from(vec![0x60, 0x01, 0x60, 0x00, 0x55, 0x00]);
+    let parent_hash = B256::repeat_byte(0x07);
+    let block_hash = B256::repeat_byte(0x08);
+    let tx_hash = B256::repeat_byte(0x11);
+
+    // At the parent block `to` is empty and the sender has not sent anything
+    let config = EstimatorConfig::default();
+    let empty = CachedAccount {
+        balance: U256::ZERO,
+        nonce: 0,
+        code_hash: KECCAK_EMPTY,
+    };
+    config.state_cache.insert_account(1, parent_hash, to, empty);
+    config
+        .state_cache
+        .insert_account(1, parent_hash, sender, empty);
+    config
+        .state_cache
+        .insert_storage(1, parent_hash, to, U256::ZERO, U256::ZERO);
+
+    let legacy = alloy::consensus::TxLegacy {
+        chain_id: Some(1),
+        nonce: 1,
+        gas_price: 1_000_000_000,
+        gas_limit: 50_000,
+        to: TxKind::Call(to),
+        value: U256::ZERO,
+        input: Bytes::new(),
+    };
+    let envelope = alloy::consensus::TxEnvelope::Legacy(alloy::consensus::Signed::new_unchecked(
+        legacy,
+        alloy::primitives::Signature::test_signature(),
+        tx_hash,
+    ));
+    let tx = alloy::rpc::types::Transaction {
+        inner: alloy::consensus::transaction::Recovered::new_unchecked(envelope, sender),
+        block_hash: Some(block_hash),
+        block_number: Some(22_500_001),
+        transaction_index: Some(1),
+        effective_gas_price: Some(1_000_000_000),
+    };
+    let mut parent = alloy::rpc::types::Block::<alloy::rpc::types::Transaction>::default();
+    parent.header.hash = parent_hash;
+    parent.header.inner.number = 22_500_000;
+    let mut block = parent.clone();
+    block.header.hash = block_hash;
+    block.header.inner.parent_hash = parent_hash;
+    block.header.inner.number = 22_500_001;
+    block.header.inner.timestamp = 1_750_000_000;
+    block.header.inner.gas_limit = 36_000_000;
+
+    let asserter = Asserter::new();
+    asserter.push_success(&tx);
+    asserter.push_success(&json!({
+        "transactionHash": tx_hash,
+        "transactionIndex": "0x1",
+        "blockHash": block_hash,
+        "blockNumber": "0x1574be1",
+        "from": sender,
+        "to": to,
+        "gasUsed": "0x5aa6",
+        "cumulativeGasUsed": "0x5aa6",
+        "effectiveGasPrice": "0x3b9aca00",
+        "contractAddress": null,
+        "logs": [],
+        "logsBloom": alloy::primitives::Bloom::ZERO,
+        "status": "0x1",
+        "type": "0x0",
+    }));
+    asserter.push_success(&U64::from(1));
+    asserter.push_success(&block);
+    asserter.push_success(&parent);
+    // The first transaction of the block funded the sender, deployed `to` and set its slot 0;
+    // the replayed one, second in the block, is traced as well
+    asserter.push_success(&json!([{
+        "txHash": B256::repeat_byte(0x10),
+        "result": {
+            "pre": { sender.to_string(): { "balance": "0x0" } },
+            "post": {
+                sender.to_string(): { "balance": "0xde0b6b3a7640000", "nonce": 1 },
+                to.to_string(): {
+                    "code": code,
+                    "storage": { B256::ZERO.to_string(): B256::with_last_byte(1) },
+                },
+            },
+        },
+    }, {
+        "txHash": tx_hash,
+        "result": { "pre": {}, "post": {} },
+    }]));
+    asserter.push_success(&U256::from(1_000_000_000));
+    asserter.push_success(&U256::from(1_000_000_000));
+
+    let report = mocked_estimator(&asserter, &config)
+        .replay_transaction(tx_hash)
+        .await
+        .unwrap();
+    assert!(asserter.read_q().is_empty());
+    assert_eq!(report.block_number, 22_500_001);
+    assert_eq!(report.transaction.nonce, Some(1));
+    // Rewriting slot 0 with the value the earlier transaction stored costs a warm no-op
+    // SSTORE after the cold access, not the 20000 of setting a zero slot
+    assert_eq!(report.estimate.estimated_gas, 21_000 + 3 + 3 + 2_100 + 100);
+    assert_eq!(report.gas_used, 23_206);
+    assert_eq!(report.difference, 0);
+    assert!(report.success && !report.underestimated);
+    // The earlier transactions' changes come without proofs
+    assert!(!report.estimate.verified);
+    // The parent's state was cached as fetched, without the overlay
+    assert_eq!(
+        config.state_cache.storage(1, parent_hash, to, U256::ZERO),
+        Some(U256::ZERO)
+    );
+}
 
-    let contract_call_tx = Tx {
-        from: Some(wallet.address()),
-        to: Some(caller_contract_address),
+#[tokio::test]
+async fn test_replay_executes_with_the_block_header_fields() {
+    let sender = Address::repeat_byte(0xa);
+    let to = Address::repeat_byte(0xc);
+    let coinbase = Address::repeat_byte(0xcb);
+    let parent_hash = B256::with_last_byte(7);
+    let block_hash = B256::with_last_byte(8);
+    let tx_hash = B256::repeat_byte(0x12);
+
+    // BASEFEE PUSH1 5 JUMPI STOP JUMPDEST COINBASE PUSH1 0 SSTORE STOP: stores the coinbase
+    // in slot 0, but only when the base fee is not zero
+    let config = EstimatorConfig::default();
+    cache_account(
+        &config,
+        7,
+        to,
+        U256::ZERO,
+        0,
+        &[
+            0x48, 0x60, 0x05, 0x57, 0x00, 0x5b, 0x41, 0x60, 0x00, 0x55, 0x00,
+        ],
+    );
+    cache_account(&config, 7, sender, U256::from(10u128.pow(18)), 0, &[]);
+    config
+        .state_cache
+        .insert_storage(1, parent_hash, to, U256::ZERO, U256::ZERO);
+
+    let legacy = alloy::consensus::TxLegacy {
+        chain_id: Some(1),
+        nonce: 0,
+        gas_price: 2_000_000_000,
+        gas_limit: 100_000,
+        to: TxKind::Call(to),
         value: U256::ZERO,
-        data: Some(encoded_call_data.clone()),
-        nonce: Some(1),
-        chain_id: Some(U64::from(31337)), // Anvil default chain ID
-        gas_limit: None,
-        gas_price: Some(20000000000),
-        max_fee_per_gas: None,
-        max_priority_fee_per_gas: None,
-        access_list: None,
-        transaction_type: Some(U64::from(2)), // EIP-1559
-    };
-
-    // Test EVM-based estimation for contract call via RPC
-    let evm_call_estimate_4 = estimate_gas_via_rpc(&server_url, contract_call_tx.clone())
+        input: Bytes::new(),
+    };
+    let envelope = alloy::consensus::TxEnvelope::Legacy(alloy::consensus::Signed::new_unchecked(
+        legacy,
+        alloy::primitives::Signature::test_signature(),
+        tx_hash,
+    ));
+    let tx = alloy::rpc::types::Transaction {
+        inner: alloy::consensus::transaction::Recovered::new_unchecked(envelope, sender),
+        block_hash: Some(block_hash),
+        block_number: Some(8),
+        transaction_index: Some(0),
+        effective_gas_price: Some(2_000_000_000),
+    };
+    let mut parent = alloy::rpc::types::Block::<alloy::rpc::types::Transaction>::default();
+    parent.header.hash = parent_hash;
+    parent.header.inner.number = 7;
+    let mut block = parent.clone();
+    block.header.hash = block_hash;
+    block.header.inner.parent_hash = parent_hash;
+    block.header.inner.number = 8;
+    block.header.inner.timestamp = 1_750_000_000;
+    block.header.inner.gas_limit = 36_000_000;
+    block.header.inner.base_fee_per_gas = Some(1_000_000_000);
+    block.header.inner.beneficiary = coinbase;
+
+    let asserter = Asserter::new();
+    asserter.push_success(&tx);
+    asserter.push_success(&json!({
+        "transactionHash": tx_hash,
+        "transactionIndex": "0x0",
+        "blockHash": block_hash,
+        "blockNumber": "0x8",
+        "from": sender,
+        "to": to,
+        "gasUsed": "0xa871",
+        "cumulativeGasUsed": "0xa871",
+        "effectiveGasPrice": "0x77359400",
+        "contractAddress": null,
+        "logs": [],
+        "logsBloom": alloy::primitives::Bloom::ZERO,
+        "status": "0x1",
+        "type": "0x0",
+    }));
+    asserter.push_success(&U64::from(1));
+    asserter.push_success(&block);
+    asserter.push_success(&parent);
+    asserter.push_success(&U256::from(2_000_000_000));
+    asserter.push_failure_msg("the method debug_traceCall does not exist");
+    asserter.push_success(&U256::from(2_000_000_000));
+
+    let report = mocked_estimator(&asserter, &config)
+        .replay_transaction(tx_hash)
         .await
         .unwrap();
+    assert!(asserter.read_q().is_empty());
+    // The base fee is read as the block's, so the coinbase is written to the zero slot
+    assert_eq!(
+        report.estimate.estimated_gas,
+        21_000 + 2 + 3 + 10 + 1 + 2 + 3 + 22_100
+    );
+    assert_eq!(report.gas_used, 43_121);
+    assert_eq!(report.difference, 0);
+}
 
-    // Test Alloy provider estimation for contract call
-    let alloy_call_tx = alloy::rpc::types::TransactionRequest::default()
-        .from(wallet.address())
-        .to(caller_contract_address)
-        .input(encoded_call_data.into());
+fn replayed(to: Address, data: &[u8], estimated_gas: u128, gas_used: u64) -> ReplayOutcome {
+    let transaction = Tx {
+        from: Some(Address::repeat_byte(0xa)),
+        to: Some(to),
+        value: U256::ZERO,
+        data: Some(Bytes::copy_from_slice(data)),
+        ..Default::default()
+    };
+    let estimate = GasEstimate {
+        estimated_gas,
+        gas_price: 0,
+        total_cost_wei: 0,
+        total_cost_native: String::new(),
+        chain_id: 1,
+        breakdown: GasBreakdown {
+            base_cost: 21_000,
+            data_cost: 0,
+            contract_creation_cost: 0,
+            execution_cost: estimated_gas - 21_000,
+            l1_gas: None,
+            l1_data_fee: None,
+        },
+        verified: false,
+        simulation_rounds: 1,
+        padded_gas: estimated_gas,
+        execution: ExecutionSummary::default(),
+        failure: None,
+        comparison: None,
+        profile: None,
+    };
+    let tx_hash = B256::repeat_byte(data.first().copied().unwrap_or_default());
+    ReplayOutcome {
+        tx_hash,
+        transaction: transaction.clone(),
+        report: Ok(ReplayReport::new(
+            tx_hash,
+            1,
+            0,
+            transaction,
+            estimate,
+            gas_used,
+            true,
+        )),
+    }
+}
 
-    let alloy_call_result = provider.estimate_gas(alloy_call_tx).await;
-    let alloy_call_estimate_4 = alloy_call_result.unwrap();
+#[test]
+fn test_accuracy_report() {
+    assert_eq!(sample_blocks(10, 14, None), vec![10, 11, 12, 13, 14]);
+    assert_eq!(sample_blocks(0, 99, Some(4)), vec![0, 25, 50, 75]);
+    assert_eq!(sample_blocks(10, 12, Some(10)), vec![10, 11, 12]);
+
+    let token = Address::repeat_byte(0x70);
+    let transfer = [0xa9, 0x05, 0x9c, 0xbb, 0x00];
+    let approve = [0x09, 0x5e, 0xa7, 0xb3];
+    let mut outcomes = vec![
+        replayed(token, &transfer, 50_000, 50_000),
+        replayed(token, &transfer, 45_000, 50_000),
+        replayed(token, &approve, 46_200, 44_000),
+        replayed(Address::repeat_byte(0xe), &[], 21_000, 21_000),
+    ];
+    let mut failed = replayed(token, &approve, 21_000, 21_000);
+    failed.report = Err(Error::BlockNotFound);
+    outcomes.push(failed);
+
+    let report = AccuracyReport::new(1, 2, 2, &outcomes, 2);
+    let overall = &report.overall;
+    assert_eq!((overall.transactions, overall.failed), (5, 1));
+    assert_eq!(overall.underestimates, 1);
+    assert_eq!(overall.underestimate_rate, 0.25);
+    // Errors of 0, -10, +5 and 0 percent
+    assert!((overall.mean_error_percent + 1.25).abs() < 1e-9);
+    assert!((overall.mean_abs_error_percent - 3.75).abs() < 1e-9);
+    assert_eq!(overall.p50_abs_error_percent, 0.0);
+    assert!((overall.p90_abs_error_percent - 10.0).abs() < 1e-9);
+    assert!((overall.max_abs_error_percent - 10.0).abs() < 1e-9);
+
+    // Grouped by contract and selector, underestimating groups first
+    assert_eq!(report.worst_offenders.len(), 2);
+    let worst = &report.worst_offenders[0];
+    assert_eq!(worst.to, Some(token));
+    assert_eq!(
+        worst.selector,
+        Some(FixedBytes::from([0xa9, 0x05, 0x9c, 0xbb]))
+    );
+    assert_eq!(
+        (worst.stats.transactions, worst.stats.underestimates),
+        (2, 1)
+    );
+    let second = &report.worst_offenders[1];
+    assert_eq!(second.selector, Some(FixedBytes::from(approve)));
+    assert_eq!((second.stats.transactions, second.stats.failed), (2, 1));
+
+    let csv = report.to_csv();
+    let rows: Vec<_> = csv.lines().collect();
+    assert_eq!(rows.len(), 4);
+    assert!(rows[0].starts_with("to,selector,transactions,failed,"));
+    assert!(rows[1].starts_with("all,all,5,1,-1.2500,3.7500,"));
+    assert!(rows[2].starts_with(&format!("{},0xa9059cbb,2,0,", token)));
+}
+
+fn sign_raw<T>(mut tx: T, signer: &PrivateKeySigner) -> Bytes
+where
+    T: SignableTransaction<alloy::primitives::Signature>,
+    TxEnvelope: From<Signed<T>>,
+{
+    let signature = signer.sign_transaction_sync(&mut tx).unwrap();
+    TxEnvelope::from(tx.into_signed(signature))
+        .encoded_2718()
+        .into()
+}
 
-    // Call 5 ------------------------------------------------------
-    let call_data = ERC20::transferCall::new((
-        address!("0x1234567890123456789012345678901234567890"),
-        U256::from(1),
+#[tokio::test]
+async fn test_raw_transactions_decoded_and_delegations_simulated() {
+    let signer = PrivateKeySigner::random();
+    let sender = signer.address();
+    let to = Address::repeat_byte(0xc);
+    let data = Bytes::from(vec![0xa9, 0x05, 0x9c, 0xbb]);
+
+    let legacy = Tx::from_raw(&sign_raw(
+        TxLegacy {
+            chain_id: Some(1),
+            nonce: 3,
+            gas_price: 7,
+            gas_limit: 50_000,
+            to: TxKind::Call(to),
+            value: U256::from(1),
+            input: data.clone(),
+        },
+        &signer,
+    ))
+    .unwrap();
+    assert_eq!(legacy.from, Some(sender));
+    assert_eq!((legacy.nonce, legacy.gas_price), (Some(3), Some(7)));
+    assert_eq!(legacy.data, Some(data.clone()));
+    assert_eq!(legacy.transaction_type, Some(U64::from(0)));
+
+    let access_list = AccessList(vec![AccessListItem {
+        address: to,
+        storage_keys: vec![B256::ZERO],
+    }]);
+    let eip2930 = Tx::from_raw(&sign_raw(
+        TxEip2930 {
+            chain_id: 1,
+            to: TxKind::Call(to),
+            access_list: access_list.clone(),
+            ..Default::default()
+        },
+        &signer,
+    ))
+    .unwrap();
+    assert_eq!(eip2930.access_list, Some(access_list));
+
+    let eip1559 = Tx::from_raw(&sign_raw(
+        TxEip1559 {
+            chain_id: 1,
+            max_fee_per_gas: 30,
+            max_priority_fee_per_gas: 2,
+            to: TxKind::Create,
+            ..Default::default()
+        },
+        &signer,
+    ))
+    .unwrap();
+    assert_eq!(eip1559.to, None);
+    assert_eq!(
+        (eip1559.max_fee_per_gas, eip1559.max_priority_fee_per_gas),
+        (Some(30), Some(2))
+    );
+    assert_eq!(eip1559.gas_price, None);
+
+    let blob_hash = B256::with_last_byte(1);
+    let eip4844 = Tx::from_raw(&sign_raw(
+        TxEip4844 {
+            chain_id: 1,
+            to,
+            blob_versioned_hashes: vec![blob_hash],
+            max_fee_per_blob_gas: 9,
+            ..Default::default()
+        },
+        &signer,
+    ))
+    .unwrap();
+    assert_eq!(eip4844.blob_versioned_hashes, Some(vec![blob_hash]));
+    assert_eq!(eip4844.max_fee_per_blob_gas, Some(9));
+
+    // An account delegating itself to a contract that sets its slot 0
+    let delegate = Address::repeat_byte(0xd);
+    let authorization = Authorization {
+        chain_id: U256::from(1),
+        address: delegate,
+        nonce: 1,
+    };
+    let authorization_signature = signer
+        .sign_hash_sync(&authorization.signature_hash())
+        .unwrap();
+    let eip7702 = Tx::from_raw(&sign_raw(
+        TxEip7702 {
+            chain_id: 1,
+            nonce: 0,
+            gas_limit: 100_000,
+            max_fee_per_gas: 1_000_000_000,
+            to: sender,
+            authorization_list: vec![authorization.into_signed(authorization_signature)],
+            ..Default::default()
+        },
+        &signer,
+    ))
+    .unwrap();
+    assert_eq!(eip7702.transaction_type, Some(U64::from(4)));
+    assert!(eip7702.has_authorizations());
+
+    assert!(matches!(
+        Tx::from_raw(&[0x02, 0xc0]),
+        Err(Error::InvalidRawTransaction(_))
+    ));
+    let mut trailing = sign_raw(TxLegacy::default(), &signer).to_vec();
+    trailing.push(0);
+    assert!(matches!(
+        Tx::from_raw(&trailing),
+        Err(Error::InvalidRawTransaction(_))
     ));
-    let encoded_call_data = Bytes::from(call_data.abi_encode());
 
-    let contract_call_tx = Tx {
-        from: Some(wallet.address()),
-        to: Some(erc20_contract_address),
-        value: U256::ZERO,
-        data: Some(encoded_call_data.clone()),
-        nonce: Some(1),
-        chain_id: Some(U64::from(31337)), // Anvil default chain ID
-        gas_limit: None,
-        gas_price: Some(20000000000),
-        max_fee_per_gas: None,
-        max_priority_fee_per_gas: None,
-        access_list: None,
-        transaction_type: Some(U64::from(2)), // EIP-1559
-    };
-
-    // Test EVM-based estimation for contract call via RPC
-    let evm_call_estimate_5 = estimate_gas_via_rpc(&server_url, contract_call_tx.clone())
+    // The sender has no code of its own, but the delegation makes the transaction run the delegate's
+    let config = EstimatorConfig::default();
+    let block_hash = B256::with_last_byte(7);
+    let code = Bytes::from(vec![0x60, 0x01, 0x60, 0x00, 0x55, 0x00]);
+    let cache = &config.state_cache;
+    cache.insert_account(
+        1,
+        block_hash,
+        sender,
+        CachedAccount {
+            balance: U256::from(10u128.pow(18)),
+            nonce: 0,
+            code_hash: KECCAK_EMPTY,
+        },
+    );
+    cache.insert_account(
+        1,
+        block_hash,
+        delegate,
+        CachedAccount {
+            balance: U256::ZERO,
+            nonce: 1,
+            code_hash: keccak256(&code),
+        },
+    );
+    cache.insert_bytecode(keccak256(&code), Bytecode::new_raw(code));
+    cache.insert_storage(1, block_hash, sender, U256::ZERO, U256::ZERO);
+
+    let asserter = Asserter::new();
+    push_chain_head(&asserter, 7);
+    asserter.push_success(&U256::from(1_000_000_000));
+    asserter.push_failure_msg("the method debug_traceCall does not exist");
+    asserter.push_success(&U256::from(1_000_000_000));
+    let estimate = mocked_estimator(&asserter, &config)
+        .estimate_gas(eip7702)
         .await
         .unwrap();
+    assert!(asserter.read_q().is_empty());
+    assert!(estimate.simulation_rounds > 0);
+    // The authorization of an existing account, then setting a zero slot, cold
+    assert_eq!(estimate.estimated_gas, 21_000 + 12_500 + 3 + 3 + 22_100);
+}
+
+#[test]
+fn test_calldata_encoded_from_function_and_json_args() {
+    let to = Address::repeat_byte(0xb);
+    let request: EstimateGasRequest = serde_json::from_value(json!({
+        "transaction": { "to": Address::repeat_byte(0xe), "value": "0x0" },
+        "function": "transfer(address,uint256)",
+        "args": [to, "1000000000000000000"],
+        "rpc_url": null,
+    }))
+    .unwrap();
+    let calldata = request
+        .function
+        .unwrap()
+        .encode_call(&request.args)
+        .unwrap();
+    let expected = ERC20::transferCall {
+        recipient: to,
+        amount: U256::from(10u128.pow(18)),
+    }
+    .abi_encode();
+    assert_eq!(calldata, Bytes::from(expected));
+
+    // A JSON ABI fragment with a tuple and an array, numbers and booleans given as JSON
+    let function: FunctionSpec = serde_json::from_value(json!({
+        "type": "function",
+        "name": "submit",
+        "stateMutability": "nonpayable",
+        "inputs": [
+            { "name": "order", "type": "tuple", "components": [
+                { "name": "maker", "type": "address" },
+                { "name": "amount", "type": "uint128" },
+            ]},
+            { "name": "ids", "type": "uint8[]" },
+            { "name": "strict", "type": "bool" },
+        ],
+        "outputs": [],
+    }))
+    .unwrap();
+    assert!(matches!(function, FunctionSpec::Abi(_)));
+    let calldata = function
+        .encode_call(&[json!([to, 5]), json!([1, "2"]), json!(true)])
+        .unwrap();
+    assert_eq!(
+        calldata[..4],
+        keccak256("submit((address,uint128),uint8[],bool)")[..4]
+    );
+    assert_eq!(calldata.len(), 4 + 32 * 7);
+
+    let transfer = FunctionSpec::Signature("transfer(address,uint256)".to_string());
+    for args in [
+        vec![json!(to)],
+        vec![json!(to), json!("-1")],
+        vec![json!(to), json!({})],
+    ] {
+        assert!(matches!(
+            transfer.encode_call(&args),
+            Err(Error::InvalidAbiCall(_))
+        ));
+    }
+    assert!(matches!(
+        FunctionSpec::Signature("transfer(address".to_string()).encode_call(&[]),
+        Err(Error::InvalidAbiCall(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_deployment_estimated_from_artifact() {
+    let artifact = Artifact::load("contracts/out/Counter.sol/Counter.json").unwrap();
+    let initcode = artifact.initcode(&[], &HashMap::new()).unwrap();
+    assert_eq!(
+        initcode,
+        Bytes::from_str(&artifact.bytecode.object).unwrap()
+    );
+    assert!(matches!(
+        artifact.initcode(&[json!(1)], &HashMap::new()),
+        Err(Error::InvalidAbiCall(_))
+    ));
+
+    // Library placeholders linked by name or by file and name, then constructor arguments
+    let library = Address::repeat_byte(0x1b);
+    let linked: Artifact = serde_json::from_value(json!({
+        "abi": [{
+            "type": "constructor",
+            "stateMutability": "nonpayable",
+            "inputs": [{ "name": "count", "type": "uint256" }],
+        }],
+        "bytecode": {
+            "object": format!("0x73__${}$__50", "a".repeat(34)),
+            "linkReferences": {
+                "src/Math.sol": { "Math": [{ "start": 1, "length": 20 }] },
+            },
+        },
+        "deployedBytecode": { "object": "0x00" },
+    }))
+    .unwrap();
+    for key in ["Math", "src/Math.sol:Math"] {
+        let libraries = HashMap::from([(key.to_string(), library)]);
+        let initcode = linked.initcode(&[json!("7")], &libraries).unwrap();
+        assert_eq!(initcode[0], 0x73);
+        assert_eq!(initcode[1..21], library[..]);
+        assert_eq!(initcode[21], 0x50);
+        assert_eq!(U256::from_be_slice(&initcode[22..]), U256::from(7));
+    }
+    assert!(matches!(
+        linked.initcode(&[json!("7")], &HashMap::new()),
+        Err(Error::InvalidArtifact(_))
+    ));
 
-    // Test Alloy provider estimation for contract call
-    let alloy_call_tx = alloy::rpc::types::TransactionRequest::default()
-        .from(wallet.address())
-        .to(erc20_contract_address)
-        .input(encoded_call_data.into());
+    // The CREATE is simulated, the contract landing at the sender's next nonce
+    let sender = Address::repeat_byte(0x5e);
+    let created = sender.create(5);
+    let config = EstimatorConfig::default();
+    let block_hash = B256::with_last_byte(7);
+    for (address, balance, nonce) in [
+        (sender, U256::from(10u128.pow(18)), 5),
+        (created, U256::ZERO, 0),
+    ] {
+        config.state_cache.insert_account(
+            1,
+            block_hash,
+            address,
+            CachedAccount {
+                balance,
+                nonce,
+                code_hash: KECCAK_EMPTY,
+            },
+        );
+    }
+    // The constructor sets slots 0 and 2
+    for slot in [0, 2] {
+        config
+            .state_cache
+            .insert_storage(1, block_hash, created, U256::from(slot), U256::ZERO);
+    }
+    let deployment = Deployment {
+        from: Some(sender),
+        ..Deployment::new(&artifact, &[], &HashMap::new()).unwrap()
+    };
 
-    let alloy_call_result = provider.estimate_gas(alloy_call_tx).await;
-    let alloy_call_estimate_5 = alloy_call_result.unwrap();
+    let asserter = Asserter::new();
+    push_chain_head(&asserter, 7);
+    asserter.push_success(&U256::from(1_000_000_000));
+    asserter.push_failure_msg("the method debug_traceCall does not exist");
+    asserter.push_success(&U256::from(1_000_000_000));
+    let report = mocked_estimator(&asserter, &config)
+        .estimate_deployment(deployment)
+        .await
+        .unwrap();
+    assert!(asserter.read_q().is_empty());
+    assert_eq!(report.address, created);
+    assert_eq!(report.initcode, initcode);
+    assert_eq!(report.runtime_code_size, artifact.runtime_code_size());
+    assert!(!report.exceeds_code_size_limit && !report.exceeds_initcode_size_limit);
+    assert_eq!(report.estimate.breakdown.contract_creation_cost, 32_000);
+
+    // Same gas as running the CREATE directly, which deploys the artifact's runtime code
+    let mut cache_db = CacheDB::new(EmptyDB::default());
+    cache_db.insert_account_info(
+        sender,
+        AccountInfo {
+            balance: U256::from(10u128.pow(18)),
+            nonce: 5,
+            ..Default::default()
+        },
+    );
+    let tx = TxEnvBuilder::new()
+        .caller(sender)
+        .kind(TxKind::Create)
+        .data(initcode)
+        .nonce(5)
+        .gas_limit(1_000_000)
+        .gas_price(1_000_000_000)
+        .build()
+        .unwrap();
+    let result = Context::mainnet()
+        .with_db(cache_db)
+        .modify_cfg_chained(|cfg| cfg.spec = SpecId::PRAGUE)
+        .build_mainnet()
+        .transact(tx)
+        .unwrap();
+    let Some(runtime_code) = result.output() else {
+        panic!("creation failed: {result:?}");
+    };
+    assert_eq!(runtime_code.len(), report.runtime_code_size);
+    assert_eq!(report.estimate.estimated_gas, result.gas_used() as u128);
+}
 
-    // Summary and comparison
-    println!("\nSummary Comparison:");
-    println!("Simple Transfer:");
-    println!("  - EVM-based (via RPC): {} gas", evm_transfer_estimate);
-    println!("  - Alloy Provider: {} gas", alloy_transfer_estimate);
+#[tokio::test]
+async fn test_deployment_with_reverting_constructor_fails() {
+    // PUSH1 0 PUSH1 0 REVERT
+    let artifact: Artifact = serde_json::from_value(json!({
+        "abi": [],
+        "bytecode": { "object": "0x60006000fd" },
+        "deployedBytecode": { "object": "0x" },
+    }))
+    .unwrap();
+    let sender = Address::repeat_byte(0x5e);
+    let created = sender.create(5);
+    let config = EstimatorConfig::default();
+    cache_account(&config, 7, sender, U256::from(10u128.pow(18)), 5, &[]);
+    cache_account(&config, 7, created, U256::ZERO, 0, &[]);
+    let deployment = Deployment {
+        from: Some(sender),
+        ..Deployment::new(&artifact, &[], &HashMap::new()).unwrap()
+    };
 
-    println!("Contract Deployment:");
-    println!("  - EVM-based (via RPC): {} gas", evm_deploy_estimate);
-    println!("  - Alloy Provider: {} gas", alloy_deploy_estimate);
+    let asserter = Asserter::new();
+    push_simulated_estimate(&asserter, 7);
+    let error = mocked_estimator(&asserter, &config)
+        .estimate_deployment(deployment)
+        .await
+        .unwrap_err();
+    assert!(asserter.read_q().is_empty());
+    assert!(
+        matches!(
+            error.root(),
+            Error::DeploymentFailed { address, reason }
+                if *address == created && reason == "reverted"
+        ),
+        "{error}"
+    );
+}
 
-    println!("Contract Call 1:");
-    println!("  - EVM-based (via RPC): {} gas", evm_call_estimate_1);
-    println!("  - Alloy Provider: {} gas", alloy_call_estimate_1);
+#[tokio::test]
+async fn test_deployment_address_at_pinned_block_nonce() {
+    // STOP, deploying no code
+    let artifact: Artifact = serde_json::from_value(json!({
+        "abi": [],
+        "bytecode": { "object": "0x00" },
+        "deployedBytecode": { "object": "0x" },
+    }))
+    .unwrap();
+    // At block 7 the sender had sent 3 transactions, whatever it has sent since
+    let sender = Address::repeat_byte(0x5e);
+    let created = sender.create(3);
+    let config = EstimatorConfig {
+        block: Some(7),
+        ..Default::default()
+    };
+    cache_account(&config, 7, sender, U256::from(10u128.pow(18)), 3, &[]);
+    cache_account(&config, 7, created, U256::ZERO, 0, &[]);
+    let deployment = Deployment {
+        from: Some(sender),
+        ..Deployment::new(&artifact, &[], &HashMap::new()).unwrap()
+    };
 
-    println!("Contract Call 2:");
-    println!("  - EVM-based (via RPC): {} gas", evm_call_estimate_2);
-    println!("  - Alloy Provider: {} gas", alloy_call_estimate_2);
+    let asserter = Asserter::new();
+    let mut block = alloy::rpc::types::Block::<alloy::rpc::types::Transaction>::default();
+    block.header.hash = B256::with_last_byte(7);
+    block.header.inner.number = 7;
+    block.header.inner.timestamp = 1_750_000_000;
+    block.header.inner.gas_limit = 36_000_000;
+    block.header.inner.base_fee_per_gas = Some(1_000_000_000);
+    asserter.push_success(&U64::from(1));
+    asserter.push_success(&block);
+    asserter.push_failure_msg("the method debug_traceCall does not exist");
+    let report = mocked_estimator(&asserter, &config)
+        .estimate_deployment(deployment)
+        .await
+        .unwrap();
+    // Neither the nonce nor the head of the chain was asked for
+    assert!(asserter.read_q().is_empty());
+    assert_eq!(report.address, created);
+    assert_eq!(report.estimate.breakdown.contract_creation_cost, 32_000);
+}
 
-    println!("Contract Call 3:");
-    println!("  - EVM-based (via RPC): {} gas", evm_call_estimate_3);
-    println!("  - Alloy Provider: {} gas", alloy_call_estimate_3);
+#[tokio::test]
+async fn test_gas_profiled_by_solidity_function_and_line() {
+    let artifact = Artifact::load("contracts/out/Counter.sol/Counter.json").unwrap();
+    let source_path = artifact.source_path().unwrap();
+    assert_eq!(source_path, "contracts/src/Counter.sol");
+    let sources = HashMap::from([(
+        source_path.clone(),
+        std::fs::read_to_string(&source_path).unwrap(),
+    )]);
+
+    // Counter with offset = 42 and n = 10, calling complex()
+    let sender = Address::repeat_byte(0x5e);
+    let counter = Address::repeat_byte(0xc0);
+    let code = artifact.deployed_bytecode.link(&HashMap::new()).unwrap();
+    let config = EstimatorConfig {
+        profile: Some(counter),
+        ..Default::default()
+    };
+    let block_hash = B256::with_last_byte(7);
+    let cache = &config.state_cache;
+    for (address, balance, code_hash) in [
+        (sender, U256::from(10u128.pow(18)), KECCAK_EMPTY),
+        (counter, U256::ZERO, keccak256(&code)),
+    ] {
+        cache.insert_account(
+            1,
+            block_hash,
+            address,
+            CachedAccount {
+                balance,
+                nonce: 1,
+                code_hash,
+            },
+        );
+    }
+    cache.insert_bytecode(keccak256(&code), Bytecode::new_raw(code));
+    for (slot, value) in [(0u64, 42u64), (1, 0), (2, 10), (3, 0)] {
+        cache.insert_storage(1, block_hash, counter, U256::from(slot), U256::from(value));
+    }
+    let tx = Tx {
+        from: Some(sender),
+        to: Some(counter),
+        value: U256::ZERO,
+        data: Some(Bytes::from(Counter::complexCall {}.abi_encode())),
+        ..Default::default()
+    };
 
-    println!("Contract Call 4:");
-    println!("  - EVM-based (via RPC): {} gas", evm_call_estimate_4);
-    println!("  - Alloy Provider: {} gas", alloy_call_estimate_4);
+    let asserter = Asserter::new();
+    push_chain_head(&asserter, 7);
+    asserter.push_success(&U256::from(1_000_000_000));
+    asserter.push_failure_msg("the method debug_traceCall does not exist");
+    asserter.push_success(&U256::from(1_000_000_000));
+    let estimate = mocked_estimator(&asserter, &config)
+        .estimate_gas(tx)
+        .await
+        .unwrap();
+    assert!(asserter.read_q().is_empty());
+    let profile = estimate.profile.unwrap();
+    assert_eq!(profile.code_address, counter);
+
+    // Everything but the intrinsic cost runs the contract's code
+    let report = SourceProfile::new(&profile, &artifact, &sources).unwrap();
+    assert_eq!(
+        report.total_gas as u128,
+        estimate.estimated_gas - 21_000 - 16 * 4
+    );
+    assert!(report.unmapped_gas < report.total_gas / 10);
+    assert_eq!(report.functions[0].function, "Counter.complex");
+    let functions_gas: u64 = report.functions.iter().map(|function| function.gas).sum();
+    let lines_gas: u64 = report.lines.iter().map(|line| line.gas).sum();
+    assert_eq!(functions_gas, report.total_gas - report.unmapped_gas);
+    assert_eq!(lines_gas, functions_gas);
+
+    // Storing the result in the zero slot `f` costs the most
+    let top = &report.lines[0];
+    assert_eq!(
+        (top.file.as_str(), top.line),
+        ("contracts/src/Counter.sol", 25)
+    );
+    assert_eq!(top.source, "f = sequence[n];");
+    assert_eq!(top.function.as_deref(), Some("Counter.complex"));
+    assert!(top.gas >= 22_100);
+
+    // Artifacts built with an AST name functions from it instead of the source
+    let source = &sources[&source_path];
+    let start = source.find("function complex").unwrap();
+    let length = source[start..].find("\n    }\n").unwrap() + "\n    }".len();
+    let with_ast = Artifact {
+        ast: Some(json!({
+            "nodeType": "SourceUnit",
+            "src": format!("0:{}:20", source.len()),
+            "absolutePath": source_path,
+            "nodes": [{
+                "nodeType": "ContractDefinition",
+                "name": "Counter",
+                "src": "65:591:20",
+                "nodes": [{
+                    "nodeType": "FunctionDefinition",
+                    "name": "complex",
+                    "kind": "function",
+                    "src": format!("{start}:{length}:20"),
+                }],
+            }],
+        })),
+        ..artifact.clone()
+    };
+    assert_eq!(
+        SourceProfile::new(&profile, &with_ast, &sources).unwrap(),
+        report
+    );
+}
 
-    println!("Contract Call 5:");
-    println!("  - EVM-based (via RPC): {} gas", evm_call_estimate_5);
-    println!("  - Alloy Provider: {} gas", alloy_call_estimate_5);
+#[tokio::test]
+async fn test_gas_snapshot_of_spec_matches_committed_snapshot() {
+    let spec = SnapshotSpec::load("contracts/gas-snapshot.toml").unwrap();
+    let snapshot = take_snapshot(&spec, Path::new("contracts")).await.unwrap();
+    let committed = std::fs::read_to_string("contracts/.gas-snapshot").unwrap();
+    assert_eq!(snapshot.to_string(), committed);
+
+    // The deployment runs the constructor's two fresh SSTOREs, the call sees Counter's address
+    let counter = snapshot.0["Counter:constructor()"];
+    assert!(counter > 53_000 + 2 * 22_100);
+    assert!(snapshot.0.contains_key("Caller:call_counter(address)"));
+}
 
-    println!("\nAll gas estimation approaches tested successfully!");
+#[test]
+fn test_gas_snapshot_compared_within_tolerance() {
+    let previous = GasSnapshot::parse(
+        "Counter:complex() (gas: 50000)\n\
+         Counter:constructor() (gas: 220000)\n\
+         Counter:setNumber(uint256) (gas: 45000)\n",
+    )
+    .unwrap();
+    assert_eq!(GasSnapshot::parse(&previous.to_string()).unwrap(), previous);
+    assert!(GasSnapshot::parse("Counter:complex() 50000").is_err());
+
+    let current = GasSnapshot::parse(
+        "Counter:complex() (gas: 51000)\n\
+         Counter:constructor() (gas: 220100)\n\
+         Counter:number() (gas: 23000)\n",
+    )
+    .unwrap();
+    let diff = current.compare(&previous, 1.0);
+    assert_eq!(
+        diff.regressions,
+        vec![GasChange {
+            name: "Counter:complex()".to_string(),
+            previous: 50_000,
+            current: 51_000,
+        }]
+    );
+    assert!(diff.improvements.is_empty());
+    assert_eq!(diff.added, vec!["Counter:number()".to_string()]);
+    assert_eq!(diff.removed, vec!["Counter:setNumber(uint256)".to_string()]);
+
+    // A 2% increase passes a 2% tolerance, and any change fails none
+    assert!(current.compare(&previous, 2.0).regressions.is_empty());
+    assert_eq!(current.compare(&previous, 0.0).regressions.len(), 2);
 }
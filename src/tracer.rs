@@ -1,39 +1,119 @@
+use crate::profile::ExecutionProfile;
 use revm::bytecode::opcode;
 use revm::context::ContextTr;
 use revm::inspector::JournalExt;
-use revm::interpreter::interpreter_types::Jumps;
-use revm::interpreter::{CallInputs, CallOutcome, Interpreter};
+use revm::interpreter::interpreter_types::{InputsTr, Jumps};
+use revm::interpreter::{
+    CallInputs, CallOutcome, CreateInputs, CreateOutcome, InstructionResult, Interpreter,
+};
 use revm::primitives::B256;
 use revm::{
+    database::{CacheDB, EmptyDB},
     inspector::Inspector,
     primitives::{Address, U256},
 };
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
+/// Collects the state a simulation round touched that has not been loaded yet
 #[derive(Debug, Clone)]
 pub struct Tracer {
+    /// Accounts whose balance, nonce or code the transaction reads
     pub contract_addresses: HashSet<Address>,
-    pub storage_accesses: HashMap<Address, U256>,
-    address_stack: Vec<Address>,
+    /// Storage slots read or written, keyed by the account that owns the storage
+    pub storage_accesses: HashSet<(Address, U256)>,
+    /// Accounts and storage slots this round's execution touched, loaded before or not
+    pub touched_accounts: HashSet<Address>,
+    pub touched_slots: HashSet<(Address, U256)>,
+    /// Calls contracts made this round, on top of the transaction's own call
+    pub external_calls: usize,
+    call_depth: usize,
     // Keep track of historical accesses
-    storage_access_archive: HashMap<Address, U256>,
+    storage_access_archive: HashSet<(Address, U256)>,
     contract_addresses_archive: HashSet<Address>,
+    /// Steps executed this round, and how many are allowed before the round is halted
+    steps: u64,
+    max_steps: u64,
+    /// Raised when the estimate is no longer wanted, halting execution
+    cancelled: Option<Arc<AtomicBool>>,
+    /// Gas this round spent running the profiled code, by program counter
+    pub profile: Option<ExecutionProfile>,
+    /// Frames running, innermost last
+    profile_frames: Vec<ProfiledFrame>,
+}
+
+/// A running frame, and the last instruction it ran when it runs the profiled code.
+/// That instruction's gas is known once the frame moves on to the next one, or ends.
+#[derive(Debug, Clone, Default)]
+struct ProfiledFrame {
+    profiled: bool,
+    pending: Option<(usize, u64)>,
 }
 
 impl Tracer {
     pub fn new() -> Self {
         Self {
             contract_addresses: HashSet::new(),
-            storage_accesses: HashMap::new(),
-            address_stack: Vec::new(),
+            storage_accesses: HashSet::new(),
+            touched_accounts: HashSet::new(),
+            touched_slots: HashSet::new(),
+            external_calls: 0,
+            call_depth: 0,
 
-            storage_access_archive: HashMap::new(),
+            storage_access_archive: HashSet::new(),
             contract_addresses_archive: HashSet::new(),
+            steps: 0,
+            max_steps: u64::MAX,
+            cancelled: None,
+            profile: None,
+            profile_frames: Vec::new(),
+        }
+    }
+
+    /// Record the gas spent by each instruction of the code at `code_address`
+    pub fn with_profile(mut self, code_address: Option<Address>) -> Self {
+        self.profile = code_address.map(ExecutionProfile::new);
+        self
+    }
+
+    /// Halt execution once a round runs more than `max_steps` EVM steps
+    pub fn with_step_limit(mut self, max_steps: u64) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Halt execution once `cancelled` is raised
+    pub fn set_cancellation(&mut self, cancelled: Arc<AtomicBool>) {
+        self.cancelled = Some(cancelled);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled
+            .as_ref()
+            .is_some_and(|cancelled| cancelled.load(Ordering::Relaxed))
+    }
+
+    pub fn step_limit_exceeded(&self) -> bool {
+        self.steps > self.max_steps
+    }
+
+    /// Treat the accounts and storage slots already in `cache_db` as loaded,
+    /// so rounds only report the state missing from it
+    pub fn mark_loaded(&mut self, cache_db: &CacheDB<EmptyDB>) {
+        for (address, account) in &cache_db.cache.accounts {
+            self.contract_addresses_archive.insert(*address);
+            self.storage_access_archive
+                .extend(account.storage.keys().map(|slot| (*address, *slot)));
         }
     }
 
     pub fn has_new_accesses(&self) -> bool {
-        self.contract_addresses.len() > 0 || self.storage_accesses.len() > 0
+        !self.contract_addresses.is_empty() || !self.storage_accesses.is_empty()
     }
 
     pub fn reset_state(&mut self) {
@@ -44,6 +124,69 @@ impl Tracer {
 
         self.storage_accesses.clear();
         self.contract_addresses.clear();
+        self.touched_accounts.clear();
+        self.touched_slots.clear();
+        self.external_calls = 0;
+        self.steps = 0;
+        if let Some(profile) = &mut self.profile {
+            profile.pcs.clear();
+        }
+        self.profile_frames.clear();
+    }
+
+    fn enter_frame(&mut self, code_address: Option<Address>) {
+        let profiled = self
+            .profile
+            .as_ref()
+            .is_some_and(|profile| Some(profile.code_address) == code_address);
+        self.profile_frames.push(ProfiledFrame {
+            profiled,
+            pending: None,
+        });
+    }
+
+    /// Charge the last instruction of the ending frame with the gas it left
+    fn exit_frame(&mut self, gas_remaining: u64) {
+        if let Some(ProfiledFrame {
+            pending: Some((pc, gas_before)),
+            ..
+        }) = self.profile_frames.pop()
+        {
+            self.record_gas(pc, gas_before.saturating_sub(gas_remaining));
+        }
+    }
+
+    fn profile_step(&mut self, pc: usize, gas_remaining: u64) {
+        let Some(frame) = self.profile_frames.last_mut() else {
+            return;
+        };
+        let pending = frame.pending.take();
+        if frame.profiled {
+            frame.pending = Some((pc, gas_remaining));
+        }
+        if let Some((pending_pc, gas_before)) = pending {
+            self.record_gas(pending_pc, gas_before.saturating_sub(gas_remaining));
+        }
+    }
+
+    fn record_gas(&mut self, pc: usize, gas: u64) {
+        if let Some(profile) = &mut self.profile {
+            profile.record(pc, gas);
+        }
+    }
+
+    fn record_account(&mut self, address: Address) {
+        self.touched_accounts.insert(address);
+        if !self.contract_addresses_archive.contains(&address) {
+            self.contract_addresses.insert(address);
+        }
+    }
+
+    fn record_slot(&mut self, address: Address, slot: U256) {
+        self.touched_slots.insert((address, slot));
+        if !self.storage_access_archive.contains(&(address, slot)) {
+            self.storage_accesses.insert((address, slot));
+        }
     }
 }
 
@@ -57,45 +200,71 @@ impl<CTX> Inspector<CTX> for Tracer
 where
     CTX: ContextTr<Journal: JournalExt>,
 {
-    // IMPROVEMENT:
-    // Since CTX is available on every call
-    // We can dinamically fetch account code and balance to populate DB
     fn call(&mut self, _context: &mut CTX, inputs: &mut CallInputs) -> Option<CallOutcome> {
-        if !self
-            .contract_addresses_archive
-            .contains(&inputs.target_address)
-        {
-            self.contract_addresses.insert(inputs.target_address);
+        // Under DELEGATECALL and CALLCODE the code runs from `bytecode_address`
+        // against the storage and balance of `target_address`, so both are needed
+        self.record_account(inputs.target_address);
+        self.record_account(inputs.bytecode_address);
+        if self.call_depth > 0 {
+            self.external_calls += 1;
         }
-        self.address_stack.push(inputs.target_address);
+        self.call_depth += 1;
+        self.enter_frame(Some(inputs.bytecode_address));
+        None
+    }
+
+    fn call_end(&mut self, _context: &mut CTX, _inputs: &CallInputs, outcome: &mut CallOutcome) {
+        self.call_depth = self.call_depth.saturating_sub(1);
+        self.exit_frame(outcome.result.gas.remaining());
+    }
+
+    fn create(&mut self, _context: &mut CTX, _inputs: &mut CreateInputs) -> Option<CreateOutcome> {
+        self.enter_frame(None);
         None
     }
 
-    fn call_end(&mut self, _context: &mut CTX, _inputs: &CallInputs, _outcome: &mut CallOutcome) {
-        self.address_stack.pop();
+    fn create_end(
+        &mut self,
+        _context: &mut CTX,
+        _inputs: &CreateInputs,
+        outcome: &mut CreateOutcome,
+    ) {
+        self.exit_frame(outcome.result.gas.remaining());
+        // The created address may already hold a balance, or collide with existing code
+        if let Some(address) = outcome.address {
+            self.record_account(address);
+        }
+    }
+
+    fn selfdestruct(&mut self, _contract: Address, target: Address, _value: U256) {
+        self.record_account(target);
     }
 
-    // IMPROVEMENT:
-    // Since CTX is available on every call
-    // We can dinamically fetch storage variables and populate the DB
     fn step(&mut self, interpreter: &mut Interpreter, _context: &mut CTX) {
-        // Get the current opcode from the bytecode
+        self.steps += 1;
+        if self.step_limit_exceeded() || self.is_cancelled() {
+            // Every frame still running halts on its next step, unwinding the whole call
+            interpreter.halt(InstructionResult::OutOfGas);
+            return;
+        }
+        if self.profile.is_some() {
+            self.profile_step(interpreter.bytecode.pc(), interpreter.gas.remaining());
+        }
+        // Storage belongs to the account the frame runs as, which is not the code address under DELEGATECALL
+        let storage_owner = interpreter.input.target_address();
+        let operand = |index| interpreter.stack.peek(index).ok();
         match interpreter.bytecode.opcode() {
-            // SLOAD - Load from storage (opcode 0x54)
-            opcode::SLOAD => {
-                if let Ok(slot) = interpreter.stack.peek(0) {
-                    if let Some(address) = self.address_stack.last() {
-                        if !self.storage_access_archive.contains_key(address) {
-                            self.storage_accesses.insert(*address, slot);
-                        }
-                    }
+            opcode::SLOAD | opcode::SSTORE => {
+                if let Some(slot) = operand(0) {
+                    self.record_slot(storage_owner, slot);
                 }
             }
-            opcode::DELEGATECALL | opcode::CALL | opcode::STATICCALL | opcode::CALLCODE => {
-                let slot = interpreter.stack.peek(1).unwrap();
-                let addr = Address::from_word(B256::from(slot.to_be_bytes()));
-                println!("Calling address: {:?}", addr);
+            opcode::BALANCE | opcode::EXTCODESIZE | opcode::EXTCODEHASH | opcode::EXTCODECOPY => {
+                if let Some(word) = operand(0) {
+                    self.record_account(Address::from_word(B256::from(word.to_be_bytes())));
+                }
             }
+            opcode::SELFBALANCE => self.record_account(storage_owner),
             _ => {}
         }
     }
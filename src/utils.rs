@@ -1,31 +1,227 @@
-use revm::primitives::Bytes;
+use crate::chain_profile::IntrinsicGas;
+use alloy::eips::eip2930::AccessList;
+use revm::{
+    interpreter::gas::{
+        calc_tx_floor_cost, get_tokens_in_calldata, ACCESS_LIST_ADDRESS, ACCESS_LIST_STORAGE_KEY,
+    },
+    precompile::{
+        bls12_381_const::{
+            DISCOUNT_TABLE_G1_MSM, DISCOUNT_TABLE_G2_MSM, G1_ADD_ADDRESS, G1_ADD_BASE_GAS_FEE,
+            G1_MSM_ADDRESS, G1_MSM_BASE_GAS_FEE, G1_MSM_INPUT_LENGTH, G2_ADD_ADDRESS,
+            G2_ADD_BASE_GAS_FEE, G2_MSM_ADDRESS, G2_MSM_BASE_GAS_FEE, G2_MSM_INPUT_LENGTH,
+            MAP_FP2_TO_G2_ADDRESS, MAP_FP2_TO_G2_BASE_GAS_FEE, MAP_FP_TO_G1_ADDRESS,
+            MAP_FP_TO_G1_BASE_GAS_FEE, PAIRING_ADDRESS, PAIRING_INPUT_LENGTH,
+            PAIRING_MULTIPLIER_BASE, PAIRING_OFFSET_BASE,
+        },
+        bls12_381_utils::msm_required_gas,
+        u64_to_address,
+    },
+    primitives::{hardfork::SpecId, Address, Bytes, U256},
+};
 
 /// Calculate gas cost for calldata (transaction input data)
-pub fn calculate_calldata_cost(data: &Bytes) -> u128 {
-    let mut cost = 0;
-
-    for byte in data.iter() {
-        if *byte == 0 {
-            // Zero bytes cost 4 gas each
-            cost += 4;
-        } else {
-            // Non-zero bytes cost 16 gas each
-            cost += 16;
+pub fn calculate_calldata_cost(data: &Bytes, intrinsic_gas: &IntrinsicGas, spec: SpecId) -> u128 {
+    let (zero_byte_cost, non_zero_byte_cost) = intrinsic_gas.calldata_byte_costs(spec);
+    let zero_bytes = data.iter().filter(|byte| **byte == 0).count() as u128;
+    let non_zero_bytes = data.len() as u128 - zero_bytes;
+    zero_bytes * zero_byte_cost as u128 + non_zero_bytes * non_zero_byte_cost as u128
+}
+
+/// Intrinsic gas of the addresses and storage keys of an EIP-2930 access list
+pub fn access_list_cost(access_list: Option<&AccessList>) -> u128 {
+    access_list.map_or(0, |access_list| {
+        access_list
+            .iter()
+            .map(|item| {
+                ACCESS_LIST_ADDRESS as u128
+                    + ACCESS_LIST_STORAGE_KEY as u128 * item.storage_keys.len() as u128
+            })
+            .sum()
+    })
+}
+
+/// EIP-7623 floor on the gas of a transaction with `data`, from Prague
+pub fn calldata_floor(data: &[u8], spec: SpecId) -> u128 {
+    if !spec.is_enabled_in(SpecId::PRAGUE) {
+        return 0;
+    }
+    calc_tx_floor_cost(get_tokens_in_calldata(data, true)) as u128
+}
+
+/// Number of 32 byte words needed to hold `len` bytes
+fn words(len: usize) -> u64 {
+    len.div_ceil(32) as u64
+}
+
+/// ecrecover (0x01): flat cost
+pub fn ecrecover_gas() -> u64 {
+    3_000
+}
+
+/// sha256 (0x02): 60 + 12 per word
+pub fn sha256_gas(input: &[u8]) -> u64 {
+    60 + 12 * words(input.len())
+}
+
+/// ripemd160 (0x03): 600 + 120 per word
+pub fn ripemd160_gas(input: &[u8]) -> u64 {
+    600 + 120 * words(input.len())
+}
+
+/// identity (0x04): 15 + 3 per word
+pub fn identity_gas(input: &[u8]) -> u64 {
+    15 + 3 * words(input.len())
+}
+
+/// modexp (0x05) with the EIP-2565 pricing
+pub fn modexp_gas(input: &[u8]) -> u64 {
+    // Input is right padded with zeros when shorter than the header
+    let word_at = |offset: usize| -> U256 {
+        let mut word = [0u8; 32];
+        for (i, byte) in word.iter_mut().enumerate() {
+            *byte = input.get(offset + i).copied().unwrap_or_default();
         }
+        U256::from_be_bytes(word)
+    };
+    let (Ok(base_len), Ok(exp_len), Ok(mod_len)) = (
+        u64::try_from(word_at(0)),
+        u64::try_from(word_at(32)),
+        u64::try_from(word_at(64)),
+    ) else {
+        return u64::MAX;
+    };
+
+    // First (at most) 32 bytes of the exponent, as a big-endian number
+    let exp_head_len = exp_len.min(32) as usize;
+    let exp_head = (base_len as usize)
+        .checked_add(96)
+        .map(|exp_offset| {
+            let mut head = [0u8; 32];
+            for (i, byte) in head[32 - exp_head_len..].iter_mut().enumerate() {
+                *byte = input.get(exp_offset + i).copied().unwrap_or_default();
+            }
+            U256::from_be_bytes(head)
+        })
+        .unwrap_or_default();
+
+    let exp_head_bits = exp_head.bit_len() as u64;
+    let iteration_count = if exp_len <= 32 {
+        exp_head_bits.saturating_sub(1)
+    } else {
+        (8 * (exp_len - 32)).saturating_add(exp_head_bits.saturating_sub(1))
+    }
+    .max(1);
+
+    let words = base_len.max(mod_len).div_ceil(8) as u128;
+    let multiplication_complexity = words * words;
+    let gas = multiplication_complexity.saturating_mul(iteration_count as u128) / 3;
+    u64::try_from(gas).unwrap_or(u64::MAX).max(200)
+}
+
+/// ecAdd (0x06), repriced by EIP-1108 in Istanbul
+pub fn ec_add_gas(spec: SpecId) -> u64 {
+    if spec.is_enabled_in(SpecId::ISTANBUL) {
+        150
+    } else {
+        500
     }
+}
 
-    cost
+/// ecMul (0x07), repriced by EIP-1108 in Istanbul
+pub fn ec_mul_gas(spec: SpecId) -> u64 {
+    if spec.is_enabled_in(SpecId::ISTANBUL) {
+        6_000
+    } else {
+        40_000
+    }
 }
 
-/// Calculate contract creation cost
-pub fn calculate_contract_creation_cost(data: Option<&Bytes>) -> u128 {
-    if let Some(bytecode) = data {
-        // Base cost for contract creation
-        let mut cost = 32_000;
-        // Additional cost per byte of bytecode
-        cost += bytecode.len() as u128 * 200;
-        cost
+/// ecPairing (0x08): base cost plus a cost per 192 byte (G1, G2) pair,
+/// `None` when the input is not made of whole pairs
+pub fn ec_pairing_gas(input: &[u8], spec: SpecId) -> Option<u64> {
+    if !input.len().is_multiple_of(192) {
+        return None;
+    }
+    let pairs = (input.len() / 192) as u64;
+    if spec.is_enabled_in(SpecId::ISTANBUL) {
+        Some(45_000 + 34_000 * pairs)
     } else {
-        0
+        Some(100_000 + 80_000 * pairs)
+    }
+}
+
+/// blake2f (0x09): one gas per round, `None` when the input is malformed
+pub fn blake2f_gas(input: &[u8]) -> Option<u64> {
+    if input.len() != 213 {
+        return None;
+    }
+    let rounds = u32::from_be_bytes(input[0..4].try_into().ok()?);
+    Some(rounds as u64)
+}
+
+/// KZG point evaluation (0x0a): flat cost, `None` when the input is not 192 bytes
+pub fn point_evaluation_gas(input: &[u8]) -> Option<u64> {
+    if input.len() != 192 {
+        return None;
+    }
+    Some(50_000)
+}
+
+/// BLS12-381 precompiles (0x0b-0x11) from EIP-2537, `None` for other addresses
+pub fn bls12_381_gas(address: Address, input: &[u8]) -> Option<u64> {
+    let gas = match address {
+        G1_ADD_ADDRESS => G1_ADD_BASE_GAS_FEE,
+        G1_MSM_ADDRESS => msm_required_gas(
+            input.len() / G1_MSM_INPUT_LENGTH,
+            &DISCOUNT_TABLE_G1_MSM,
+            G1_MSM_BASE_GAS_FEE,
+        ),
+        G2_ADD_ADDRESS => G2_ADD_BASE_GAS_FEE,
+        G2_MSM_ADDRESS => msm_required_gas(
+            input.len() / G2_MSM_INPUT_LENGTH,
+            &DISCOUNT_TABLE_G2_MSM,
+            G2_MSM_BASE_GAS_FEE,
+        ),
+        PAIRING_ADDRESS => {
+            PAIRING_MULTIPLIER_BASE * (input.len() / PAIRING_INPUT_LENGTH) as u64
+                + PAIRING_OFFSET_BASE
+        }
+        MAP_FP_TO_G1_ADDRESS => MAP_FP_TO_G1_BASE_GAS_FEE,
+        MAP_FP2_TO_G2_ADDRESS => MAP_FP2_TO_G2_BASE_GAS_FEE,
+        _ => return None,
+    };
+    Some(gas)
+}
+
+/// RIP-7212 P256VERIFY (0x100): flat cost
+pub fn p256_verify_gas() -> u64 {
+    3_450
+}
+
+/// Gas a precompile charges for `input` under `spec`.
+/// `None` when the precompile or its pricing is unknown, and the call has to be simulated.
+pub fn precompile_gas(address: Address, input: &[u8], spec: SpecId) -> Option<u64> {
+    if address == u64_to_address(0x100) {
+        return Some(p256_verify_gas());
+    }
+    if address > u64_to_address(0xff) {
+        return None;
+    }
+    match address.as_slice()[19] {
+        0x01 => Some(ecrecover_gas()),
+        0x02 => Some(sha256_gas(input)),
+        0x03 => Some(ripemd160_gas(input)),
+        0x04 => Some(identity_gas(input)),
+        // EIP-198 before Berlin and EIP-7883 from Osaka are left to the EVM
+        0x05 if spec.is_enabled_in(SpecId::BERLIN) && !spec.is_enabled_in(SpecId::OSAKA) => {
+            Some(modexp_gas(input))
+        }
+        0x06 => Some(ec_add_gas(spec)),
+        0x07 => Some(ec_mul_gas(spec)),
+        0x08 => ec_pairing_gas(input, spec),
+        0x09 => blake2f_gas(input),
+        0x0a => point_evaluation_gas(input),
+        0x0b..=0x11 => bls12_381_gas(address, input),
+        _ => None,
     }
 }